@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::borrow::Borrow;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
@@ -9,50 +9,248 @@ use slog::Logger;
 use futures::prelude::*;
 use futures::future;
 use futures::stream;
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use regex::Regex;
 
+use calendar::{BusinessCalendar, CalendarConfig};
 use client;
 use client::types;
-
-#[derive(Clone, Debug)]
+use commands;
+use commits;
+use config::{self, Config, ConfigProblem};
+use description;
+use diff;
+use failure_hints;
+use flaky;
+use markers::{self, Marker};
+use reminders::{self, EscalationStep};
+use reports;
+use stale;
+use store;
+use templates;
+use trace;
+use webhook;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct FullMergeRequest {
     pub project: types::Project,
     pub request: types::MergeRequest,
     pub source_branch: types::Branch,
     pub source_branch_commits: Vec<types::Commit>,
     pub target_branch_commits: Vec<types::Commit>,
+    /// Commits of the merge request, only loaded when the repo config has a
+    /// `[commits]` section.
+    #[serde(default)]
+    pub commits: Vec<types::Commit>,
     pub comments: Vec<types::Note>,
     pub bot_comments: Vec<types::Note>,
+    /// Markers of the bot comments, by note id.
+    #[serde(skip)]
+    pub bot_markers: HashMap<u64, Marker>,
     pub pipelines: Vec<types::Pipeline>,
+    /// Added lines of the changed files by new path, at the head commit of
+    /// `request`.
+    #[serde(default)]
+    pub changed_lines: HashMap<String, HashSet<u64>>,
 
     pub repo_config: RepoConfig,
+    /// Where `repo_config` was loaded from.
+    #[serde(default)]
+    pub config_revision: ConfigRevision,
+    /// Problems of the repo config shown in the report: those of the
+    /// changed config, or of the applied one for merge requests into the
+    /// default branch.
+    #[serde(default)]
+    pub config_problems: Vec<ConfigProblem>,
+    /// Time of the last bot action on the merge request since it was
+    /// loaded. Gitlab bumps `updated_at` for those, so the merge request is
+    /// then compared by content.
+    #[serde(default)]
+    pub bot_changed_at: Option<DateTime<Utc>>,
 }
 
 impl FullMergeRequest {
-    pub fn has_bot_comment(&self, marker: &str, max_age_days: Option<i64>) -> bool {
-        let now = Utc::now();
+    /// Parse the markers of the bot comments.
+    pub fn parse_markers(&mut self) {
+        self.bot_markers = self.bot_comments
+            .iter()
+            .filter_map(|c| Marker::parse(&c.body).map(|m| (c.id, m)))
+            .collect();
+    }
+
+    /// Get the bot comments with the given marker kind, newest first.
+    pub fn bot_comments_of_kind(&self, kind: &str) -> Vec<&types::Note> {
         self.bot_comments
             .iter()
-            .find(|c| {
-                // If max age is set, drop all older comments.
-                if let Some(days) = max_age_days.clone() {
-                    if c.created_at < now - Duration::days(days) {
-                        return false;
-                    }
-                }
-                // Only check comments which contain marker.
-                if c.body.contains(marker) == false {
-                    return false;
-                }
-                true
+            .filter(|c| {
+                self.bot_markers
+                    .get(&c.id)
+                    .map(|m| m.kind == kind)
+                    .unwrap_or(false)
             })
-            .is_some()
+            .collect()
+    }
+
+    pub fn has_bot_comment(&self, kind: &str) -> bool {
+        self.bot_comments_of_kind(kind).len() > 0
+    }
+
+    /// Check if the bot replied to the commands of a comment.
+    pub fn has_command_reply(&self, note_id: u64) -> bool {
+        self.bot_markers
+            .values()
+            .any(|m| m.kind == markers::COMMAND_REPLY && m.note_id == Some(note_id))
+    }
+
+    /// Check for a bot comment with the marker kind that is younger than the
+    /// given number of working days.
+    pub fn has_recent_bot_comment(
+        &self,
+        kind: &str,
+        max_age_days: i64,
+        calendar: &BusinessCalendar,
+    ) -> bool {
+        let now = Utc::now();
+        self.bot_comments_of_kind(kind)
+            .iter()
+            .any(|c| calendar.working_days_between(c.created_at, now) < max_age_days)
+    }
+
+    /// Count the bot comments with the marker kind posted after the given
+    /// time.
+    pub fn bot_comments_since(&self, kind: &str, since: DateTime<Utc>) -> usize {
+        self.bot_comments_of_kind(kind)
+            .iter()
+            .filter(|c| c.created_at > since)
+            .count()
+    }
+
+    /// Time of the last activity: the newest commit or comment by someone
+    /// other than the bot, including system notes like approvals.
+    pub fn last_activity(&self) -> DateTime<Utc> {
+        let bot_note_ids = self.bot_comments.iter().map(|c| c.id).collect::<Vec<_>>();
+        let last_note = self.comments
+            .iter()
+            .filter(|c| !bot_note_ids.contains(&c.id))
+            .map(|c| c.created_at)
+            .max();
+        let last_commit = self.source_branch.commit.committed_date;
+        match last_note {
+            Some(n) if n > last_commit => n,
+            _ => last_commit,
+        }
     }
 
     pub fn job_url(&self, job_id: u64) -> String {
         format!("{}/-/jobs/{}", self.project.web_url, job_id)
     }
+
+    pub fn state(&self, bot_id: u64) -> MergeRequestState {
+        MergeRequestState::new(&self.pipelines, &self.comments, bot_id)
+    }
+
+    /// Check if the merge request is unchanged, or only changed by actions
+    /// of the bot, which bump `updated_at` and the comment count.
+    pub fn is_unchanged(&self, mr: &types::MergeRequest) -> bool {
+        if self.request.updated_at == mr.updated_at {
+            return true;
+        }
+        if self.bot_changed_at.is_none() {
+            return false;
+        }
+        let content = |mr: &types::MergeRequest| {
+            let mut value = ::serde_json::to_value(mr).unwrap_or(::serde_json::Value::Null);
+            if let Some(fields) = value.as_object_mut() {
+                fields.remove("updated_at");
+                fields.remove("user_notes_count");
+            }
+            value
+        };
+        content(&self.request) == content(mr)
+    }
+
+    /// Take over `updated_at` and the newest notes, newest first, of a merge
+    /// request that is unchanged besides the bot's actions.
+    ///
+    /// Returns false if there was nothing to update.
+    pub fn refresh(
+        &mut self,
+        mr: &types::MergeRequest,
+        notes: &[types::Note],
+        bot_id: u64,
+    ) -> bool {
+        if self.request.updated_at == mr.updated_at && self.bot_changed_at.is_none() {
+            return false;
+        }
+        self.request = mr.clone();
+        self.bot_changed_at = None;
+        for note in notes {
+            let is_bot = note.author.as_ref().map(|a| a.id == bot_id).unwrap_or(false);
+            merge_note(&mut self.comments, note);
+            if is_bot {
+                merge_note(&mut self.bot_comments, note);
+            }
+        }
+        self.parse_markers();
+        true
+    }
+
+    /// Remove a deleted comment.
+    fn remove_note(&mut self, note_id: u64) {
+        self.comments.retain(|c| c.id != note_id);
+        self.bot_comments.retain(|c| c.id != note_id);
+        self.bot_markers.remove(&note_id);
+    }
+}
+
+/// Add or replace a note in a list of notes, newest first.
+fn merge_note(notes: &mut Vec<types::Note>, note: &types::Note) {
+    notes.retain(|n| n.id != note.id);
+    let index = notes
+        .iter()
+        .position(|n| n.created_at < note.created_at)
+        .unwrap_or(notes.len());
+    notes.insert(index, note.clone());
+}
+
+/// The parts of a merge request besides `updated_at` that require a
+/// re-check when they change.
+///
+/// Gitlab does not bump `updated_at` for finished pipelines, so the head
+/// pipeline and the latest comment are tracked as well.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergeRequestState {
+    /// Id and status of the newest pipeline.
+    pub pipeline: Option<(u64, String)>,
+    /// Id of the newest comment that was not written by the bot.
+    pub last_note_id: Option<u64>,
+}
+
+impl MergeRequestState {
+    /// Compute the state from the pipelines and notes of a merge request,
+    /// both newest first. Used for both the cached and the current state, so
+    /// they can not disagree.
+    pub fn new(pipelines: &[types::Pipeline], notes: &[types::Note], bot_id: u64) -> Self {
+        MergeRequestState {
+            pipeline: Self::pipeline(pipelines),
+            last_note_id: notes
+                .iter()
+                .find(|n| n.author.as_ref().map(|a| a.id != bot_id).unwrap_or(true))
+                .map(|n| n.id),
+        }
+    }
+
+    /// Id and status of the newest pipeline.
+    pub fn pipeline(pipelines: &[types::Pipeline]) -> Option<(u64, String)> {
+        pipelines.get(0).map(|p| (p.id, p.status.clone()))
+    }
+}
+
+/// Result of the change detection of a merge request.
+enum Freshness {
+    Unchanged,
+    /// Changed, with the pipelines if they were loaded for the check.
+    Changed(Option<Vec<types::Pipeline>>),
 }
 
 struct CacheItem<T> {
@@ -84,30 +282,132 @@ impl<K: ::std::cmp::Eq + ::std::hash::Hash, V> Cacher<K, V> {
             },
         );
     }
+
+    /// Drop expired items, returns the number of dropped items.
+    fn remove_expired(&mut self) -> usize {
+        let now = Instant::now();
+        let len = self.items.len();
+        self.items.retain(|_, i| match i.valid_until {
+            Some(x) => x >= now,
+            None => true,
+        });
+        len - self.items.len()
+    }
 }
 
-#[derive(Default)]
+/// Maximum number of diff discussions started for one merge request check.
+const MAX_DISCUSSIONS_PER_RUN: usize = 20;
+
+/// Comment commands older than this are ignored, so an empty store does not
+/// replay old commands.
+///
+/// Without a state dir, younger comments handled before a restart are
+/// recognized by the award emoji or the reply of the bot.
+const COMMAND_MAX_AGE_HOURS: i64 = 24;
+
+/// Merge requests closed as stale are reopened on replies for this long.
+const STALE_REOPEN_WINDOW_DAYS: i64 = 30;
+
+/// Actions and handled commands are kept for this long.
+const STATE_RETENTION_DAYS: i64 = 90;
+
 struct CacheInner {
     merge_requests: HashMap<u64, FullMergeRequest>,
-    project_configs: Cacher<u64, RepoConfig>,
+    /// Repo configs by project id and branch.
+    project_configs: Cacher<(u64, String), (RepoConfig, ConfigRevision)>,
+    /// Parsed base configs of `extends`, by reference.
+    base_configs: Cacher<String, ::toml::Value>,
+    /// Coverage of the target branch by project id, commit sha, job name and
+    /// artifact path.
+    base_coverage: Cacher<(u64, String, String, String), Option<reports::coverage::Coverage>>,
+    /// How long a loaded repo config is used before it is reloaded.
+    config_ttl: ::std::time::Duration,
+    actions: Vec<store::Action>,
+    commands: Vec<store::ProcessedCommand>,
+    store: Box<store::Store>,
+    log: Logger,
+}
+
+impl CacheInner {
+    fn store_failed(&self, op: &str, e: Error) {
+        error!(self.log, "store_write_failed";
+            "operation" => op,
+            "error" => e.to_string(),
+        );
+    }
 }
 
 #[derive(Clone)]
 struct Cache(Arc<Mutex<CacheInner>>);
 
 impl Cache {
-    fn new() -> Self {
-        Cache(Arc::new(Mutex::new(CacheInner::default())))
+    /// Create a new cache, restoring previously persisted state from the store.
+    fn new(
+        store: Box<store::Store>,
+        config_ttl: ::std::time::Duration,
+        log: Logger,
+    ) -> Result<Self, Error> {
+        let mut merge_requests = HashMap::new();
+        for mut mr in store.merge_requests()? {
+            mr.parse_markers();
+            merge_requests.insert(mr.request.id, mr);
+        }
+
+        let now = Utc::now();
+        let mut project_configs = Cacher::default();
+        for stored in store.project_configs()? {
+            if stored.revision.branch == "" {
+                continue;
+            }
+            let remaining = stored.loaded_at + Duration::from_std(config_ttl)? - now;
+            if let Ok(remaining) = remaining.to_std() {
+                project_configs.add(
+                    (stored.project_id, stored.revision.branch.clone()),
+                    (stored.config, stored.revision),
+                    Some(Instant::now() + remaining),
+                );
+            }
+        }
+
+        // Older versions recorded visible `[kind]` markers.
+        let mut actions = store.actions()?;
+        for action in actions.iter_mut() {
+            action.marker = action.marker.as_ref().map(|m| markers::normalize_kind(m));
+        }
+        let commands = store.commands()?;
+
+        debug!(log, "cache_restored";
+            "merge_requests" => merge_requests.len(),
+            "actions" => actions.len(),
+            "commands" => commands.len(),
+        );
+
+        Ok(Cache(Arc::new(Mutex::new(CacheInner {
+            merge_requests,
+            project_configs,
+            base_configs: Cacher::default(),
+            base_coverage: Cacher::default(),
+            config_ttl,
+            actions,
+            commands,
+            store,
+            log,
+        }))))
     }
 
     fn merge_request_changed(&self, mr: &types::MergeRequest) -> bool {
         let b = self.0.lock().unwrap();
         match b.merge_requests.get(&mr.id) {
-            Some(ref x) if x.request.updated_at == mr.updated_at => false,
+            Some(ref x) if x.is_unchanged(mr) => false,
             _ => true,
         }
     }
 
+    fn get_merge_request_state(&self, id: u64, bot_id: u64) -> Option<MergeRequestState> {
+        let b = self.0.lock().unwrap();
+        b.merge_requests.get(&id).map(|x| x.state(bot_id))
+    }
+
     fn get_merge_request(&self, mr: types::MergeRequest) -> Option<FullMergeRequest> {
         let b = self.0.lock().unwrap();
         match b.merge_requests.get(&mr.id) {
@@ -116,24 +416,301 @@ impl Cache {
         }
     }
 
+    /// Update the snapshot of a merge request that only changed by actions
+    /// of the bot.
+    fn refresh_merge_request(&self, mr: &types::MergeRequest, notes: &[types::Note], bot_id: u64) {
+        let mut b = self.0.lock().unwrap();
+        let refreshed = match b.merge_requests.get_mut(&mr.id) {
+            Some(cached) => {
+                if !cached.refresh(mr, notes, bot_id) {
+                    return;
+                }
+                cached.clone()
+            }
+            None => return,
+        };
+        let res = b.store.save_merge_request(&refreshed);
+        if let Err(e) = res {
+            b.store_failed("save_merge_request", e);
+        }
+    }
+
     fn set_merge_request(&self, mr: FullMergeRequest) {
         let mut b = self.0.lock().unwrap();
+        let res = b.store.save_merge_request(&mr);
+        if let Err(e) = res {
+            b.store_failed("save_merge_request", e);
+        }
         b.merge_requests.insert(mr.request.id, mr);
     }
 
-    fn get_project_config(&self, project_id: u64) -> Option<RepoConfig> {
+    /// Drop a cached merge request so it is fully reloaded on the next check.
+    fn invalidate_merge_request(&self, id: u64) {
+        let mut b = self.0.lock().unwrap();
+        let res = b.store.remove_merge_request(id);
+        if let Err(e) = res {
+            b.store_failed("remove_merge_request", e);
+        }
+        b.merge_requests.remove(&id);
+    }
+
+    fn get_project_config(
+        &self,
+        project_id: u64,
+        branch: String,
+    ) -> Option<(RepoConfig, ConfigRevision)> {
         let b = self.0.lock().unwrap();
-        b.project_configs.get(&project_id).map(|x| x.clone())
+        b.project_configs.get(&(project_id, branch)).map(|x| x.clone())
     }
 
-    fn set_project_config(&self, project_id: u64, conf: RepoConfig) {
+    fn set_project_config(&self, project_id: u64, conf: RepoConfig, revision: ConfigRevision) {
         let mut b = self.0.lock().unwrap();
-        b.project_configs.add(
+        let stored = store::StoredProjectConfig {
             project_id,
-            conf,
-            Some(Instant::now() + ::std::time::Duration::from_secs(60 * 30)),
+            config: conf.clone(),
+            revision: revision.clone(),
+            loaded_at: Utc::now(),
+        };
+        let res = b.store.save_project_config(&stored);
+        if let Err(e) = res {
+            b.store_failed("save_project_config", e);
+        }
+        let valid_until = Instant::now() + b.config_ttl;
+        b.project_configs.add(
+            (project_id, revision.branch.clone()),
+            (conf, revision),
+            Some(valid_until),
+        );
+    }
+
+    fn get_base_config(&self, reference: &str) -> Option<::toml::Value> {
+        let b = self.0.lock().unwrap();
+        b.base_configs.get(&reference.to_string()).map(|x| x.clone())
+    }
+
+    fn set_base_config(&self, reference: String, value: ::toml::Value) {
+        let mut b = self.0.lock().unwrap();
+        let valid_until = Instant::now() + b.config_ttl;
+        b.base_configs.add(reference, value, Some(valid_until));
+    }
+
+    fn get_base_coverage(
+        &self,
+        key: &(u64, String, String, String),
+    ) -> Option<Option<reports::coverage::Coverage>> {
+        let b = self.0.lock().unwrap();
+        b.base_coverage.get(key).map(|x| x.clone())
+    }
+
+    fn set_base_coverage(
+        &self,
+        key: (u64, String, String, String),
+        coverage: Option<reports::coverage::Coverage>,
+    ) {
+        let mut b = self.0.lock().unwrap();
+        let valid_until = Instant::now() + b.config_ttl;
+        b.base_coverage.add(key, coverage, Some(valid_until));
+    }
+
+    /// Record an action the bot performed.
+    ///
+    /// The snapshot of the merge request is marked as changed by the bot, in
+    /// the store too, so the next check compares it by content and takes
+    /// over the new `updated_at` and comments. Deleted comments are removed
+    /// from it right away.
+    fn add_action(&self, action: store::Action) {
+        let mut b = self.0.lock().unwrap();
+        let res = b.store.add_action(&action);
+        if let Err(e) = res {
+            b.store_failed("add_action", e);
+        }
+
+        let changed = b.merge_requests
+            .values_mut()
+            .find(|mr| {
+                mr.request.project_id == action.project_id
+                    && mr.request.iid == action.merge_request_iid
+            })
+            .map(|mr| {
+                mr.bot_changed_at = Some(action.created_at);
+                if action.kind == store::ActionKind::CommentDeleted {
+                    if let Some(id) = action.note_id {
+                        mr.remove_note(id);
+                    }
+                }
+                mr.clone()
+            });
+        if let Some(mr) = changed {
+            let res = b.store.save_merge_request(&mr);
+            if let Err(e) = res {
+                b.store_failed("save_merge_request", e);
+            }
+        }
+
+        b.actions.push(action);
+    }
+
+    /// Drop state that is no longer needed: snapshots of merge requests that
+    /// are not open anymore, and actions and handled commands older than
+    /// the retention window, unless a snooze is still running.
+    ///
+    /// The logs in the store are rewritten if anything was dropped.
+    fn compact(&self, open_ids: &[u64]) {
+        let mut b = self.0.lock().unwrap();
+        let inner = &mut *b;
+
+        let closed = inner
+            .merge_requests
+            .keys()
+            .filter(|id| !open_ids.contains(id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for id in closed.iter() {
+            inner.merge_requests.remove(id);
+            let res = inner.store.remove_merge_request(*id);
+            if let Err(e) = res {
+                inner.store_failed("remove_merge_request", e);
+            }
+        }
+
+        let now = Utc::now();
+        let since = now - Duration::days(STATE_RETENTION_DAYS);
+        let actions = inner.actions.len();
+        inner.actions.retain(|a| a.created_at > since);
+        let dropped_actions = actions - inner.actions.len();
+        if dropped_actions > 0 {
+            let res = inner.store.replace_actions(&inner.actions);
+            if let Err(e) = res {
+                inner.store_failed("replace_actions", e);
+            }
+        }
+
+        let commands = inner.commands.len();
+        inner.commands.retain(|c| {
+            c.processed_at > since || c.snoozed_until.map(|t| t > now).unwrap_or(false)
+        });
+        let dropped_commands = commands - inner.commands.len();
+        if dropped_commands > 0 {
+            let res = inner.store.replace_commands(&inner.commands);
+            if let Err(e) = res {
+                inner.store_failed("replace_commands", e);
+            }
+        }
+
+        let dropped_coverage = inner.base_coverage.remove_expired();
+
+        debug!(inner.log, "cache_compacted";
+            "merge_requests" => closed.len(),
+            "actions" => dropped_actions,
+            "commands" => dropped_commands,
+            "base_coverage" => dropped_coverage,
         );
     }
+
+    /// Record a comment with commands as handled, unless it already is.
+    ///
+    /// Webhooks and the periodic check can see the same comment at the same
+    /// time, only the caller that gets true must run the commands.
+    fn claim_command(&self, command: store::ProcessedCommand) -> bool {
+        let mut b = self.0.lock().unwrap();
+        if b.commands.iter().any(|c| c.note_id == command.note_id) {
+            return false;
+        }
+        let res = b.store.add_command(&command);
+        if let Err(e) = res {
+            b.store_failed("add_command", e);
+        }
+        b.commands.push(command);
+        true
+    }
+
+    fn is_command_processed(&self, note_id: u64) -> bool {
+        let b = self.0.lock().unwrap();
+        b.commands.iter().any(|c| c.note_id == note_id)
+    }
+
+    /// End of the latest snooze of a merge request.
+    fn snoozed_until(&self, project_id: u64, iid: u64) -> Option<DateTime<Utc>> {
+        let b = self.0.lock().unwrap();
+        b.commands
+            .iter()
+            .filter(|c| c.project_id == project_id && c.merge_request_iid == iid)
+            .filter_map(|c| c.snoozed_until)
+            .max()
+    }
+
+    /// Count the comments with the given marker the bot posted on a merge
+    /// request after the given time.
+    fn posted_since(&self, project_id: u64, iid: u64, marker: &str, since: DateTime<Utc>) -> usize {
+        let b = self.0.lock().unwrap();
+        b.actions
+            .iter()
+            .filter(|a| {
+                a.project_id == project_id && a.merge_request_iid == iid
+                    && a.kind == store::ActionKind::CommentCreated
+                    && a.created_at > since
+                    && a.marker.as_ref().map(|m| m == marker).unwrap_or(false)
+            })
+            .count()
+    }
+
+    /// Get the actions of a kind on a merge request with a marker starting
+    /// with the given prefix.
+    fn find_actions(
+        &self,
+        project_id: u64,
+        iid: u64,
+        kind: store::ActionKind,
+        marker_prefix: &str,
+    ) -> Vec<store::Action> {
+        let b = self.0.lock().unwrap();
+        b.actions
+            .iter()
+            .filter(|a| {
+                a.project_id == project_id && a.merge_request_iid == iid && a.kind == kind
+                    && a.marker
+                        .as_ref()
+                        .map(|m| m.starts_with(marker_prefix))
+                        .unwrap_or(false)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Get the merge requests the bot closed with the given marker after
+    /// `since`, and did not reopen afterwards, as `(project_id, iid)`.
+    fn closed_merge_requests(&self, marker: &str, since: DateTime<Utc>) -> Vec<(u64, u64)> {
+        let b = self.0.lock().unwrap();
+        let mut closed: Vec<(u64, u64)> = Vec::new();
+        for a in b.actions.iter() {
+            let key = (a.project_id, a.merge_request_iid);
+            match a.kind {
+                store::ActionKind::MergeRequestClosed => {
+                    let matches = a.marker.as_ref().map(|m| m == marker).unwrap_or(false);
+                    if matches && a.created_at > since && !closed.contains(&key) {
+                        closed.push(key);
+                    }
+                }
+                store::ActionKind::MergeRequestReopened => {
+                    closed.retain(|k| k != &key);
+                }
+                _ => {}
+            }
+        }
+        closed
+    }
+
+    /// Check if the bot ever posted a comment with the given marker on a
+    /// merge request.
+    fn has_posted(&self, project_id: u64, iid: u64, marker: &str) -> bool {
+        let b = self.0.lock().unwrap();
+        b.actions.iter().any(|a| {
+            a.project_id == project_id && a.merge_request_iid == iid
+                && (a.kind == store::ActionKind::CommentCreated
+                    || a.kind == store::ActionKind::DiscussionCreated)
+                && a.marker.as_ref().map(|m| m == marker).unwrap_or(false)
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
@@ -141,6 +718,38 @@ pub struct ReportConfig {
     pub job_name: String,
     pub path: String,
     pub format: Option<String>,
+    /// Heading of the report. Defaults to the artifact path.
+    pub title: Option<String>,
+    /// Maximum artifact size in bytes.
+    pub max_size: Option<usize>,
+}
+
+impl ReportConfig {
+    pub fn report_format(&self) -> Result<reports::ReportFormat, Error> {
+        match self.format.as_ref() {
+            Some(name) => reports::ReportFormat::from_name(name),
+            None => Ok(reports::ReportFormat::from_path(&self.path)),
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        self.title.as_ref().unwrap_or(&self.path)
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size.unwrap_or(reports::DEFAULT_MAX_SIZE)
+    }
+
+    /// Largest artifact that is downloaded.
+    ///
+    /// Markdown reports over `max_size` are rejected anyway.
+    pub fn max_download_size(&self, format: reports::ReportFormat) -> usize {
+        if format == reports::ReportFormat::Markdown {
+            self.max_size()
+        } else {
+            reports::MAX_DOWNLOAD_SIZE
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
@@ -149,6 +758,19 @@ pub struct RepoMergeRequestConfig {
     pub title_error: Option<String>,
     pub branch_name_pattern: Option<String>,
     pub branch_name_error: Option<String>,
+    /// Minimum length of the description in characters.
+    pub description_min_length: Option<usize>,
+    pub description_length_error: Option<String>,
+    /// Headings the description must contain, eg `## Testing`.
+    #[serde(default)]
+    pub required_headings: Vec<String>,
+    pub headings_error: Option<String>,
+    /// Require all task list items of the description to be checked.
+    pub require_checked_tasks: Option<bool>,
+    pub tasks_error: Option<String>,
+    /// Require a reference that closes an issue, eg `Closes #123`.
+    pub require_issue_reference: Option<bool>,
+    pub issue_reference_error: Option<String>,
 }
 
 impl RepoMergeRequestConfig {
@@ -161,14 +783,108 @@ impl RepoMergeRequestConfig {
         self.branch_name_pattern.as_ref()
             .and_then(|p| Regex::new(p).ok())
     }
+
+    pub fn require_checked_tasks(&self) -> bool {
+        self.require_checked_tasks.unwrap_or(false)
+    }
+
+    pub fn require_issue_reference(&self) -> bool {
+        self.require_issue_reference.unwrap_or(false)
+    }
+
+    /// Check the description of a merge request against the configured
+    /// rules, one validation item per rule.
+    pub fn check_description(&self, description: &str) -> Vec<templates::ValidationItem> {
+        let mut items = Vec::new();
+        let item = |label: &str, valid: bool, error: &Option<String>, default: String| {
+            if valid {
+                templates::ValidationItem::new(label, valid)
+            } else {
+                let detail = error.clone().unwrap_or(default);
+                templates::ValidationItem::with_detail(label, valid, detail)
+            }
+        };
+
+        if let Some(min) = self.description_min_length {
+            let len = description::length(description);
+            items.push(item(
+                "Description Length",
+                len >= min,
+                &self.description_length_error,
+                format!("Description should be at least {} characters long", min),
+            ));
+        }
+        if !self.required_headings.is_empty() {
+            let missing = description::missing_headings(description, &self.required_headings);
+            items.push(item(
+                "Description Headings",
+                missing.is_empty(),
+                &self.headings_error,
+                format!("Description is missing: `{}`", missing.join("`, `")),
+            ));
+        }
+        if self.require_checked_tasks() {
+            let unchecked = description::unchecked_tasks(description);
+            items.push(item(
+                "All Tasks Checked",
+                unchecked == 0,
+                &self.tasks_error,
+                format!("{} task(s) of the description are not checked", unchecked),
+            ));
+        }
+        if self.require_issue_reference() {
+            items.push(item(
+                "Linked Issue",
+                description::has_issue_reference(description),
+                &self.issue_reference_error,
+                "Description should close an issue, eg `Closes #123`".to_string(),
+            ));
+        }
+
+        items
+    }
 }
 
+/// Path of the repo config in the repository.
+pub const REPO_CONFIG_PATH: &str = ".gitlab-bot.toml";
+
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct RepoConfig {
+    /// Base config this one is merged onto, eg
+    /// `group/bot-config:path/to/base.toml`.
+    pub extends: Option<String>,
     pub disabled: Option<bool>,
+    /// Use the config of the merge request's target branch instead of the
+    /// default branch. Only read from the default branch.
+    pub target_branch_config: Option<bool>,
+    /// Use the config of the source branch for merge requests that change
+    /// it, to preview the changes. Only read from the default branch.
+    pub preview_config_changes: Option<bool>,
     pub merge_requests: Option<RepoMergeRequestConfig>,
+    /// Conventions checked for every commit of a merge request.
+    pub commits: Option<commits::CommitsConfig>,
     #[serde(default)]
     pub reports: Vec<ReportConfig>,
+    /// Minimum line coverage in percent.
+    pub min_coverage: Option<f64>,
+    /// Maximum drop of the line coverage compared to the target branch, in
+    /// percentage points.
+    pub max_coverage_drop: Option<f64>,
+    #[serde(default)]
+    pub reminders: reminders::ReminderConfig,
+    #[serde(default)]
+    pub stale: stale::StaleConfig,
+    #[serde(default)]
+    pub flaky: flaky::FlakyConfig,
+    #[serde(default)]
+    pub failure_hints: Vec<failure_hints::FailureHint>,
+    #[serde(default)]
+    pub trace: trace::TraceConfig,
+    #[serde(default)]
+    pub templates: templates::TemplateConfig,
+    /// Working days used for age based rules. Overrides the bot settings.
+    #[serde(default)]
+    pub calendar: CalendarConfig,
 }
 
 impl RepoConfig {
@@ -176,30 +892,79 @@ impl RepoConfig {
         self.disabled.unwrap_or(false)
     }
 
-}
+    pub fn use_target_branch_config(&self) -> bool {
+        self.target_branch_config.unwrap_or(false)
+    }
 
-#[derive(Clone)]
-pub struct Config {
-    endpoint: String,
-    token: String,
-    interval: u64,
-}
+    pub fn preview_config_changes(&self) -> bool {
+        self.preview_config_changes.unwrap_or(false)
+    }
+
+    /// Find problems that are not caught while parsing, eg invalid regexes.
+    ///
+    /// Broken settings are ignored at runtime, so this is the only place
+    /// where they show up.
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
 
-impl Config {
-    fn from_env() -> Result<Self, Error> {
-        use std::env::var;
+        if let Some(mr_config) = self.merge_requests.as_ref() {
+            if let Some(p) = mr_config.title_pattern.as_ref() {
+                check_regex(&mut problems, "merge_requests.title_pattern", p);
+            }
+            if let Some(p) = mr_config.branch_name_pattern.as_ref() {
+                check_regex(&mut problems, "merge_requests.branch_name_pattern", p);
+            }
+        }
+        for (index, report) in self.reports.iter().enumerate() {
+            if let Err(e) = report.report_format() {
+                let key = format!("reports.{}.format", index);
+                problems.push(ConfigProblem::for_key(key, e.to_string()));
+            }
+        }
+        for (index, pattern) in self.flaky.patterns.iter().enumerate() {
+            if let Some(e) = pattern.pattern.error() {
+                let key = format!("flaky.patterns.{}.pattern", index);
+                problems.push(ConfigProblem::invalid_regex(&key, e));
+            }
+        }
+        for (index, hint) in self.failure_hints.iter().enumerate() {
+            if let Some(e) = hint.pattern.error() {
+                let key = format!("failure_hints.{}.pattern", index);
+                problems.push(ConfigProblem::invalid_regex(&key, e));
+            }
+        }
+        if let Some(e) = self.trace.error_pattern.as_ref().and_then(|p| p.error()) {
+            problems.push(ConfigProblem::invalid_regex("trace.error_pattern", e));
+        }
+        if let Err(e) = self.calendar.calendar() {
+            problems.push(ConfigProblem::for_key("calendar", e.to_string()));
+        }
+        problems.extend(self.reminders.validate());
+        problems.extend(self.templates.validate());
 
-        let url =
-            var("GITLAB_BOT_URL").map_err(|_| format_err!("Missing env var: GITLAB_BOT_URL"))?;
+        problems
+    }
+}
 
-        let token =
-            var("GITLAB_BOT_TOKEN").map_err(|_| format_err!("Missing env var: GITLAB_BOT_TOKEN"))?;
+/// The branch and commit a repo config was loaded from.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct ConfigRevision {
+    pub branch: String,
+    /// Head commit of the branch when the config was loaded.
+    pub sha: String,
+    pub short_sha: String,
+    /// False if there was no valid config file, so only the defaults apply.
+    pub found: bool,
+    /// Loaded from the merge request's source branch to preview changes.
+    pub preview: bool,
+    /// Problems found while loading the config.
+    #[serde(default)]
+    pub problems: Vec<ConfigProblem>,
+}
 
-        Ok(Config {
-            endpoint: url,
-            token,
-            interval: 60 * 5, // 5 minutes.
-        })
+fn check_regex(problems: &mut Vec<ConfigProblem>, key: &str, pattern: &str) {
+    if let Err(e) = Regex::new(pattern) {
+        problems.push(ConfigProblem::invalid_regex(key, &e));
     }
 }
 
@@ -214,28 +979,43 @@ pub struct Bot {
 
 impl Bot {
     pub fn new(config: Config, handle: Handle) -> Result<Self, Error> {
-        let log = Self::default_logger();
-        let c = client::Gitlab::new(&config.endpoint, config.token.clone(), log.clone(), &handle)?;
+        let log = Self::logger(config.log_level);
+        let mut c =
+            client::Gitlab::new(&config.endpoint, config.token.clone(), log.clone(), &handle)?;
+        if config.dry_run {
+            info!(log, "dry_run_enabled";
+                "file" => config.dry_run_file.as_ref().map(|p| p.display().to_string()),
+            );
+            let recorder = client::recorder::Recorder::new(log.clone(), config.dry_run_file.clone())?;
+            c = c.with_recorder(recorder);
+        }
+
+        // Nothing happened in dry-run mode, so the action log must not be
+        // persisted.
+        let store: Box<store::Store> = match config.state_dir.as_ref() {
+            Some(dir) if !config.dry_run => {
+                Box::new(store::FileStore::open(dir.clone(), log.clone())?)
+            }
+            _ => Box::new(store::MemoryStore),
+        };
+        let config_ttl = ::std::time::Duration::from_secs(config.config_cache_ttl);
+        let cache = Cache::new(store, config_ttl, log.clone())?;
+
         Ok(Bot {
             client: c,
             handle,
             log,
-            cache: Cache::new(),
+            cache,
             config,
         })
     }
 
-    pub fn from_env(handle: Handle) -> Result<Self, Error> {
-        Self::new(Config::from_env()?, handle)
-    }
-
-    fn default_logger() -> Logger {
+    fn logger(level: ::sloggers::types::Severity) -> Logger {
         use sloggers::Build;
         use sloggers::terminal::{Destination, TerminalLoggerBuilder};
-        use sloggers::types::Severity;
 
         let mut builder = TerminalLoggerBuilder::new();
-        builder.level(Severity::Trace);
+        builder.level(level);
         builder.destination(Destination::Stderr);
 
         let logger = builder.build().unwrap();
@@ -243,40 +1023,273 @@ impl Bot {
         logger
     }
 
+    /// Get the business calendar for a merge request, combining the bot and
+    /// the repo settings.
+    fn calendar(&self, mr: &FullMergeRequest) -> BusinessCalendar {
+        let config = self.config.calendar.merge(&mr.repo_config.calendar);
+        match config.calendar() {
+            Ok(c) => c,
+            Err(e) => {
+                warn!(self.log, "invalid_calendar_config";
+                    "project_id" => mr.project.id,
+                    "error" => e.to_string(),
+                );
+                self.config.calendar.calendar().unwrap_or_default()
+            }
+        }
+    }
+
+    /// Load the repo config of a branch at a commit.
     #[async]
-    fn cached_repo_config(self, project: types::Project) -> Result<RepoConfig, Error> {
-        if let Some(conf) = self.cache.get_project_config(project.id) {
-            // Found cached version.
-            Ok(conf)
-        } else {
-            // No cached version, actually load it.
-            // Load repo config.
-            let repo_config_res = await!(self.client.clone().repo_file(
-                project.id,
-                ".gitlab-bot.toml".to_string(),
-                "master".to_string()
-            )).map_err(Error::from)
-                .and_then(|data| ::toml::from_slice::<RepoConfig>(&data).map_err(Error::from));
-
-            let config = match repo_config_res {
-                Ok(c) => c,
-                Err(e) => {
-                    eprintln!("Could not load repo config: {}", e);
-                    RepoConfig::default()
+    fn load_repo_config(
+        self,
+        project_id: u64,
+        branch: String,
+        commit: types::Commit,
+    ) -> Result<(RepoConfig, ConfigRevision), Error> {
+        let file_res = await!(self.client.clone().repo_file(
+            project_id,
+            REPO_CONFIG_PATH.to_string(),
+            commit.id.clone()
+        ));
+        let parsed_toml = match file_res {
+            Ok(data) => Some(config::parse_toml(&data)),
+            // No config file, so the defaults apply.
+            Err(ref e) if e.status() == Some(::reqwest::StatusCode::NotFound) => None,
+            Err(e) => return Err(e.into()),
+        };
+        let (parsed, mut problems, resolved) = match parsed_toml {
+            Some(Ok(value)) => {
+                let resolved = await!(self.clone().resolve_extends(project_id, value))?;
+                let (parsed, mut more) = self.config.repo_config(resolved.value.clone());
+                resolved.tag(&mut more);
+                let mut problems = resolved.problems.clone();
+                problems.extend(more);
+                (parsed, problems, Some(resolved))
+            }
+            Some(Err(problem)) => (None, vec![problem], None),
+            None => (None, Vec::new(), None),
+        };
+        let found = parsed.is_some();
+        let mut config = parsed.unwrap_or_else(|| self.config.default_repo_config());
+
+        // Load template files from the project that declares them.
+        for (name, path) in config.templates.files.clone() {
+            let key = format!("templates.files.{}", name);
+            let source = resolved
+                .as_ref()
+                .and_then(|r| r.source(&key))
+                .map(|s| s.to_string());
+            let res = await!(self.clone().template_file(
+                project_id,
+                commit.id.clone(),
+                path.clone(),
+                source.clone()
+            )).and_then(|data| String::from_utf8(data).map_err(Error::from))
+                .and_then(|template| config.templates.set(&name, template));
+            if let Err(e) = res {
+                let mut problem = ConfigProblem::for_key(key, format!("`{}`: {}", path, e));
+                problem.source = source;
+                problems.push(problem);
+            }
+        }
+
+        for problem in problems.iter() {
+            warn!(self.log, "repo_config_problem";
+                "project_id" => project_id,
+                "branch" => &branch,
+                "sha" => &commit.id,
+                "key" => problem.key.clone(),
+                "line" => problem.line,
+                "column" => problem.column,
+                "message" => &problem.message,
+                "source" => problem.source.clone(),
+            );
+        }
+
+        let revision = ConfigRevision {
+            branch,
+            sha: commit.id,
+            short_sha: commit.short_id,
+            found,
+            preview: false,
+            problems,
+        };
+        Ok((config, revision))
+    }
+
+    /// Load the base config of an `extends` reference, without resolving
+    /// its own `extends`.
+    #[async]
+    fn cached_base_config(self, reference: String) -> Result<::toml::Value, Error> {
+        if let Some(value) = self.cache.get_base_config(&reference) {
+            return Ok(value);
+        }
+
+        let (project_path, path) = config::parse_extends(&reference)?;
+        let project = await!(self.client.clone().project_by_path(project_path))?;
+        let data = await!(self.client.clone().repo_file(project.id, path, project.default_branch))?;
+        let value = config::parse_toml(&data).map_err(|p| format_err!("{}", p))?;
+
+        self.cache.set_base_config(reference, value.clone());
+        Ok(value)
+    }
+
+    /// Load a template file of the repo config.
+    ///
+    /// Files declared in a base config are loaded from the default branch of
+    /// its project, others from the local project at the commit.
+    #[async]
+    fn template_file(
+        self,
+        project_id: u64,
+        commit_id: String,
+        path: String,
+        source: Option<String>,
+    ) -> Result<Vec<u8>, Error> {
+        let data = match source {
+            Some(reference) => {
+                let (project_path, _) = config::parse_extends(&reference)?;
+                let project = await!(self.client.clone().project_by_path(project_path))?;
+                await!(self.client.clone().repo_file(project.id, path, project.default_branch))?
+            }
+            None => await!(self.client.clone().repo_file(project_id, path, commit_id))?,
+        };
+        Ok(data)
+    }
+
+    /// Merge a repo config with the chain of base configs it extends.
+    ///
+    /// Each config wins over the one it extends. Bases must be in the same
+    /// top-level group as the project or in `extends_allowlist`. If a base
+    /// is not allowed, can not be loaded or the chain has a cycle, the chain
+    /// is cut there and a problem is returned.
+    #[async]
+    fn resolve_extends(
+        self,
+        project_id: u64,
+        value: ::toml::Value,
+    ) -> Result<config::ResolvedConfig, Error> {
+        let mut chain = config::ExtendsChain::new(value);
+        let mut local_project: Option<String> = None;
+        while let Some(reference) = chain.next() {
+            if local_project.is_none() {
+                let project = await!(self.client.clone().project(project_id))?;
+                local_project = Some(project.path_with_namespace);
+            }
+            let allowed = match config::parse_extends(&reference) {
+                Ok((project, _)) => {
+                    let local = local_project.as_ref().map(|p| p.as_str()).unwrap_or("");
+                    self.config.extends_allowed(local, &project)
                 }
+                // Reported when loading the base.
+                Err(_) => true,
             };
+            if !allowed {
+                chain.fail(format!(
+                    "`{}` is outside of the group of this project and not in `extends_allowlist`",
+                    reference
+                ));
+                break;
+            }
+
+            match await!(self.clone().cached_base_config(reference.clone())) {
+                Ok(base) => chain.push(reference, base),
+                Err(e) => {
+                    chain.fail(format!("could not load `{}`: {}", reference, e));
+                    break;
+                }
+            }
+        }
+        Ok(chain.resolve())
+    }
+
+    /// Get the repo config of a branch, loading it if it is not cached.
+    #[async]
+    fn cached_repo_config(
+        self,
+        project_id: u64,
+        branch: String,
+    ) -> Result<(RepoConfig, ConfigRevision), Error> {
+        if let Some(conf) = self.cache.get_project_config(project_id, branch.clone()) {
+            // Found cached version.
+            return Ok(conf);
+        }
+
+        // No cached version, actually load it.
+        let head = await!(self.client.clone().branch(project_id, branch.clone()))?;
+        let (config, revision) =
+            await!(self.clone().load_repo_config(project_id, branch, head.commit))?;
+        self.cache
+            .set_project_config(project_id, config.clone(), revision.clone());
+        Ok((config, revision))
+    }
+
+    /// Get the repo config that applies to a merge request.
+    ///
+    /// The config of the default branch decides whether the config of the
+    /// target branch is used instead and whether the config of the source
+    /// branch is previewed, if the merge request changes it.
+    #[async]
+    fn merge_request_config(
+        self,
+        project: types::Project,
+        mr: types::MergeRequest,
+        source_config: Option<(RepoConfig, ConfigRevision)>,
+    ) -> Result<(RepoConfig, ConfigRevision), Error> {
+        let (default_config, default_revision) = await!(
+            self.clone()
+                .cached_repo_config(project.id, project.default_branch.clone())
+        )?;
 
-            self.cache.set_project_config(project.id, config.clone());
+        // The changed config controls what the bot does, eg posting
+        // comments, so only maintainers get a preview.
+        if let Some((config, mut revision)) = source_config {
+            if default_config.preview_config_changes() {
+                let access = await!(self.clone().access_level(project.id, mr.author.id))?;
+                if access >= types::MAINTAINER_ACCESS {
+                    revision.preview = true;
+                    return Ok((config, revision));
+                }
+                debug!(self.log, "config_preview_skipped";
+                    "project_id" => project.id,
+                    "merge_request_iid" => mr.iid,
+                    "author" => &mr.author.username,
+                );
+            }
+        }
 
-            Ok(config)
+        if default_config.use_target_branch_config() && mr.target_branch != project.default_branch
+        {
+            return await!(self.cached_repo_config(project.id, mr.target_branch));
         }
+
+        Ok((default_config, default_revision))
     }
 
+    /// Access level of a user in a project, including memberships inherited
+    /// from groups. 0 if the user is no member.
+    #[async]
+    fn access_level(self, project_id: u64, user_id: u64) -> Result<u64, Error> {
+        let members = await!(self.client.clone().project_members(project_id))?;
+        let level = members
+            .iter()
+            .filter(|m| m.id == user_id)
+            .map(|m| m.access_level)
+            .max()
+            .unwrap_or(0);
+        Ok(level)
+    }
+
+    /// Load everything about a merge request, unless it is cached.
+    ///
+    /// Pipelines that were already loaded to detect changes are reused.
     #[async]
     pub fn full_merge_request(
         self,
         mr: types::MergeRequest,
         bot_id: u64,
+        pipelines: Option<Vec<types::Pipeline>>,
     ) -> Result<FullMergeRequest, Error> {
         // Check cache first.
         // Cache will match if updated_at did not change.
@@ -285,7 +1298,6 @@ impl Bot {
         }
 
         let project = await!(self.client.clone().project(mr.project_id))?;
-        let repo_config = await!(self.clone().cached_repo_config(project.clone()))?;
 
         // Load source branch.
         let source_branch = await!(
@@ -294,18 +1306,60 @@ impl Bot {
                 .branch(mr.project_id, mr.source_branch.clone())
         )?;
 
+        // The diff is only loaded here, diagnostics use the changed lines.
+        let changes = await!(self.client.clone().merge_request_changes(mr.project_id, mr.iid))?;
+        let changes_config = changes
+            .changes
+            .iter()
+            .any(|c| c.new_path == REPO_CONFIG_PATH || c.old_path == REPO_CONFIG_PATH);
+        let changed_lines = diff::changed_lines(&changes.changes);
+        let source_config = if changes_config {
+            Some(await!(self.clone().load_repo_config(
+                mr.project_id,
+                source_branch.name.clone(),
+                source_branch.commit.clone()
+            ))?)
+        } else {
+            None
+        };
+
+        let (repo_config, config_revision) = await!(self.clone().merge_request_config(
+            project.clone(),
+            mr.clone(),
+            source_config.clone()
+        ))?;
+
+        // Problems are shown where the config takes effect when merged.
+        let config_problems = match source_config {
+            Some((_, revision)) => revision.problems,
+            None if mr.target_branch == project.default_branch => {
+                config_revision.problems.clone()
+            }
+            None => Vec::new(),
+        };
+
         let source_branch_commits = await!(self.client.clone().commits(
             mr.project_id,
             mr.source_branch.clone(),
-            100
+            self.config.commit_depth
         ))?;
 
         let target_branch_commits = await!(self.client.clone().commits(
             mr.project_id,
             mr.target_branch.clone(),
-            100
+            self.config.commit_depth
         ))?;
 
+        let commits = if repo_config.commits.is_some() {
+            await!(
+                self.client
+                    .clone()
+                    .merge_request_commits(mr.project_id, mr.iid)
+            )?
+        } else {
+            Vec::new()
+        };
+
         // Load comments.
         let comments = await!(
             self.client
@@ -318,75 +1372,1103 @@ impl Bot {
             .map(|x| x.clone())
             .collect::<Vec<_>>();
 
-        let pipelines = await!(
-            self.client
-                .clone()
-                .merge_request_pipelines(mr.project_id, mr.iid)
-        )?;
+        let pipelines = match pipelines {
+            Some(p) => p,
+            None => await!(
+                self.client
+                    .clone()
+                    .merge_request_pipelines(mr.project_id, mr.iid)
+            )?,
+        };
 
-        let full = FullMergeRequest {
+        let mut full = FullMergeRequest {
             project,
             request: mr,
             source_branch,
             source_branch_commits,
             target_branch_commits: target_branch_commits,
+            commits,
             comments,
             bot_comments,
+            bot_markers: HashMap::new(),
             pipelines,
+            changed_lines,
             repo_config,
+            config_revision,
+            config_problems,
+            bot_changed_at: None,
+        };
+        full.parse_markers();
+
+        self.cache.set_merge_request(full.clone());
+        Ok(full)
+    }
+
+    /// Render a comment template.
+    ///
+    /// Falls back to the built-in template if the repo template is broken.
+    fn render_template<T: ::serde::Serialize>(
+        &self,
+        mr: &FullMergeRequest,
+        name: &str,
+        context: &T,
+    ) -> Result<String, Error> {
+        self.render_repo_template(&mr.repo_config, mr.request.project_id, name, context)
+    }
+
+    /// Render a comment template with the overrides of a repo config.
+    fn render_repo_template<T: ::serde::Serialize>(
+        &self,
+        repo_config: &RepoConfig,
+        project_id: u64,
+        name: &str,
+        context: &T,
+    ) -> Result<String, Error> {
+        if let Some(res) = repo_config.templates.render_override(name, context) {
+            match res {
+                Ok(body) => return Ok(body),
+                Err(e) => {
+                    warn!(self.log, "template_render_failed";
+                        "project_id" => project_id,
+                        "template" => name,
+                        "error" => e.to_string(),
+                    );
+                }
+            }
+        }
+        templates::render_default(name, context)
+    }
+
+    /// Post a new merge request comment with the marker and record it in
+    /// the action log.
+    #[async]
+    fn create_comment(
+        self,
+        project_id: u64,
+        iid: u64,
+        marker: Marker,
+        body: String,
+    ) -> Result<(), Error> {
+        let body = marker.embed(&body);
+        await!(self.client.clone().merge_request_comment_create(project_id, iid, body))?;
+        self.cache.add_action(store::Action::new(
+            store::ActionKind::CommentCreated,
+            project_id,
+            iid,
+            None,
+            &marker.kind,
+        ));
+        Ok(())
+    }
+
+    /// Update a merge request comment with the marker and record it in the
+    /// action log.
+    #[async]
+    fn update_comment(
+        self,
+        project_id: u64,
+        iid: u64,
+        note_id: u64,
+        marker: Marker,
+        body: String,
+    ) -> Result<(), Error> {
+        let body = marker.embed(&body);
+        await!(self.client.clone().merge_request_comment_update(project_id, iid, note_id, body))?;
+        self.cache.add_action(store::Action::new(
+            store::ActionKind::CommentUpdated,
+            project_id,
+            iid,
+            Some(note_id),
+            &marker.kind,
+        ));
+        Ok(())
+    }
+
+    /// Delete a merge request comment and record it in the action log.
+    #[async]
+    fn delete_comment(
+        self,
+        project_id: u64,
+        iid: u64,
+        note_id: u64,
+        kind: &'static str,
+    ) -> Result<(), Error> {
+        await!(self.client.clone().merge_request_comment_delete(project_id, iid, note_id))?;
+        self.cache.add_action(store::Action::new(
+            store::ActionKind::CommentDeleted,
+            project_id,
+            iid,
+            Some(note_id),
+            kind,
+        ));
+        Ok(())
+    }
+
+    /// Update attributes of a merge request and record it in the action log.
+    #[async]
+    fn update_merge_request(
+        self,
+        project_id: u64,
+        iid: u64,
+        marker: String,
+        changes: ::serde_json::Value,
+    ) -> Result<(), Error> {
+        await!(self.client.clone().merge_request_update(project_id, iid, changes))?;
+        self.cache.add_action(store::Action::new(
+            store::ActionKind::MergeRequestUpdated,
+            project_id,
+            iid,
+            None,
+            &marker,
+        ));
+        Ok(())
+    }
+
+    /// Close or reopen a merge request and record it in the action log.
+    #[async]
+    fn set_merge_request_state(
+        self,
+        project_id: u64,
+        iid: u64,
+        marker: String,
+        state_event: &'static str,
+    ) -> Result<(), Error> {
+        await!(self.client.clone().merge_request_set_state(
+            project_id,
+            iid,
+            state_event.to_string()
+        ))?;
+        let kind = if state_event == "close" {
+            store::ActionKind::MergeRequestClosed
+        } else {
+            store::ActionKind::MergeRequestReopened
+        };
+        self.cache.add_action(store::Action::new(kind, project_id, iid, None, &marker));
+        Ok(())
+    }
+
+    /// Start a diff discussion with the marker and record it in the action
+    /// log.
+    #[async]
+    fn create_discussion(
+        self,
+        project_id: u64,
+        iid: u64,
+        marker: Marker,
+        body: String,
+        position: types::DiffPosition,
+    ) -> Result<(), Error> {
+        await!(self.client.clone().merge_request_discussion_create(
+            project_id,
+            iid,
+            marker.embed(&body),
+            position
+        ))?;
+        self.cache.add_action(store::Action::new(
+            store::ActionKind::DiscussionCreated,
+            project_id,
+            iid,
+            None,
+            &marker.kind,
+        ));
+        Ok(())
+    }
+
+    /// Post compiler diagnostics that point to changed lines as diff
+    /// discussions.
+    ///
+    /// Returns the number of diagnostics on changed lines.
+    #[async]
+    fn post_diagnostics(
+        self,
+        mr: FullMergeRequest,
+        pipeline_sha: String,
+        diagnostics: Vec<reports::cargo::Diagnostic>,
+    ) -> Result<usize, Error> {
+        let project_id = mr.request.project_id;
+        let iid = mr.request.iid;
+
+        let versions = await!(self.client.clone().merge_request_versions(project_id, iid))?;
+        let version = match versions.into_iter().next() {
+            Some(v) => v,
+            None => return Ok(0),
+        };
+        // Line numbers of an outdated pipeline do not match the diff, and
+        // the changed lines were loaded for the head commit of the request.
+        if version.head_commit_sha != pipeline_sha || mr.request.sha != pipeline_sha {
+            return Ok(0);
+        }
+
+        let on_changed_lines = diagnostics
+            .into_iter()
+            .filter(|d| {
+                mr.changed_lines
+                    .get(&d.file)
+                    .map(|lines| lines.contains(&d.line))
+                    .unwrap_or(false)
+            })
+            .collect::<Vec<_>>();
+        let count = on_changed_lines.len();
+
+        let mut posted = 0;
+        for diagnostic in on_changed_lines {
+            if posted >= MAX_DISCUSSIONS_PER_RUN {
+                break;
+            }
+            let marker = diagnostic.marker();
+            if mr.has_bot_comment(&marker) || self.cache.has_posted(project_id, iid, &marker) {
+                continue;
+            }
+
+            let position = types::DiffPosition {
+                base_sha: version.base_commit_sha.clone(),
+                start_sha: version.start_commit_sha.clone(),
+                head_sha: version.head_commit_sha.clone(),
+                position_type: "text".to_string(),
+                old_path: diagnostic.file.clone(),
+                new_path: diagnostic.file.clone(),
+                new_line: diagnostic.line,
+            };
+            await!(self.clone().create_discussion(
+                project_id,
+                iid,
+                Marker::new(marker).with_sha(pipeline_sha.clone()),
+                diagnostic.render(),
+                position
+            ))?;
+            posted += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Count reminders with the marker since the last activity, from both
+    /// the comments and the action log, in case comments were deleted.
+    fn reminders_since_activity(&self, mr: &FullMergeRequest, marker: &str) -> usize {
+        let since = mr.last_activity();
+        let comments = mr.bot_comments_since(marker, since);
+        let actions =
+            self.cache
+                .posted_since(mr.request.project_id, mr.request.iid, marker, since);
+        ::std::cmp::max(comments, actions)
+    }
+
+    /// Check if reminders and stale warnings were paused with `snooze`.
+    fn is_snoozed(&self, mr: &FullMergeRequest) -> bool {
+        self.cache
+            .snoozed_until(mr.request.project_id, mr.request.iid)
+            .map(|until| until > Utc::now())
+            .unwrap_or(false)
+    }
+
+    /// Post a reminder or fire an escalation step if the merge request is
+    /// idle.
+    ///
+    /// Returns true if an escalation step closed the merge request.
+    #[async]
+    fn process_merge_request_reminder(self, mr: FullMergeRequest) -> Result<bool, Error> {
+        let config = mr.repo_config.reminders.clone();
+        if config.is_skipped(&mr.request) || self.is_snoozed(&mr) {
+            return Ok(false);
+        }
+
+        let calendar = self.calendar(&mr);
+        let idle_days = calendar.working_days_since(mr.last_activity());
+
+        if config.escalation.len() > 0 {
+            return await!(self.process_merge_request_escalation(mr, idle_days));
+        }
+
+        // Check if time reminder is needed.
+        let reminder_days = config.days();
+        if idle_days >= reminder_days {
+            let has_reminder =
+                mr.has_recent_bot_comment(markers::REMINDER, reminder_days, &calendar);
+            let count = self.reminders_since_activity(&mr, markers::REMINDER);
+
+            if has_reminder == false && config.below_max_count(count) {
+                // Create a new reminder comment.
+
+                let context = templates::ReminderContext {
+                    merge_request: mr.request.clone(),
+                    message: config.message(&mr.request)?,
+                    idle_days,
+                };
+                let body = self.render_template(&mr, templates::REMINDER, &context)?;
+
+                await!(self.clone().create_comment(
+                    mr.request.project_id,
+                    mr.request.iid,
+                    Marker::new(markers::REMINDER),
+                    body
+                ))?;
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Fire the highest due step of the reminder escalation ladder, unless
+    /// it or a higher step ever fired on the merge request.
+    ///
+    /// Returns true if the step closed the merge request.
+    #[async]
+    fn process_merge_request_escalation(
+        self,
+        mr: FullMergeRequest,
+        idle_days: i64,
+    ) -> Result<bool, Error> {
+        let config = mr.repo_config.reminders.clone();
+        let project_id = mr.request.project_id;
+        let iid = mr.request.iid;
+        let index = {
+            let fired = |marker: &str| {
+                mr.has_bot_comment(marker) || self.cache.has_posted(project_id, iid, marker)
+            };
+            match config.step_to_fire(idle_days, fired) {
+                Some(i) => i,
+                None => return Ok(false),
+            }
+        };
+
+        let step = config.escalation[index].clone();
+        let marker = EscalationStep::marker(index);
+
+        debug!(self.log, "reminder_escalation";
+            "mr_id" => mr.request.id,
+            "step" => index + 1,
+            "idle_days" => idle_days,
+        );
+
+        let context = templates::ReminderContext {
+            merge_request: mr.request.clone(),
+            message: step.message(&mr.request)?,
+            idle_days,
         };
+        let body = self.render_template(&mr, templates::REMINDER, &context)?;
+        await!(self.clone().create_comment(project_id, iid, Marker::new(marker.clone()), body))?;
+
+        let new_labels = step.add_labels
+            .iter()
+            .filter(|l| !mr.request.labels.contains(l))
+            .cloned()
+            .collect::<Vec<_>>();
+        if new_labels.len() > 0 {
+            let mut labels = mr.request.labels.clone();
+            labels.extend(new_labels);
+            await!(self.clone().update_merge_request(
+                project_id,
+                iid,
+                marker.clone(),
+                json!({ "labels": labels.join(",") })
+            ))?;
+        }
+
+        if !step.close {
+            return Ok(false);
+        }
+        await!(self.close_stale_merge_request(mr, idle_days))?;
+        Ok(true)
+    }
+
+    /// Run a command given in a comment.
+    #[async]
+    fn run_command(self, mr: FullMergeRequest, cmd: commands::Command) -> Result<(), Error> {
+        let project_id = mr.request.project_id;
+        let iid = mr.request.iid;
+        match cmd {
+            commands::Command::Retry => {
+                let pipeline_id = match mr.pipelines.first() {
+                    Some(p) => p.id,
+                    None => bail!("There is no pipeline to retry."),
+                };
+                await!(self.client.clone().pipeline_retry(project_id, pipeline_id))?;
+            }
+            commands::Command::Rebase => {
+                await!(self.client.clone().merge_request_rebase(project_id, iid))?;
+            }
+            commands::Command::Recheck => {
+                self.cache.invalidate_merge_request(mr.request.id);
+            }
+            commands::Command::Snooze(_) | commands::Command::Help => {}
+        }
+        Ok(())
+    }
+
+    /// Handle the commands in comments addressed to the bot.
+    ///
+    /// Only the author of the merge request and project members with at
+    /// least developer access may run commands.
+    /// Every comment is claimed before its commands run, so they never run
+    /// twice. Comments the bot already awarded or replied to are claimed
+    /// without running them.
+    /// Returns true if the merge request should be reloaded.
+    #[async]
+    fn process_merge_request_commands(
+        self,
+        bot: types::User,
+        mr: FullMergeRequest,
+    ) -> Result<bool, Error> {
+        let project_id = mr.request.project_id;
+        let iid = mr.request.iid;
+        let min_created_at = Utc::now() - Duration::hours(COMMAND_MAX_AGE_HOURS);
+
+        let notes = mr.comments
+            .iter()
+            .filter(|c| {
+                c.system != Some(true) && c.created_at > min_created_at
+                    && c.author.as_ref().map(|a| a.id != bot.id).unwrap_or(false)
+                    && !self.cache.is_command_processed(c.id)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut reload = false;
+        // Loaded for the first comment not written by the author.
+        let mut members: Option<Vec<types::Member>> = None;
+        for note in notes {
+            let cmds = commands::parse(&note.body, &bot.username);
+            if cmds.len() == 0 {
+                continue;
+            }
+            let (author_id, author) = note.author
+                .as_ref()
+                .map(|a| (a.id, a.username.clone()))
+                .unwrap_or_default();
+
+            // The cache forgets handled comments on restarts without a
+            // state dir, but the award or reply of the bot stays.
+            let handled = if mr.has_command_reply(note.id) {
+                true
+            } else {
+                let awards = await!(self.client.clone().merge_request_comment_awards(
+                    project_id,
+                    iid,
+                    note.id
+                ))?;
+                awards.iter().any(|a| a.user.id == bot.id)
+            };
+
+            let allowed = if author_id == mr.request.author.id {
+                true
+            } else {
+                if members.is_none() {
+                    members = Some(await!(self.client.clone().project_members(project_id))?);
+                }
+                members.as_ref().map_or(false, |members| {
+                    members
+                        .iter()
+                        .any(|m| m.id == author_id && m.access_level >= types::DEVELOPER_ACCESS)
+                })
+            };
+
+            // Snoozes that end out of range are answered like invalid
+            // durations. Those of handled comments are restored from the
+            // time of the comment.
+            let now = if handled { note.created_at } else { Utc::now() };
+            let mut snooze_end = None;
+            let cmds = cmds.into_iter()
+                .map(|c| match c {
+                    Ok(commands::Command::Snooze(days)) => {
+                        match now.checked_add_signed(Duration::days(days)) {
+                            Some(end) => {
+                                snooze_end = ::std::cmp::max(snooze_end, Some(end));
+                                Ok(commands::Command::Snooze(days))
+                            }
+                            None => Err(commands::invalid_duration(&format!("{}d", days))),
+                        }
+                    }
+                    other => other,
+                })
+                .collect::<Vec<_>>();
+            let snoozed_until = if allowed { snooze_end } else { None };
+            let names = cmds.iter()
+                .map(|c| c.as_ref().map(|c| c.name()).unwrap_or("invalid"))
+                .collect::<Vec<_>>()
+                .join(",");
+            let claimed = self.cache.claim_command(store::ProcessedCommand {
+                project_id,
+                merge_request_iid: iid,
+                note_id: note.id,
+                command: if allowed { names } else { "denied".to_string() },
+                snoozed_until,
+                processed_at: Utc::now(),
+            });
+            if !claimed || handled {
+                continue;
+            }
+
+            if !allowed {
+                info!(self.log, "command_denied";
+                    "project_id" => project_id,
+                    "mr_id" => mr.request.id,
+                    "note_id" => note.id,
+                    "author" => &author,
+                );
+                let context = templates::CommandReplyContext {
+                    merge_request: mr.request.clone(),
+                    author,
+                    replies: vec![
+                        "Only the author of the merge request and members with developer \
+                         access can run commands."
+                            .to_string(),
+                    ],
+                };
+                let body = self.render_template(&mr, templates::COMMAND_REPLY, &context)?;
+                await!(self.clone().create_comment(
+                    project_id,
+                    iid,
+                    Marker::new(markers::COMMAND_REPLY).with_note_id(note.id),
+                    body
+                ))?;
+                continue;
+            }
+
+            let mut replies = Vec::new();
+            let mut acknowledge = false;
+            for cmd in cmds {
+                let cmd = match cmd {
+                    Ok(cmd) => cmd,
+                    Err(msg) => {
+                        replies.push(format!("{}\n\n{}", msg, commands::help(&bot.username)));
+                        continue;
+                    }
+                };
+                info!(self.log, "command_received";
+                    "project_id" => project_id,
+                    "mr_id" => mr.request.id,
+                    "note_id" => note.id,
+                    "command" => cmd.name(),
+                );
+                if cmd == commands::Command::Help {
+                    replies.push(commands::help(&bot.username));
+                    continue;
+                }
+                if cmd == commands::Command::Recheck {
+                    reload = true;
+                }
+                let name = cmd.name();
+                match await!(self.clone().run_command(mr.clone(), cmd)) {
+                    Ok(_) => acknowledge = true,
+                    Err(e) => {
+                        warn!(self.log, "command_failed";
+                            "project_id" => project_id,
+                            "mr_id" => mr.request.id,
+                            "command" => name,
+                            "error" => e.to_string(),
+                        );
+                        replies.push(format!("`{}` failed: {}", name, e));
+                    }
+                }
+            }
+
+            if acknowledge {
+                await!(self.client.clone().merge_request_comment_award(
+                    project_id,
+                    iid,
+                    note.id,
+                    "thumbsup".to_string()
+                ))?;
+            }
+            if replies.len() > 0 {
+                let context = templates::CommandReplyContext {
+                    merge_request: mr.request.clone(),
+                    author,
+                    replies,
+                };
+                let body = self.render_template(&mr, templates::COMMAND_REPLY, &context)?;
+                await!(self.clone().create_comment(
+                    project_id,
+                    iid,
+                    Marker::new(markers::COMMAND_REPLY).with_note_id(note.id),
+                    body
+                ))?;
+            }
+        }
+
+        Ok(reload)
+    }
+
+    /// Retry failed jobs with a trace matching a flaky pattern, up to the
+    /// configured number of retries per pipeline.
+    ///
+    /// Returns all automatic retries of the pipeline.
+    #[async]
+    fn retry_flaky_jobs(
+        self,
+        mr: FullMergeRequest,
+        pipeline_id: u64,
+        failed: Vec<(types::Job, String)>,
+    ) -> Result<Vec<store::Action>, Error> {
+        let config = mr.repo_config.flaky.clone();
+        let project_id = mr.request.project_id;
+        let iid = mr.request.iid;
+        let prefix = flaky::pipeline_marker_prefix(pipeline_id);
+
+        for (job, trace) in failed {
+            let marker = flaky::retry_marker(pipeline_id, job.id);
+            let retried =
+                self.cache
+                    .find_actions(project_id, iid, store::ActionKind::JobRetried, &marker);
+            if retried.len() > 0 {
+                continue;
+            }
+            let reason = match config.matches(&trace) {
+                Some(reason) => reason,
+                None => continue,
+            };
+            let count = self.cache
+                .find_actions(project_id, iid, store::ActionKind::JobRetried, &prefix)
+                .len();
+            if count >= config.max_retries() {
+                debug!(self.log, "flaky_retry_limit_reached";
+                    "project_id" => project_id,
+                    "pipeline_id" => pipeline_id,
+                    "job_id" => job.id,
+                );
+                break;
+            }
+
+            info!(self.log, "retrying_flaky_job";
+                "project_id" => project_id,
+                "pipeline_id" => pipeline_id,
+                "job_id" => job.id,
+                "reason" => &reason,
+            );
+            await!(self.client.clone().job_retry(project_id, job.id))?;
+            let mut action =
+                store::Action::new(store::ActionKind::JobRetried, project_id, iid, None, &marker);
+            action.details = Some(format!(
+                "[{}]({}): {}",
+                job.name,
+                mr.job_url(job.id),
+                reason
+            ));
+            self.cache.add_action(action);
+        }
+
+        Ok(self.cache
+            .find_actions(project_id, iid, store::ActionKind::JobRetried, &prefix))
+    }
+
+    /// Warn about and close stale merge requests.
+    ///
+    /// Returns true if the merge request was closed.
+    #[async]
+    fn process_merge_request_stale(self, mr: FullMergeRequest) -> Result<bool, Error> {
+        let config = mr.repo_config.stale.clone();
+        if config.is_exempt(&mr.request) || self.is_snoozed(&mr) {
+            return Ok(false);
+        }
 
-        self.cache.set_merge_request(full.clone());
-        Ok(full)
+        let project_id = mr.request.project_id;
+        let iid = mr.request.iid;
+        let calendar = self.calendar(&mr);
+        let last_activity = mr.last_activity();
+
+        // Only a warning without activity afterwards is pending. The action
+        // log also knows warnings posted after the merge request was loaded.
+        let logged = self.cache
+            .find_actions(
+                project_id,
+                iid,
+                store::ActionKind::CommentCreated,
+                stale::WARNING_MARKER,
+            )
+            .into_iter()
+            .map(|a| a.created_at);
+        let warned_at = mr.bot_comments_of_kind(stale::WARNING_MARKER)
+            .iter()
+            .map(|c| c.created_at)
+            .chain(logged)
+            .filter(|t| *t > last_activity)
+            .max();
+
+        match config.action(&calendar, last_activity, warned_at, Utc::now()) {
+            None => Ok(false),
+            Some(stale::StaleAction::Warn(idle_days)) => {
+                let context = config.context(&mr.request, idle_days);
+                let body = self.render_template(&mr, templates::STALE_WARNING, &context)?;
+                await!(self.clone().create_comment(
+                    project_id,
+                    iid,
+                    Marker::new(stale::WARNING_MARKER),
+                    body
+                ))?;
+                Ok(false)
+            }
+            Some(stale::StaleAction::Close) => {
+                let idle_days = calendar.working_days_since(last_activity);
+                await!(self.close_stale_merge_request(mr, idle_days))?;
+                Ok(true)
+            }
+        }
     }
 
+    /// Close an idle merge request with a comment.
+    ///
+    /// Used by the stale rules and escalation steps, so both are reopened
+    /// on replies.
     #[async]
-    fn process_merge_request_reminder(self, mr: FullMergeRequest) -> Result<(), Error> {
-        // Check if time reminder is needed.
-        let now = Utc::now();
-        let reminder_days = 5;
-        if mr.source_branch.commit.committed_date < now - Duration::days(reminder_days) {
-            let has_reminder = mr.has_bot_comment("[reminder]", Some(reminder_days));
+    fn close_stale_merge_request(self, mr: FullMergeRequest, idle_days: i64) -> Result<(), Error> {
+        let project_id = mr.request.project_id;
+        let iid = mr.request.iid;
+        info!(self.log, "closing_stale_merge_request";
+            "project_id" => project_id,
+            "mr_id" => mr.request.id,
+        );
+        let context = mr.repo_config.stale.context(&mr.request, idle_days);
+        let body = self.render_template(&mr, templates::STALE_CLOSE, &context)?;
+        await!(self.clone().create_comment(
+            project_id,
+            iid,
+            Marker::new(stale::CLOSED_MARKER),
+            body
+        ))?;
+        await!(self.set_merge_request_state(
+            project_id,
+            iid,
+            stale::CLOSED_MARKER.to_string(),
+            "close"
+        ))?;
+        Ok(())
+    }
 
-            if has_reminder == false {
-                // Create a new reminder comment.
+    /// Reopen a merge request closed as stale if someone replied after it
+    /// was closed.
+    #[async]
+    fn process_stale_reopen(self, bot_id: u64, mr: types::MergeRequest) -> Result<(), Error> {
+        if mr.state != "closed" {
+            return Ok(());
+        }
+        let project_id = mr.project_id;
+        let iid = mr.iid;
+
+        let project = await!(self.client.clone().project(project_id))?;
+        let (repo_config, _) =
+            await!(self.clone().cached_repo_config(project_id, project.default_branch))?;
+        let closes = repo_config.stale.enabled
+            || repo_config.reminders.escalation.iter().any(|s| s.close);
+        if !closes {
+            return Ok(());
+        }
+
+        let comments = await!(self.client.clone().merge_request_comments(project_id, iid))?;
+        let is_bot = |c: &types::Note| c.author.as_ref().map(|a| a.id == bot_id).unwrap_or(false);
+        let closed_at = comments
+            .iter()
+            .filter(|c| {
+                is_bot(c)
+                    && Marker::parse(&c.body)
+                        .map(|m| m.kind == stale::CLOSED_MARKER)
+                        .unwrap_or(false)
+            })
+            .map(|c| c.created_at)
+            .max();
+        let closed_at = match closed_at {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        let replied = comments
+            .iter()
+            .any(|c| !is_bot(c) && c.system != Some(true) && c.created_at > closed_at);
+        if !replied {
+            return Ok(());
+        }
 
-                let body = format!(
-                    "@{} friendly reminder: this merge request has not been updated for {} days!\nLet's get going! ;)\n\n[reminder]",
-                    mr.request.author.username, reminder_days);
+        info!(self.log, "reopening_stale_merge_request";
+            "project_id" => project_id,
+            "mr_id" => mr.id,
+        );
+        await!(self.clone().set_merge_request_state(
+            project_id,
+            iid,
+            stale::REOPENED_MARKER.to_string(),
+            "reopen"
+        ))?;
+        let context = repo_config.stale.context(&mr, 0);
+        let body =
+            self.render_repo_template(&repo_config, project_id, templates::STALE_REOPEN, &context)?;
+        await!(self.clone().create_comment(
+            project_id,
+            iid,
+            Marker::new(stale::REOPENED_MARKER),
+            body
+        ))?;
+        self.cache.invalidate_merge_request(mr.id);
 
-                await!(self.client.clone().merge_request_comment_create(
-                    mr.request.project_id,
-                    mr.request.iid,
-                    body
-                ))?;
+        Ok(())
+    }
+
+    /// Check merge requests that were recently closed as stale for replies.
+    #[async]
+    fn process_stale_reopens(self, bot_id: u64) -> Result<(), Error> {
+        let since = Utc::now() - Duration::days(STALE_REOPEN_WINDOW_DAYS);
+        let closed = self.cache
+            .closed_merge_requests(stale::CLOSED_MARKER, since);
+
+        for (project_id, iid) in closed {
+            let res = await!(
+                self.client
+                    .clone()
+                    .merge_request(project_id, iid)
+                    .map_err(Error::from)
+                    .and_then({
+                        let bot = self.clone();
+                        move |mr| bot.process_stale_reopen(bot_id, mr)
+                    })
+            );
+            if let Err(e) = res {
+                error!(self.log, "stale_reopen_check_failed";
+                    "project_id" => project_id,
+                    "merge_request_iid" => iid,
+                    "error" => e.to_string(),
+                );
             }
         }
 
         Ok(())
     }
 
+    /// Load the coverage of a report from the latest successful pipeline of
+    /// the target branch.
+    ///
+    /// The result is cached per commit of the target branch, so the artifact
+    /// is only downloaded again once the target branch has a new pipeline.
     #[async]
-    fn process_merge_request(self, bot: types::User, mr: types::MergeRequest) -> Result<(), Error> {
-        trace!(self.log, "process_merge_request_start";
-            "project_id" => mr.project_id,
-            "merge_request_id" => mr.id,
+    fn base_coverage(
+        self,
+        mr: FullMergeRequest,
+        report: ReportConfig,
+        format: reports::ReportFormat,
+    ) -> Result<Option<reports::coverage::Coverage>, Error> {
+        let project_id = mr.request.project_id;
+        let pipelines = await!(self.client.clone().branch_pipelines(
+            project_id,
+            mr.request.target_branch.clone(),
+            "success".to_string()
+        ))?;
+        let pipeline = match pipelines.into_iter().next() {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+
+        let key = (
+            project_id,
+            pipeline.sha.clone(),
+            report.job_name.clone(),
+            report.path.clone(),
         );
-        let project_id = mr.project_id;
-        let updated_at = mr.updated_at.clone();
+        if let Some(coverage) = self.cache.get_base_coverage(&key) {
+            return Ok(coverage);
+        }
 
-        let mr = await!(self.clone().full_merge_request(mr, bot.id))?;
+        let jobs = await!(self.client.clone().pipeline_jobs(project_id, pipeline.id))?;
+        let job = jobs.into_iter()
+            .filter(|j| j.name == report.job_name && j.status == "success")
+            .max_by_key(|j| j.id);
+        let job = match job {
+            Some(j) => j,
+            None => {
+                self.cache.set_base_coverage(key, None);
+                return Ok(None);
+            }
+        };
 
-        if mr.repo_config.is_disabled() {
-            return Ok(());
+        let data = await!(self.client.clone().job_artifact_file(
+            project_id,
+            job.id,
+            report.path.clone(),
+            report.max_download_size(format)
+        ))?;
+        let coverage = reports::coverage::parse(format, &data)?;
+        self.cache.set_base_coverage(key, Some(coverage.clone()));
+        Ok(Some(coverage))
+    }
+
+    /// Render the configured artifact reports of a pipeline.
+    ///
+    /// Summaries of all reports are returned as one line each, the bodies
+    /// combined in a Reports section.
+    /// The coverage of the first coverage report is returned for validation.
+    #[async]
+    fn render_reports(
+        self,
+        mr: FullMergeRequest,
+        jobs: Vec<types::Job>,
+    ) -> Result<reports::Rendered, Error> {
+        let project_id = mr.request.project_id;
+        let mut summary = String::new();
+        let mut body = String::new();
+        let mut coverage = None;
+
+        for report in mr.repo_config.reports.clone() {
+            let title = report.title().to_string();
+
+            let format = match report.report_format() {
+                Ok(f) => f,
+                Err(e) => {
+                    body.push_str(&format!("### {}\n\n:warning: {}\n\n", title, e));
+                    continue;
+                }
+            };
+
+            // Use the newest job with the name, to pick up retried jobs.
+            let job = jobs.iter()
+                .filter(|j| j.name == report.job_name)
+                .max_by_key(|j| j.id)
+                .cloned();
+            let job = match job {
+                Some(j) => j,
+                None => {
+                    body.push_str(&format!(
+                        "### {}\n\n:warning: No job named `{}` in the pipeline.\n\n",
+                        title, report.job_name
+                    ));
+                    continue;
+                }
+            };
+            match job.status.as_str() {
+                "success" | "failed" => {}
+                // These never produce artifacts without someone starting
+                // the job again.
+                "canceled" | "skipped" | "manual" => {
+                    body.push_str(&format!(
+                        "### {}\n\n:warning: Job [{}]({}) did not run ({}).\n\n",
+                        title,
+                        job.name,
+                        mr.job_url(job.id),
+                        job.status
+                    ));
+                    continue;
+                }
+                _ => {
+                    body.push_str(&format!(
+                        "### {}\n\nWaiting for job [{}]({})...\n\n",
+                        title,
+                        job.name,
+                        mr.job_url(job.id)
+                    ));
+                    continue;
+                }
+            }
+
+            let data_res = await!(self.client.clone().job_artifact_file(
+                project_id,
+                job.id,
+                report.path.clone(),
+                report.max_download_size(format)
+            ));
+            let data_res = data_res.map_err(|e| {
+                format_err!(
+                    "Could not load artifact `{}` from job [{}]({}): {}",
+                    report.path,
+                    job.name,
+                    mr.job_url(job.id),
+                    e
+                )
+            });
+
+            let rendered = match data_res {
+                Ok(data) => {
+                    if format == reports::ReportFormat::Cargo {
+                        match reports::cargo::parse(&data) {
+                            Ok(diagnostics) => {
+                                let post_res = await!(self.clone().post_diagnostics(
+                                    mr.clone(),
+                                    job.commit.id.clone(),
+                                    diagnostics.clone()
+                                ));
+                                let on_changed_lines = post_res.unwrap_or_else(|e| {
+                                    warn!(self.log, "posting_diagnostics_failed";
+                                        "mr_id" => mr.request.id,
+                                        "error" => e.to_string(),
+                                    );
+                                    0
+                                });
+                                Ok(reports::Rendered {
+                                    summary: Some(reports::cargo::summary(
+                                        &title,
+                                        &diagnostics,
+                                        on_changed_lines,
+                                    )),
+                                    body: None,
+                                    coverage: None,
+                                })
+                            }
+                            Err(e) => Err(e),
+                        }
+                    } else if format.is_coverage() {
+                        // Compare against the target branch. Missing base
+                        // coverage only means no delta can be shown.
+                        let base_res = await!(self.clone().base_coverage(
+                            mr.clone(),
+                            report.clone(),
+                            format
+                        ));
+                        let base = base_res.unwrap_or_else(|e| {
+                            debug!(self.log, "base_coverage_failed";
+                                "mr_id" => mr.request.id,
+                                "job_name" => &report.job_name,
+                                "error" => e.to_string(),
+                            );
+                            None
+                        });
+                        reports::coverage::parse(format, &data).map(|current| {
+                            let change = reports::coverage::CoverageChange { current, base };
+                            reports::coverage::render(&title, change, &mr.request.target_branch)
+                        })
+                    } else {
+                        reports::render(format, &title, &data, report.max_size())
+                    }
+                }
+                Err(e) => Err(e),
+            };
+
+            match rendered {
+                Ok(r) => {
+                    if let Some(s) = r.summary {
+                        summary.push_str(&format!("{}\n", s));
+                    }
+                    if let Some(b) = r.body {
+                        body.push_str(&format!("### {}\n\n{}\n\n", title, b));
+                    }
+                    if coverage.is_none() {
+                        coverage = r.coverage;
+                    }
+                }
+                Err(e) => {
+                    debug!(self.log, "report_failed";
+                        "mr_id" => mr.request.id,
+                        "job_name" => &report.job_name,
+                        "path" => &report.path,
+                        "error" => e.to_string(),
+                    );
+                    body.push_str(&format!("### {}\n\n:warning: {}\n\n", title, e));
+                }
+            }
         }
 
-        // If the MR has not been updated for X days, post a reminder comment.
-        await!(self.clone().process_merge_request_reminder(mr.clone()))?;
+        Ok(reports::Rendered {
+            summary: if summary == "" { None } else { Some(summary) },
+            body: if body == "" {
+                None
+            } else {
+                Some(format!("## Reports\n\n{}", body))
+            },
+            coverage,
+        })
+    }
 
-        let mut msg = String::new();
+    /// Render the report of a merge request.
+    ///
+    /// Also posts the title, branch name and commit warnings and retries
+    /// flaky jobs.
+    #[async]
+    fn build_report(self, mr: FullMergeRequest) -> Result<String, Error> {
+        let project_id = mr.request.project_id;
 
-        let mut validation_msg = String::new();
+        let mut validation = Vec::new();
 
         if let Some(mr_config) = mr.repo_config.merge_requests.clone() {
             // If configured, validate the merge request title.
@@ -394,7 +2476,8 @@ impl Bot {
                 let is_valid = re.is_match(&mr.request.title);
                 if !is_valid {
                     // Check if warning is needed.
-                    let has_warning = mr.has_bot_comment("[title_warning]", None);
+                    let has_warning = mr.has_bot_comment(markers::TITLE_WARNING)
+                        || self.cache.has_posted(project_id, mr.request.iid, markers::TITLE_WARNING);
                     if !has_warning {
                         // No warning present, so post a comment.
                         let err = mr_config
@@ -405,22 +2488,25 @@ impl Bot {
                                 re.to_string()
                             ));
 
-                        let comment_body = format!(
-                            "@{}\n\nThe merge request title is invalid. \n{}\n\n[title_warning]",
-                            mr.request.author.username, err
-                        );
+                        let context = templates::WarningContext {
+                            merge_request: mr.request.clone(),
+                            author: mr.request.author.username.clone(),
+                            error: err,
+                        };
+                        let comment_body =
+                            self.render_template(&mr, templates::TITLE_WARNING, &context)?;
 
-                        await!(self.client.clone().merge_request_comment_create(
+                        await!(self.clone().create_comment(
                             project_id,
                             mr.request.iid,
+                            Marker::new(markers::TITLE_WARNING),
                             comment_body
                         ))?;
                     }
                 }
-                validation_msg.push_str(&format!(
-                    "- [{}] Valid Merge Request Title{} \n",
-                    if is_valid { "x" } else { " " },
-                    if is_valid { "" } else { " :warning:" },
+                validation.push(templates::ValidationItem::new(
+                    "Valid Merge Request Title",
+                    is_valid,
                 ));
             }
 
@@ -430,7 +2516,12 @@ impl Bot {
                 let is_valid = re.is_match(&mr.source_branch.name);
                 if !is_valid {
                     // Check if warning is needed.
-                    let has_warning = mr.has_bot_comment("[branch_name_warning]", None);
+                    let has_warning = mr.has_bot_comment(markers::BRANCH_NAME_WARNING)
+                        || self.cache.has_posted(
+                            project_id,
+                            mr.request.iid,
+                            markers::BRANCH_NAME_WARNING,
+                        );
                     if !has_warning {
                         // No warning present, so post a comment.
                         let err = mr_config
@@ -441,120 +2532,288 @@ impl Bot {
                                 re.to_string()
                             ));
 
-                        let comment_body = format!(
-                            "@{}\n\nThe branch name is invalid. \n{}\n\n[branch_name_warning]",
-                            mr.request.author.username, err
-                        );
-
                         debug!(self.log, "posting_branch_name_warning";
                             "branch_name" => &mr.source_branch.name,
                             "err" => &err,
                         );
 
-                        await!(self.client.clone().merge_request_comment_create(
+                        let context = templates::WarningContext {
+                            merge_request: mr.request.clone(),
+                            author: mr.request.author.username.clone(),
+                            error: err,
+                        };
+                        let comment_body =
+                            self.render_template(&mr, templates::BRANCH_WARNING, &context)?;
+
+                        await!(self.clone().create_comment(
                             project_id,
                             mr.request.iid,
+                            Marker::new(markers::BRANCH_NAME_WARNING),
                             comment_body
                         ))?;
                     }
                 }
-                validation_msg.push_str(&format!(
-                    "- [{}] Valid Branch Name{} \n",
-                    if is_valid { "x" } else { " " },
-                    if is_valid { "" } else { " :warning:" },
+                validation.push(templates::ValidationItem::new("Valid Branch Name", is_valid));
+            }
+
+            // If configured, validate the description.
+            validation.extend(mr_config.check_description(&mr.request.description));
+        }
+
+        // If configured, validate the commit messages.
+        if let Some(commits_config) = mr.repo_config.commits.clone() {
+            let invalid = commits_config.invalid_commits(&mr.commits);
+
+            if invalid.is_empty() {
+                validation.push(templates::ValidationItem::new("Valid Commit Messages", true));
+            }
+            for item in invalid.iter() {
+                validation.push(templates::ValidationItem::with_detail(
+                    format!("Valid Commit Message: {}", item.commit.short_id),
+                    false,
+                    item.problems.join("; "),
                 ));
             }
+
+            // Warn once about all invalid commits, and again if the problems
+            // change.
+            let marker = commits::warning_marker(&invalid);
+            let has_warning = invalid.is_empty() || mr.has_bot_comment(&marker)
+                || self.cache.has_posted(project_id, mr.request.iid, &marker);
+            if !has_warning {
+                debug!(self.log, "posting_commit_warning";
+                    "commits" => invalid.len(),
+                    "marker" => &marker,
+                );
+
+                let context = templates::CommitWarningContext {
+                    merge_request: mr.request.clone(),
+                    author: mr.request.author.username.clone(),
+                    commits: invalid,
+                };
+                let comment_body =
+                    self.render_template(&mr, templates::COMMIT_WARNING, &context)?;
+
+                await!(self.clone().create_comment(
+                    project_id,
+                    mr.request.iid,
+                    Marker::new(marker),
+                    comment_body
+                ))?;
+            }
         }
 
-        if mr.request.assignee.is_none() {
-            validation_msg.push_str("- [ ] Reviewer selected :warning: \n");
+        validation.push(templates::ValidationItem::new(
+            "Reviewer selected",
+            mr.request.assignee.is_some(),
+        ));
+
+        // Load jobs of the newest pipeline.
+        let jobs = if mr.pipelines.len() > 0 {
+            await!(self.client.clone().pipeline_jobs(project_id, mr.pipelines[0].id))?
         } else {
-            validation_msg.push_str("- [x] Reviewer selected \n");
-        }
+            Vec::new()
+        };
+
+        let rendered_reports = if mr.pipelines.len() > 0 && mr.repo_config.reports.len() > 0 {
+            await!(self.clone().render_reports(mr.clone(), jobs.clone()))?
+        } else {
+            reports::Rendered::default()
+        };
+
+        let mut pipeline_context = None;
+        let mut retried_jobs = Vec::new();
+        let mut failed_job_contexts = Vec::new();
+        let mut successful_job_contexts = Vec::new();
 
-        // Check if failed.
         if mr.pipelines.len() > 0 {
             let pipeline = mr.pipelines[0].clone();
 
-            // Found a pipeline.
-
             // Check jobs.
-            let jobs = await!(self.client.clone().pipeline_jobs(project_id, pipeline.id))?;
             let failed_jobs = jobs.iter()
                 .filter(|j| j.status == "failed")
                 .map(|x| x.clone())
                 .collect::<Vec<_>>();
-            let success_jobs = jobs.iter()
-                .filter(|j| j.status == "success")
-                .map(|x| x.clone())
-                .collect::<Vec<_>>();
 
-            msg.push_str("## Build Status\n\n");
+            // Retrieve logs.
+            let mut traces = Vec::new();
+            if pipeline.status == "failed" || mr.repo_config.flaky.patterns.len() > 0 {
+                for job in failed_jobs {
+                    let raw = await!(self.client.clone().job_trace(project_id, job.id))?;
+                    traces.push((job, trace::clean(&raw)));
+                }
+            }
 
-            /*
-            let mut artifacts_info = "";
-            if pipelie.status != "pending" {
-                for job in &jobs {
-                    for report in &mr.config.reports {
-                        if report.job_name == job.name {
-                            let file_res = await!(self.client.clone()
-                                .job_artifact_file(project_id, job.id, report.path.clone())
-                            );
-                        }
-                    }
+            let retries = if mr.repo_config.flaky.patterns.len() > 0 {
+                await!(self.clone().retry_flaky_jobs(mr.clone(), pipeline.id, traces.clone()))?
+            } else {
+                Vec::new()
+            };
+            // Retried jobs are replaced by new ones, so they are not failures.
+            let traces = traces
+                .into_iter()
+                .filter(|&(ref job, _)| {
+                    let marker = flaky::retry_marker(pipeline.id, job.id);
+                    !retries
+                        .iter()
+                        .any(|a| a.marker.as_ref() == Some(&marker))
+                })
+                .collect::<Vec<_>>();
+            retried_jobs = retries
+                .into_iter()
+                .filter_map(|a| a.details)
+                .collect();
+
+            let restarted = pipeline.status == "failed" && traces.len() == 0
+                && retried_jobs.len() > 0;
+
+            if pipeline.status == "failed" && !restarted {
+                // The excerpts share the size limit of the comment.
+                let logs = traces.iter().map(|t| t.1.clone()).collect::<Vec<_>>();
+                let excerpts = trace::excerpts(&logs, &mr.repo_config.trace);
+                for ((job, job_log), excerpt) in traces.into_iter().zip(excerpts) {
+                    let hint = failure_hints::classify(&mr.repo_config.failure_hints, &job_log)
+                        .cloned();
+                    failed_job_contexts.push(templates::JobContext {
+                        id: job.id,
+                        url: mr.job_url(job.id),
+                        name: job.name,
+                        category: hint.as_ref().map(|h| h.category.clone()),
+                        hint: hint.and_then(|h| h.hint),
+                        log: Some(excerpt),
+                    });
                 }
             }
-            */
 
-            if pipeline.status == "failed" {
-                // Found a failed pipeline!
+            successful_job_contexts = jobs.iter()
+                .filter(|j| j.status == "success")
+                .map(|job| templates::JobContext {
+                    id: job.id,
+                    name: job.name.clone(),
+                    url: mr.job_url(job.id),
+                    category: None,
+                    hint: None,
+                    log: None,
+                })
+                .collect();
+
+            pipeline_context = Some(templates::PipelineContext {
+                id: pipeline.id,
+                sha: pipeline.sha.clone(),
+                failed: pipeline.status == "failed" && !restarted,
+                passed: pipeline.status == "success",
+                running: pipeline.status == "pending" || pipeline.status == "running"
+                    || restarted,
+                status: pipeline.status,
+            });
+        }
 
-                msg.push_str(&format!("Pipeline failed! :warning:\n\n",));
+        if let Some(min) = mr.repo_config.min_coverage {
+            validation.push(reports::coverage::min_coverage_item(
+                min,
+                rendered_reports.coverage.as_ref(),
+            ));
+        }
+        if let (Some(max_drop), Some(change)) = (
+            mr.repo_config.max_coverage_drop,
+            rendered_reports.coverage.as_ref(),
+        ) {
+            validation.extend(reports::coverage::max_coverage_drop_item(max_drop, change));
+        }
 
-                for job in failed_jobs {
-                    // Retrieve log.
-                    let trace = await!(self.client.clone().job_trace(project_id, job.id))?;
-
-                    let job_url = mr.job_url(job.id);
-                    msg.push_str(&format!(
-                        "#### Job: [{}]({})\n\n\
-                         <details>\
-                         <summary>Show Logs</summary>\
-                         <pre><code>{}</code></pre>\
-                         </details><br>\n\
-                         ",
-                        job.name, job_url, trace,
-                    ));
-                }
-            } else if pipeline.status == "success" {
-                msg.push_str(&format!(
-                    "Pipeline passed! :rocket:\n\n\
-                     Successful jobs: {}\n",
-                    success_jobs
-                        .into_iter()
-                        .map(|job| format!("[{}]({})", job.name, mr.job_url(job.id)))
-                        .collect::<Vec<_>>()
-                        .join(", "),
-                ));
-            } else if pipeline.status == "pending" {
-                msg.push_str("Pipeline is running... ")
-            }
+        let context = templates::ReportContext {
+            merge_request: mr.request.clone(),
+            project: mr.project.clone(),
+            pipeline: pipeline_context,
+            report_summary: rendered_reports.summary,
+            report_body: rendered_reports.body,
+            retried_jobs,
+            failed_jobs: failed_job_contexts,
+            successful_jobs: successful_job_contexts,
+            validation,
+            config: mr.config_revision.clone(),
+            config_problems: mr.config_problems.clone(),
+        };
+        let msg = self.render_template(&mr, templates::REPORT, &context)?
+            .trim()
+            .to_string();
+        Ok(msg)
+    }
+
+    /// Render the report of a merge request without posting it.
+    ///
+    /// Warnings and flaky job retries happen while rendering, so this is
+    /// meant for the dry-run mode. `project` is a project id or path, eg
+    /// `group/app`.
+    #[async]
+    pub fn explain_merge_request(self, project: String, iid: u64) -> Result<String, Error> {
+        let project_id = match project.parse::<u64>() {
+            Ok(id) => id,
+            Err(_) => await!(self.client.clone().project_by_path(project))?.id,
+        };
+        let user = await!(self.client.clone().user())?;
+        let mr = await!(self.client.clone().merge_request(project_id, iid))?;
+        let mr = await!(self.clone().full_merge_request(mr, user.id, None))?;
+        if mr.repo_config.is_disabled() {
+            bail!("The bot is disabled for project {}", project_id);
+        }
+        await!(self.build_report(mr))
+    }
+
+    /// Process a merge request.
+    ///
+    /// `pipelines` are reused if they were already loaded.
+    #[async]
+    fn process_merge_request(
+        self,
+        bot: types::User,
+        mr: types::MergeRequest,
+        pipelines: Option<Vec<types::Pipeline>>,
+    ) -> Result<(), Error> {
+        trace!(self.log, "process_merge_request_start";
+            "project_id" => mr.project_id,
+            "merge_request_id" => mr.id,
+        );
+        let project_id = mr.project_id;
+        let updated_at = mr.updated_at.clone();
+
+        let mr = await!(self.clone().full_merge_request(mr, bot.id, pipelines))?;
+
+        if mr.repo_config.is_disabled() {
+            return Ok(());
         }
 
-        if validation_msg != "" {
-            msg.push_str(&format!("## Validation\n\n{}\n\n", validation_msg));
+        let reload = await!(self.clone().process_merge_request_commands(bot.clone(), mr.clone()))?;
+        let mr = if reload {
+            await!(self.clone().full_merge_request(mr.request.clone(), bot.id, None))?
+        } else {
+            mr
+        };
+
+        let closed = await!(self.clone().process_merge_request_timers(mr.clone()))?;
+        if closed {
+            return Ok(());
         }
 
+        let msg = await!(self.clone().build_report(mr.clone()))?;
+
         if msg != "" {
             // Msg is non-empty.
 
-            // Append identifier tag.
-            msg.push_str(&format!("\n\n[report]\n"));
+            let mut marker = Marker::new(markers::REPORT).with_hash(&msg);
+            if let Some(pipeline) = mr.pipelines.get(0) {
+                marker = marker.with_pipeline(pipeline.sha.clone(), pipeline.id);
+            }
 
-            let has_changes = mr.bot_comments
+            let report_ids = mr.bot_comments_of_kind(markers::REPORT)
                 .iter()
-                .find(|c| c.body.contains("[report]"))
-                .map(|c| c.body != msg)
+                .map(|c| c.id)
+                .collect::<Vec<_>>();
+
+            let has_changes = report_ids
+                .get(0)
+                .and_then(|id| mr.bot_markers.get(id))
+                .map(|m| m.hash != marker.hash || m.pipeline_id != marker.pipeline_id)
                 .unwrap_or(true);
 
             if !has_changes {
@@ -564,40 +2823,44 @@ impl Bot {
 
             // Check if the very last message was a build report.
             // If so, we can just update it.
-            // NOTE: comments are sorted by date ascendingly, so the newest
+            // NOTE: comments are sorted by date descendingly, so the newest
             // comment is the first entry.
-            let update_id = mr.comments.get(0).and_then(|c| {
-                if c.author.as_ref().map(|a| a.id == bot.id).unwrap_or(false)
-                    && c.body.contains("[report]")
-                {
-                    Some(c.id)
-                } else {
-                    None
-                }
-            });
+            let update_id = mr.comments
+                .get(0)
+                .map(|c| c.id)
+                .and_then(|id| if report_ids.contains(&id) { Some(id) } else { None });
 
             if let Some(id) = update_id {
-                await!(self.client.clone().merge_request_comment_update(
+                if mr.bot_markers.get(&id).map(|m| m.is_legacy()).unwrap_or(false) {
+                    debug!(self.log, "migrating_legacy_report";
+                        "mr_id" => mr.request.id,
+                        "note_id" => id,
+                    );
+                }
+                await!(self.clone().update_comment(
                     project_id,
                     mr.request.iid,
                     id,
+                    marker,
                     msg
                 ))?;
             } else {
-                await!(self.client.clone().merge_request_comment_create(
+                await!(self.clone().create_comment(
                     project_id,
                     mr.request.iid,
+                    marker,
                     msg
                 ))?;
             }
 
             // Delete older build reports.
-            for comment in mr.bot_comments.into_iter().skip(1) {
-                if comment.body.contains("[report]") {
-                    await!(self.client.clone().merge_request_comment_delete(
+            for id in report_ids {
+                if Some(id) != update_id {
+                    await!(self.clone().delete_comment(
                         project_id,
                         mr.request.iid,
-                        comment.id,
+                        id,
+                        markers::REPORT,
                     ))?;
                 }
             }
@@ -606,6 +2869,139 @@ impl Bot {
         Ok(())
     }
 
+    /// Process the merge requests affected by a webhook event right away.
+    #[async]
+    pub fn process_hook_target(self, target: webhook::HookTarget) -> Result<(), Error> {
+        let user = await!(self.client.clone().user())?;
+
+        let mrs = match target {
+            webhook::HookTarget::MergeRequest { project_id, iid } => {
+                vec![await!(self.client.clone().merge_request(project_id, iid))?]
+            }
+            webhook::HookTarget::Branch { project_id, branch } => {
+                await!(self.client.clone().branch_merge_requests(project_id, branch))?
+            }
+        };
+
+        let since = Utc::now() - Duration::days(STALE_REOPEN_WINDOW_DAYS);
+        let stale_closed = self.cache
+            .closed_merge_requests(stale::CLOSED_MARKER, since);
+
+        for mr in mrs {
+            if mr.state == "closed" && stale_closed.contains(&(mr.project_id, mr.iid)) {
+                await!(self.clone().process_stale_reopen(user.id, mr))?;
+                continue;
+            }
+            if mr.state != "opened" {
+                continue;
+            }
+            // The event might not have touched updated_at (eg finished
+            // pipelines), so always reload the full merge request.
+            self.cache.invalidate_merge_request(mr.id);
+            debug!(self.log, "webhook_merge_request_check";
+                "mr_name" => mr.title.clone(),
+                "mr_id" => mr.id,
+            );
+            await!(self.clone().process_merge_request(user.clone(), mr, None))?;
+        }
+
+        Ok(())
+    }
+
+    /// Check if a merge request changed since it was last processed.
+    ///
+    /// If only the pipeline or the comments changed, the cached merge request
+    /// is invalidated so it gets reloaded. The notes are only loaded if the
+    /// pipeline did not change. Changes of the bot's own actions are taken
+    /// over into the cached merge request.
+    #[async]
+    fn merge_request_changed(
+        self,
+        bot_id: u64,
+        mr: types::MergeRequest,
+    ) -> Result<Freshness, Error> {
+        if self.cache.merge_request_changed(&mr) {
+            return Ok(Freshness::Changed(None));
+        }
+        let cached = match self.cache.get_merge_request_state(mr.id, bot_id) {
+            Some(s) => s,
+            None => return Ok(Freshness::Changed(None)),
+        };
+
+        let pipelines = await!(
+            self.client
+                .clone()
+                .merge_request_pipelines(mr.project_id, mr.iid)
+        )?;
+        let notes = if MergeRequestState::pipeline(&pipelines) != cached.pipeline {
+            None
+        } else {
+            Some(await!(
+                self.client
+                    .clone()
+                    .merge_request_recent_notes(mr.project_id, mr.iid, 20)
+            )?)
+        };
+        let current = notes
+            .as_ref()
+            .map(|notes| MergeRequestState::new(&pipelines, notes, bot_id));
+
+        if current.as_ref() == Some(&cached) {
+            let notes = notes.unwrap_or_default();
+            self.cache.refresh_merge_request(&mr, &notes, bot_id);
+            Ok(Freshness::Unchanged)
+        } else {
+            trace!(self.log, "merge_request_state_changed";
+                "mr_id" => mr.id,
+                "old" => format!("{:?}", cached),
+                "new" => format!("{:?}", current),
+            );
+            self.cache.invalidate_merge_request(mr.id);
+            Ok(Freshness::Changed(Some(pipelines)))
+        }
+    }
+
+    /// Run the rules that depend on the time passed, not on changes:
+    /// reminders, stale warnings and closing.
+    ///
+    /// Returns true if the merge request was closed.
+    #[async]
+    fn process_merge_request_timers(self, mr: FullMergeRequest) -> Result<bool, Error> {
+        // If the MR has not been updated for X days, post a reminder comment.
+        let closed = await!(self.clone().process_merge_request_reminder(mr.clone()))?;
+        if closed {
+            return Ok(true);
+        }
+
+        await!(self.process_merge_request_stale(mr))
+    }
+
+    /// Process a merge request if it changed.
+    ///
+    /// Unchanged merge requests only get the time based rules, which target
+    /// exactly the merge requests nobody touches.
+    #[async]
+    fn check_merge_request(self, bot: types::User, mr: types::MergeRequest) -> Result<(), Error> {
+        let freshness = await!(self.clone().merge_request_changed(bot.id, mr.clone()))?;
+        if let Freshness::Changed(pipelines) = freshness {
+            return await!(self.process_merge_request(bot, mr, pipelines));
+        }
+        let cached = match self.cache.get_merge_request(mr.clone()) {
+            Some(cached) => cached,
+            None => return await!(self.process_merge_request(bot, mr, None)),
+        };
+
+        trace!(self.log, "unchanged_merge_request_timers";
+            "project_id" => mr.project_id,
+            "merge_request_title" => &mr.title,
+        );
+        if cached.repo_config.is_disabled() {
+            return Ok(());
+        }
+        await!(self.process_merge_request_timers(cached))?;
+        Ok(())
+    }
+
     #[async]
     fn process(self) -> Result<(), Error> {
         let log = self.log.clone();
@@ -618,20 +3014,7 @@ impl Bot {
 
         // Load merge requests.
         let mrs = await!(self.client.clone().merge_requests())?;
-
-        // Filter out unchanged MRs.
-        let mrs = mrs.into_iter()
-            .filter(|mr| {
-                let changed = self.cache.merge_request_changed(mr);
-                if !changed {
-                    trace!(log, "skipping_unchanged_merge_request";
-                        "project_id" => mr.project_id,
-                        "merge_request_title" => &mr.title,
-                    );
-                }
-                changed
-            })
-            .collect::<Vec<_>>();
+        let open_ids = mrs.iter().map(|mr| mr.id).collect::<Vec<_>>();
 
         let bot = self.clone();
         let f = stream::iter_ok(mrs)
@@ -641,7 +3024,7 @@ impl Bot {
                     "mr_id" => mr.id,
                 );
                 let log = bot.log.clone();
-                let res = bot.clone().process_merge_request(user.clone(), mr.clone());
+                let res = bot.clone().check_merge_request(user.clone(), mr.clone());
                 res.then(move |res| {
                     match res {
                         Ok(_) => {
@@ -661,10 +3044,15 @@ impl Bot {
                     future::ok::<_, Error>(())
                 })
             })
-            .buffered(5)
+            .buffered(self.config.concurrency)
             .collect();
         await!(f)?;
 
+        // Closed merge requests are not listed above.
+        await!(self.clone().process_stale_reopens(user.id))?;
+
+        self.cache.compact(&open_ids);
+
         info!(self.log, "process_complete");
 
         Ok(())
@@ -688,6 +3076,33 @@ impl Bot {
     }
 
     pub fn run(&self) -> Box<Future<Item = (), Error = Error>> {
-        Box::new(self.clone().do_loop())
+        let addr = match self.config.webhook_addr {
+            Some(addr) => addr,
+            None => return Box::new(self.clone().do_loop()),
+        };
+        // The listener ignores notes of the bot user.
+        let bot = self.clone();
+        let f = self.client
+            .clone()
+            .user()
+            .map_err(Error::from)
+            .and_then(move |user| {
+                webhook::serve(
+                    bot.clone(),
+                    user.username,
+                    addr,
+                    bot.config.webhook_secret.clone().unwrap_or_default(),
+                    &bot.handle,
+                    bot.log.clone(),
+                )?;
+                Ok(bot)
+            })
+            .and_then(|bot| bot.do_loop());
+        Box::new(f)
+    }
+
+    /// Check all merge requests once, without the webhook listener.
+    pub fn run_once(&self) -> Box<Future<Item = (), Error = Error>> {
+        Box::new(self.clone().process())
     }
 }
@@ -0,0 +1,248 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc, Weekday};
+use chrono_tz::Tz;
+use failure::Error;
+
+/// Calendar settings, used by the bot config and the `[calendar]` section of
+/// the repo config.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct CalendarConfig {
+    /// IANA timezone name, eg `Europe/Vienna`. Defaults to UTC.
+    pub timezone: Option<String>,
+    /// Working weekdays, eg `["mon", "tue", "wed", "thu", "fri"]`.
+    /// Defaults to monday to friday.
+    pub working_days: Option<Vec<String>>,
+    /// Holidays in the format `YYYY-MM-DD`.
+    #[serde(default)]
+    pub holidays: Vec<NaiveDate>,
+}
+
+impl CalendarConfig {
+    /// Combine with more specific settings.
+    ///
+    /// Timezone and working days of `other` win, holidays of both are used.
+    pub fn merge(&self, other: &CalendarConfig) -> CalendarConfig {
+        let mut holidays = self.holidays.clone();
+        holidays.extend(other.holidays.iter().cloned());
+        CalendarConfig {
+            timezone: other.timezone.clone().or(self.timezone.clone()),
+            working_days: other.working_days.clone().or(self.working_days.clone()),
+            holidays,
+        }
+    }
+
+    pub fn calendar(&self) -> Result<BusinessCalendar, Error> {
+        let timezone = match self.timezone.as_ref() {
+            Some(name) => name.parse::<Tz>()
+                .map_err(|e| format_err!("Invalid timezone `{}`: {}", name, e))?,
+            None => Tz::UTC,
+        };
+        let working_days = match self.working_days.as_ref() {
+            Some(days) => days.iter()
+                .map(|d| parse_weekday(d))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
+        };
+        Ok(BusinessCalendar {
+            timezone,
+            working_days,
+            holidays: self.holidays.iter().cloned().collect(),
+        })
+    }
+}
+
+fn parse_weekday(name: &str) -> Result<Weekday, Error> {
+    let day = match name.to_lowercase().as_str() {
+        "mon" | "monday" => Weekday::Mon,
+        "tue" | "tuesday" => Weekday::Tue,
+        "wed" | "wednesday" => Weekday::Wed,
+        "thu" | "thursday" => Weekday::Thu,
+        "fri" | "friday" => Weekday::Fri,
+        "sat" | "saturday" => Weekday::Sat,
+        "sun" | "sunday" => Weekday::Sun,
+        _ => bail!("Invalid weekday `{}`", name),
+    };
+    Ok(day)
+}
+
+/// Calendar of working days, used for all age based rules.
+#[derive(Clone, Debug)]
+pub struct BusinessCalendar {
+    timezone: Tz,
+    working_days: Vec<Weekday>,
+    holidays: HashSet<NaiveDate>,
+}
+
+impl Default for BusinessCalendar {
+    fn default() -> Self {
+        CalendarConfig::default().calendar().unwrap()
+    }
+}
+
+impl BusinessCalendar {
+    fn local_date(&self, time: DateTime<Utc>) -> NaiveDate {
+        time.with_timezone(&self.timezone).date().naive_local()
+    }
+
+    pub fn is_working_day(&self, date: NaiveDate) -> bool {
+        self.working_days.contains(&date.weekday()) && !self.holidays.contains(&date)
+    }
+
+    /// Number of working days that started after `from`, up to and
+    /// including the day of `to`, in the calendar timezone.
+    pub fn working_days_between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> i64 {
+        let end = self.local_date(to);
+        let mut day = self.local_date(from) + Duration::days(1);
+        let mut count = 0;
+        while day <= end {
+            if self.is_working_day(day) {
+                count += 1;
+            }
+            day = day + Duration::days(1);
+        }
+        count
+    }
+
+    /// Working days passed since `time`.
+    pub fn working_days_since(&self, time: DateTime<Utc>) -> i64 {
+        self.working_days_between(time, Utc::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.ymd(y, m, d).and_hms(h, min, 0)
+    }
+
+    fn calendar(timezone: Option<&str>, holidays: &[(i32, u32, u32)]) -> BusinessCalendar {
+        CalendarConfig {
+            timezone: timezone.map(|t| t.to_string()),
+            working_days: None,
+            holidays: holidays
+                .iter()
+                .map(|&(y, m, d)| NaiveDate::from_ymd(y, m, d))
+                .collect(),
+        }.calendar()
+            .unwrap()
+    }
+
+    #[test]
+    fn weekends_are_skipped() {
+        let cal = BusinessCalendar::default();
+        // Friday 2018-03-09.
+        let friday = at(2018, 3, 9, 12, 0);
+        assert_eq!(cal.working_days_between(friday, friday), 0);
+        assert_eq!(cal.working_days_between(friday, at(2018, 3, 10, 12, 0)), 0);
+        assert_eq!(cal.working_days_between(friday, at(2018, 3, 11, 23, 59)), 0);
+        assert_eq!(cal.working_days_between(friday, at(2018, 3, 12, 0, 0)), 1);
+        assert_eq!(cal.working_days_between(friday, at(2018, 3, 16, 8, 0)), 5);
+        // Starting on a weekend.
+        let saturday = at(2018, 3, 10, 12, 0);
+        assert_eq!(cal.working_days_between(saturday, at(2018, 3, 12, 8, 0)), 1);
+        // An end before the start.
+        assert_eq!(cal.working_days_between(friday, at(2018, 3, 5, 8, 0)), 0);
+    }
+
+    #[test]
+    fn custom_working_days() {
+        let cal = CalendarConfig {
+            working_days: Some(
+                vec!["sun", "Monday", "tue", "wed", "thu"]
+                    .into_iter()
+                    .map(|d| d.to_string())
+                    .collect(),
+            ),
+            ..CalendarConfig::default()
+        }.calendar()
+            .unwrap();
+        // Thursday to saturday, then sunday.
+        let thursday = at(2018, 3, 8, 12, 0);
+        assert_eq!(cal.working_days_between(thursday, at(2018, 3, 10, 12, 0)), 0);
+        assert_eq!(cal.working_days_between(thursday, at(2018, 3, 11, 12, 0)), 1);
+    }
+
+    #[test]
+    fn holidays_are_skipped() {
+        // Easter monday.
+        let cal = calendar(None, &[(2018, 4, 2)]);
+        let friday = at(2018, 3, 30, 12, 0);
+        assert_eq!(cal.working_days_between(friday, at(2018, 4, 2, 12, 0)), 0);
+        assert_eq!(cal.working_days_between(friday, at(2018, 4, 3, 0, 0)), 1);
+        // Starting on the holiday counts from the next day.
+        let holiday = at(2018, 4, 2, 12, 0);
+        assert_eq!(cal.working_days_between(holiday, at(2018, 4, 3, 12, 0)), 1);
+        assert!(!cal.is_working_day(NaiveDate::from_ymd(2018, 4, 2)));
+        assert!(cal.is_working_day(NaiveDate::from_ymd(2018, 4, 3)));
+    }
+
+    #[test]
+    fn merged_holidays_are_combined() {
+        let base = CalendarConfig {
+            holidays: vec![NaiveDate::from_ymd(2018, 12, 25)],
+            ..CalendarConfig::default()
+        };
+        let repo = CalendarConfig {
+            timezone: Some("Europe/Vienna".to_string()),
+            holidays: vec![NaiveDate::from_ymd(2018, 12, 26)],
+            ..CalendarConfig::default()
+        };
+        let cal = base.merge(&repo).calendar().unwrap();
+        assert_eq!(cal.timezone, Tz::Europe__Vienna);
+        assert!(!cal.is_working_day(NaiveDate::from_ymd(2018, 12, 25)));
+        assert!(!cal.is_working_day(NaiveDate::from_ymd(2018, 12, 26)));
+        assert!(cal.is_working_day(NaiveDate::from_ymd(2018, 12, 27)));
+    }
+
+    #[test]
+    fn dates_roll_over_in_the_calendar_timezone() {
+        let utc = BusinessCalendar::default();
+
+        // Sunday 23:30 UTC is already monday in Vienna (UTC+1).
+        let vienna = calendar(Some("Europe/Vienna"), &[]);
+        let friday = at(2018, 3, 9, 12, 0);
+        let sunday_night = at(2018, 3, 11, 23, 30);
+        assert_eq!(utc.working_days_between(friday, sunday_night), 0);
+        assert_eq!(vienna.working_days_between(friday, sunday_night), 1);
+
+        // Thursday 22:00 to friday 00:30 in Auckland (UTC+13).
+        let auckland = calendar(Some("Pacific/Auckland"), &[]);
+        let from = at(2018, 3, 8, 9, 0);
+        let to = at(2018, 3, 8, 11, 30);
+        assert_eq!(utc.working_days_between(from, to), 0);
+        assert_eq!(auckland.working_days_between(from, to), 1);
+
+        // Monday 02:00 UTC is still sunday in New York (UTC-4).
+        let new_york = calendar(Some("America/New_York"), &[]);
+        let from = at(2018, 3, 12, 2, 0);
+        let to = at(2018, 3, 12, 14, 0);
+        assert_eq!(utc.working_days_between(from, to), 0);
+        assert_eq!(new_york.working_days_between(from, to), 1);
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let config = CalendarConfig {
+            timezone: Some("Mars/Olympus".to_string()),
+            ..CalendarConfig::default()
+        };
+        assert!(config.calendar().is_err());
+
+        let config = CalendarConfig {
+            working_days: Some(vec!["someday".to_string()]),
+            ..CalendarConfig::default()
+        };
+        assert!(config.calendar().is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use failure::Error;
+use tokio_core::reactor::Core;
+
+use bot::Bot;
+use config::{self, Config, ConfigProblem, Settings};
+use templates;
+
+pub fn app() -> App<'static, 'static> {
+    App::new("gitlab-bot")
+        .version(crate_version!())
+        .about("A Gitlab bot that helps with merge requests.")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .global(true)
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Bot config file, overrides GITLAB_BOT_CONFIG"),
+        )
+        .arg(
+            Arg::with_name("url")
+                .long("url")
+                .global(true)
+                .takes_value(true)
+                .help("Base url of the Gitlab instance"),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .global(true)
+                .takes_value(true)
+                .value_name("SECS")
+                .help("Seconds between two checks of all merge requests"),
+        )
+        .arg(
+            Arg::with_name("concurrency")
+                .long("concurrency")
+                .global(true)
+                .takes_value(true)
+                .value_name("N")
+                .help("Number of merge requests checked at the same time"),
+        )
+        .arg(
+            Arg::with_name("config-cache-ttl")
+                .long("config-cache-ttl")
+                .global(true)
+                .takes_value(true)
+                .value_name("SECS")
+                .help("Seconds a repo config is cached"),
+        )
+        .arg(
+            Arg::with_name("commit-depth")
+                .long("commit-depth")
+                .global(true)
+                .takes_value(true)
+                .value_name("N")
+                .help("Number of commits loaded per branch"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .global(true)
+                .takes_value(true)
+                .possible_values(&["trace", "debug", "info", "warning", "error", "critical"]),
+        )
+        .arg(
+            Arg::with_name("webhook-addr")
+                .long("webhook-addr")
+                .global(true)
+                .takes_value(true)
+                .value_name("ADDR")
+                .help("Address for the webhook listener, eg 0.0.0.0:8080"),
+        )
+        .arg(
+            Arg::with_name("state-dir")
+                .long("state-dir")
+                .global(true)
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Directory where state is persisted across restarts"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .global(true)
+                .help("Record changes instead of sending them to Gitlab"),
+        )
+        .arg(
+            Arg::with_name("dry-run-file")
+                .long("dry-run-file")
+                .global(true)
+                .takes_value(true)
+                .value_name("PATH")
+                .help("File where planned changes are appended in dry-run mode"),
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Check merge requests periodically (the default)"),
+        )
+        .subcommand(
+            SubCommand::with_name("run-once").about("Check all merge requests once, then exit"),
+        )
+        .subcommand(
+            SubCommand::with_name("check-config")
+                .about("Validate a .gitlab-bot.toml file")
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .help("Path of the config file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("explain-mr")
+                .about("Print the report of a merge request without posting it")
+                .arg(
+                    Arg::with_name("project")
+                        .required(true)
+                        .help("Project id or path, eg group/app"),
+                )
+                .arg(
+                    Arg::with_name("iid")
+                        .required(true)
+                        .help("Merge request iid, as shown in the Gitlab UI"),
+                ),
+        )
+}
+
+fn number<T>(matches: &ArgMatches, name: &str) -> Result<Option<T>, Error>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match matches.value_of(name) {
+        Some(v) => v.parse::<T>()
+            .map(Some)
+            .map_err(|e| format_err!("Invalid --{}: {}", name, e)),
+        None => Ok(None),
+    }
+}
+
+/// The settings given as flags, the top layer of the bot config.
+fn flags(matches: &ArgMatches) -> Result<Settings, Error> {
+    let string = |name: &str| matches.value_of(name).map(|v| v.to_string());
+    let path = |name: &str| matches.value_of(name).map(PathBuf::from);
+    Ok(Settings {
+        url: string("url"),
+        interval: number(matches, "interval")?,
+        concurrency: number(matches, "concurrency")?,
+        config_cache_ttl: number(matches, "config-cache-ttl")?,
+        commit_depth: number(matches, "commit-depth")?,
+        log_level: string("log-level"),
+        webhook_addr: string("webhook-addr"),
+        state_dir: path("state-dir"),
+        dry_run: if matches.is_present("dry-run") {
+            Some(true)
+        } else {
+            None
+        },
+        dry_run_file: path("dry-run-file"),
+        ..Settings::default()
+    })
+}
+
+fn config(matches: &ArgMatches) -> Result<Config, Error> {
+    Config::load(matches.value_of("config").map(Path::new), flags(matches)?)
+}
+
+pub fn execute(matches: &ArgMatches) -> Result<(), Error> {
+    match matches.subcommand() {
+        ("check-config", Some(m)) => check_config(Path::new(m.value_of("path").unwrap())),
+        ("explain-mr", Some(m)) => {
+            let project = m.value_of("project").unwrap().to_string();
+            let iid = value_t!(m, "iid", u64)?;
+            explain_merge_request(config(m)?, project, iid)
+        }
+        ("run-once", Some(m)) => {
+            let mut core = Core::new()?;
+            let bot = Bot::new(config(m)?, core.handle())?;
+            core.run(bot.run_once())
+        }
+        (_, m) => {
+            let mut core = Core::new()?;
+            let bot = Bot::new(config(m.unwrap_or(matches))?, core.handle())?;
+            core.run(bot.run())
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, Error> {
+    let mut data = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut data))
+        .map_err(|e| format_err!("Could not read {}: {}", path.display(), e))?;
+    Ok(data)
+}
+
+/// Validate a repo config and the template files it refers to.
+fn check_config(path: &Path) -> Result<(), Error> {
+    let (repo_config, mut problems) = match config::parse_toml(read_file(path)?.as_bytes()) {
+        Ok(value) => config::deserialize_repo_config(value),
+        Err(problem) => (None, vec![problem]),
+    };
+
+    if let Some(repo_config) = repo_config {
+        // Template paths are relative to the repository root.
+        let root = path.parent().unwrap_or(Path::new("."));
+        for (name, file) in repo_config.templates.files.iter() {
+            let res = read_file(&root.join(file)).and_then(|t| templates::check(name, &t));
+            if let Err(e) = res {
+                let key = format!("templates.files.{}", name);
+                problems.push(ConfigProblem::for_key(key, e.to_string()));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        println!("{}: ok", path.display());
+        return Ok(());
+    }
+    for problem in problems.iter() {
+        println!("{}: {}", path.display(), problem);
+    }
+    bail!("Found {} problem(s) in {}", problems.len(), path.display())
+}
+
+fn explain_merge_request(config: Config, project: String, iid: u64) -> Result<(), Error> {
+    let mut core = Core::new()?;
+    // Warnings and retries triggered while rendering must not be sent.
+    let bot = Bot::new(config.with_dry_run(), core.handle())?;
+    let report = core.run(bot.explain_merge_request(project, iid))?;
+    println!("{}", report);
+    Ok(())
+}
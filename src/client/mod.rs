@@ -1,14 +1,34 @@
+pub mod recorder;
 pub mod types;
 
+use std::sync::Arc;
+
+use serde_json::Value;
 use slog::Logger;
 use futures::prelude::*;
 use futures::future;
 use tokio_core::reactor::Handle;
 use reqwest::unstable::async::Client;
-use reqwest::{self, Url};
+use reqwest::{self, Method, Url};
 use reqwest::unstable::async;
 use failure::Error;
 
+/// Percent-encode a value for a query string or a single url segment.
+///
+/// Branch names may contain `/`, `&`, `#` and the like.
+fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
 /// Gitlab client struct.
 #[derive(Clone)]
 pub struct Gitlab {
@@ -16,6 +36,8 @@ pub struct Gitlab {
     token: String,
     log: Logger,
     client: Client,
+    /// Set in dry-run mode, receives all changes instead of Gitlab.
+    recorder: Option<Arc<recorder::Recorder>>,
 }
 
 impl Gitlab {
@@ -28,9 +50,17 @@ impl Gitlab {
             log,
             client: Client::new(handle),
             token,
+            recorder: None,
         })
     }
 
+    /// Enable dry-run mode: changes are passed to the recorder instead of
+    /// being sent to Gitlab.
+    pub fn with_recorder(mut self, recorder: recorder::Recorder) -> Self {
+        self.recorder = Some(Arc::new(recorder));
+        self
+    }
+
     fn auth_headers(&self) -> reqwest::header::Headers {
         let mut h = reqwest::header::Headers::new();
         h.set_raw("Private-Token".to_string(), self.token.clone());
@@ -72,19 +102,25 @@ impl Gitlab {
         self.get_url(url)
     }
 
-    fn post<S: AsRef<str>>(&self, path: S) -> async::RequestBuilder {
-        self.client.post(self.build_url(path))
-    }
-
-    fn put<S: AsRef<str>>(&self, path: S) -> async::RequestBuilder {
-        let mut b = self.client.put(self.build_url(path));
-        b.headers(self.auth_headers());
-        b
-    }
-
+    /// Send a request that changes data on Gitlab.
+    ///
+    /// In dry-run mode the request is only passed to the recorder.
     #[async]
-    fn delete(self, path: String) -> Result<(), reqwest::Error> {
-        let mut req = self.client.delete(self.build_url(path));
+    fn send_change(
+        self,
+        method: Method,
+        path: String,
+        body: Option<Value>,
+    ) -> Result<(), reqwest::Error> {
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.record(&method, &path, body.as_ref());
+            return Ok(());
+        }
+
+        let mut req = self.client.request(method, self.build_url(path));
+        if let Some(body) = body.as_ref() {
+            req.json(body);
+        }
         await!(self.send(req))?;
         Ok(())
     }
@@ -169,10 +205,27 @@ impl Gitlab {
         Ok(u)
     }
 
+    /// Load a project by its path, eg `group/project`.
+    #[async]
+    pub fn project_by_path(self, path: String) -> Result<types::Project, reqwest::Error> {
+        // The path is a single url segment.
+        let u = await!(self.get_json(format!("projects/{}", encode(&path))))?;
+        Ok(u)
+    }
+
+    /// Get the members of a project, including members inherited from
+    /// groups.
+    #[async]
+    pub fn project_members(self, pid: u64) -> Result<Vec<types::Member>, Error> {
+        let path = format!("projects/{}/members/all", pid);
+        let members = await!(self.load_paginated(path, None))?;
+        Ok(members)
+    }
+
     /// Get branch info for a branch.
     #[async]
     pub fn branch(self, pid: u64, branch: String) -> Result<types::Branch, reqwest::Error> {
-        let path = format!("projects/{}/repository/branches/{}", pid, branch);
+        let path = format!("projects/{}/repository/branches/{}", pid, encode(&branch));
         let data = await!(self.get_json(path))?;
         Ok(data)
     }
@@ -181,7 +234,7 @@ impl Gitlab {
     pub fn commits(self, pid: u64, branch: String, max: u64) -> Result<Vec<types::Commit>, Error> {
         let max_pages = (max as f64 / 100.0).ceil() as u64;
 
-        let path = format!("projects/{}/repository/commits?ref={}", pid, branch);
+        let path = format!("projects/{}/repository/commits?ref={}", pid, encode(&branch));
         let data = await!(self.load_paginated(path, Some(max_pages)))?;
         Ok(data)
     }
@@ -194,9 +247,12 @@ impl Gitlab {
         path: String,
         branch: String,
     ) -> Result<Vec<u8>, reqwest::Error> {
+        // The file path is a single url segment.
         let path = format!(
             "projects/{}/repository/files/{}/raw?ref={}",
-            pid, path, branch
+            pid,
+            encode(&path),
+            encode(&branch)
         );
         let req = self.get(path);
         let res = await!(self.send(req))?;
@@ -215,6 +271,78 @@ impl Gitlab {
         Ok(items)
     }
 
+    /// Get a single merge request of a project.
+    #[async]
+    pub fn merge_request(self, pid: u64, mrid: u64) -> Result<types::MergeRequest, reqwest::Error> {
+        let path = format!("projects/{}/merge_requests/{}", pid, mrid);
+        let mr = await!(self.get_json(path))?;
+        Ok(mr)
+    }
+
+    /// Get the open merge requests of a project that use the given source branch.
+    #[async]
+    pub fn branch_merge_requests(
+        self,
+        pid: u64,
+        branch: String,
+    ) -> Result<Vec<types::MergeRequest>, Error> {
+        let path = format!(
+            "projects/{}/merge_requests?state=opened&source_branch={}",
+            pid,
+            encode(&branch)
+        );
+        let items = await!(self.load_paginated(path, None))?;
+        Ok(items)
+    }
+
+    /// Update attributes of a merge request.
+    ///
+    /// `changes` holds the attributes to change, eg `{"labels": "a,b"}` or
+    /// `{"state_event": "close"}`.
+    #[async]
+    pub fn merge_request_update(
+        self,
+        pid: u64,
+        mrid: u64,
+        changes: Value,
+    ) -> Result<(), reqwest::Error> {
+        let path = format!("projects/{}/merge_requests/{}", pid, mrid);
+        await!(self.send_change(Method::Put, path, Some(changes)))?;
+        Ok(())
+    }
+
+    /// Change the state of a merge request.
+    ///
+    /// `state_event` is either `close` or `reopen`.
+    #[async]
+    pub fn merge_request_set_state(
+        self,
+        pid: u64,
+        mrid: u64,
+        state_event: String,
+    ) -> Result<(), reqwest::Error> {
+        await!(self.merge_request_update(pid, mrid, json!({ "state_event": state_event })))?;
+        Ok(())
+    }
+
+    /// Get the newest pipelines of a branch with the given status.
+    #[async]
+    pub fn branch_pipelines(
+        self,
+        pid: u64,
+        branch: String,
+        status: String,
+    ) -> Result<Vec<types::Pipeline>, reqwest::Error> {
+        let path = format!(
+            "projects/{}/pipelines?ref={}&status={}&order_by=id&sort=desc",
+            pid,
+            encode(&branch),
+            status
+        );
+        let pipelines = await!(self.get_json(path))?;
+        Ok(pipelines)
+    }
+
     /// Get all jobs of a pipeline.
     #[async]
     pub fn pipeline_jobs(
@@ -227,26 +355,77 @@ impl Gitlab {
         Ok(jobs)
     }
 
+    /// Retry the failed jobs of a pipeline.
+    #[async]
+    pub fn pipeline_retry(self, pid: u64, pipeline_id: u64) -> Result<(), reqwest::Error> {
+        let path = format!("projects/{}/pipelines/{}/retry", pid, pipeline_id);
+        await!(self.send_change(Method::Post, path, None))?;
+        Ok(())
+    }
+
+    /// Retry a single job.
+    #[async]
+    pub fn job_retry(self, pid: u64, job_id: u64) -> Result<(), reqwest::Error> {
+        let path = format!("projects/{}/jobs/{}/retry", pid, job_id);
+        await!(self.send_change(Method::Post, path, None))?;
+        Ok(())
+    }
+
     /// Get a single file from an artifact.
+    ///
+    /// Files larger than `max_size` bytes are refused, before downloading
+    /// them if Gitlab sends their size.
     #[async]
     pub fn job_artifact_file(
         self,
         pid: u64,
         job_id: u64,
         path: String,
-    ) -> Result<Vec<u8>, reqwest::Error> {
+        max_size: usize,
+    ) -> Result<Vec<u8>, Error> {
         let path = format!("projects/{}/jobs/{}/artifacts/{}", pid, job_id, path);
         let req = self.get(path);
-        let data = await!(self.send(req).and_then(|res| res.into_body().fold(
+        let res = await!(self.send(req))?;
+
+        let size = res.headers()
+            .get::<reqwest::header::ContentLength>()
+            .map(|l| l.0);
+        if let Some(size) = size {
+            if size > max_size as u64 {
+                bail!(
+                    "Artifact is too large: {} bytes (limit: {} bytes)",
+                    size,
+                    max_size
+                );
+            }
+        }
+
+        let data = await!(res.into_body().map_err(Error::from).fold(
             Vec::<u8>::new(),
-            |mut a, b| {
+            move |mut a, b| {
+                if a.len() + b.len() > max_size {
+                    return Err(format_err!(
+                        "Artifact is too large (limit: {} bytes)",
+                        max_size
+                    ));
+                }
                 a.extend_from_slice(&b[..]);
-                future::ok::<_, reqwest::Error>(a)
+                Ok(a)
             }
-        )))?;
+        ))?;
         Ok(data)
     }
 
+    /// Rebase the source branch of a merge request onto the target branch.
+    ///
+    /// The rebase runs in the background on the Gitlab side.
+    #[async]
+    pub fn merge_request_rebase(self, pid: u64, mrid: u64) -> Result<(), reqwest::Error> {
+        let path = format!("projects/{}/merge_requests/{}/rebase", pid, mrid);
+        await!(self.send_change(Method::Put, path, None))?;
+        Ok(())
+    }
+
     /// Get the trace log for a CI job.
     #[async]
     pub fn job_trace(self, pid: u64, job_id: u64) -> Result<String, Error> {
@@ -266,7 +445,7 @@ impl Gitlab {
         Ok(log)
     }
 
-    /// Get the comments of a merge request.
+    /// Get the commits of a merge request.
     #[async]
     pub fn merge_request_commits(self, pid: u64, mrid: u64) -> Result<Vec<types::Commit>, Error> {
         let path = format!("projects/{}/merge_requests/{}/commits", pid, mrid);
@@ -282,6 +461,22 @@ impl Gitlab {
         Ok(comments)
     }
 
+    /// Get the newest comments of a merge request, newest first.
+    #[async]
+    pub fn merge_request_recent_notes(
+        self,
+        pid: u64,
+        mrid: u64,
+        count: u64,
+    ) -> Result<Vec<types::Note>, reqwest::Error> {
+        let path = format!(
+            "projects/{}/merge_requests/{}/notes?sort=desc&order_by=created_at&per_page={}",
+            pid, mrid, count
+        );
+        let notes = await!(self.get_json(path))?;
+        Ok(notes)
+    }
+
     /// Get list of pipelines for a merge request.
     #[async]
     pub fn merge_request_pipelines(
@@ -294,6 +489,49 @@ impl Gitlab {
         Ok(pipelines)
     }
 
+    /// Get the diff versions of a merge request, newest first.
+    #[async]
+    pub fn merge_request_versions(
+        self,
+        pid: u64,
+        mrid: u64,
+    ) -> Result<Vec<types::MergeRequestVersion>, reqwest::Error> {
+        let path = format!("projects/{}/merge_requests/{}/versions", pid, mrid);
+        let versions = await!(self.get_json(path))?;
+        Ok(versions)
+    }
+
+    /// Get the changed files of a merge request, including their diffs.
+    #[async]
+    pub fn merge_request_changes(
+        self,
+        pid: u64,
+        mrid: u64,
+    ) -> Result<types::MergeRequestChanges, reqwest::Error> {
+        let path = format!("projects/{}/merge_requests/{}/changes", pid, mrid);
+        let changes = await!(self.get_json(path))?;
+        Ok(changes)
+    }
+
+    /// Create a new discussion on a line of the merge request diff.
+    #[async]
+    pub fn merge_request_discussion_create(
+        self,
+        pid: u64,
+        mrid: u64,
+        body: String,
+        position: types::DiffPosition,
+    ) -> Result<(), reqwest::Error> {
+        let path = format!("projects/{}/merge_requests/{}/discussions", pid, mrid);
+        let data = json!({
+            "body": body,
+            "position": position,
+        });
+
+        await!(self.send_change(Method::Post, path, Some(data)))?;
+        Ok(())
+    }
+
     /// Create a new merge request comment.
     #[async]
     pub fn merge_request_comment_create(
@@ -303,12 +541,48 @@ impl Gitlab {
         body: String,
     ) -> Result<(), reqwest::Error> {
         let path = format!("projects/{}/merge_requests/{}/notes", pid, mrid);
-        let mut b = self.post(path);
-        b.json(&json!({
+        let data = json!({
             "body": body,
-        }));
+        });
+
+        await!(self.send_change(Method::Post, path, Some(data)))?;
+        Ok(())
+    }
+
+    /// Get the award emojis of a merge request comment.
+    #[async]
+    pub fn merge_request_comment_awards(
+        self,
+        pid: u64,
+        mrid: u64,
+        note_id: u64,
+    ) -> Result<Vec<types::AwardEmoji>, reqwest::Error> {
+        let path = format!(
+            "projects/{}/merge_requests/{}/notes/{}/award_emoji",
+            pid, mrid, note_id
+        );
+        let awards = await!(self.get_json(path))?;
+        Ok(awards)
+    }
+
+    /// Add an award emoji, eg `thumbsup`, to a merge request comment.
+    #[async]
+    pub fn merge_request_comment_award(
+        self,
+        pid: u64,
+        mrid: u64,
+        note_id: u64,
+        name: String,
+    ) -> Result<(), reqwest::Error> {
+        let path = format!(
+            "projects/{}/merge_requests/{}/notes/{}/award_emoji",
+            pid, mrid, note_id
+        );
+        let data = json!({
+            "name": name,
+        });
 
-        await!(self.send(b))?;
+        await!(self.send_change(Method::Post, path, Some(data)))?;
         Ok(())
     }
 
@@ -322,11 +596,10 @@ impl Gitlab {
     ) -> Result<(), reqwest::Error> {
         let path = format!("projects/{}/merge_requests/{}/notes/{}", pid, mrid, note_id);
 
-        let mut b = self.put(path);
-        b.json(&json!({
+        let data = json!({
             "body": body,
-        }));
-        await!(self.clone().send(b))?;
+        });
+        await!(self.send_change(Method::Put, path, Some(data)))?;
         Ok(())
     }
 
@@ -339,7 +612,7 @@ impl Gitlab {
     ) -> Result<(), reqwest::Error> {
         let path = format!("projects/{}/merge_requests/{}/notes/{}", pid, mrid, note_id);
 
-        await!(self.delete(path))?;
+        await!(self.send_change(Method::Delete, path, None))?;
 
         Ok(())
     }
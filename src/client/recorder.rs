@@ -0,0 +1,77 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use failure::Error;
+use reqwest::Method;
+use serde_json::{self, Value};
+use slog::Logger;
+
+/// A change that would have been sent to Gitlab in dry-run mode.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlannedAction {
+    pub method: String,
+    /// API path, eg `projects/1/merge_requests/2/notes`.
+    pub path: String,
+    pub body: Option<Value>,
+    pub planned_at: DateTime<Utc>,
+}
+
+/// Records the changes of the dry-run mode instead of sending them.
+///
+/// Every change is logged and optionally appended to a JSONL file for review.
+pub struct Recorder {
+    log: Logger,
+    file: Option<Mutex<File>>,
+}
+
+impl Recorder {
+    pub fn new(log: Logger, path: Option<PathBuf>) -> Result<Self, Error> {
+        let file = match path {
+            Some(path) => {
+                let f = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .map_err(|e| {
+                        format_err!("Could not open dry-run file {}: {}", path.display(), e)
+                    })?;
+                Some(Mutex::new(f))
+            }
+            None => None,
+        };
+        Ok(Recorder { log, file })
+    }
+
+    pub fn record(&self, method: &Method, path: &str, body: Option<&Value>) {
+        let action = PlannedAction {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: body.cloned(),
+            planned_at: Utc::now(),
+        };
+
+        info!(self.log, "dry_run_action";
+            "method" => &action.method,
+            "path" => &action.path,
+            "body" => action.body.as_ref().map(|b| b.to_string()).unwrap_or_default(),
+        );
+
+        if let Some(ref file) = self.file {
+            let res = Self::append(file, &action);
+            if let Err(e) = res {
+                warn!(self.log, "dry_run_write_failed"; "error" => e.to_string());
+            }
+        }
+    }
+
+    fn append(file: &Mutex<File>, action: &PlannedAction) -> Result<(), Error> {
+        let mut line = serde_json::to_string(action)?;
+        line.push('\n');
+        let mut f = file.lock().map_err(|_| format_err!("Dry-run file lock poisoned"))?;
+        f.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
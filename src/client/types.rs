@@ -58,6 +58,22 @@ pub struct Assignee {
     pub created_at: Option<DateTime<Utc>>,
 }
 
+/// Access level of developers, who can push to unprotected branches.
+pub const DEVELOPER_ACCESS: u64 = 30;
+/// Access level of maintainers.
+pub const MAINTAINER_ACCESS: u64 = 40;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Member {
+    pub id: u64,
+    pub username: String,
+    pub name: String,
+    pub state: String,
+    /// 10 guest, 20 reporter, 30 developer, 40 maintainer, 50 owner.
+    pub access_level: u64,
+    pub expires_at: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Project {
     pub id: u64,
@@ -255,6 +271,14 @@ pub struct Note {
     pub noteable_iid: u64,
 }
 
+/// An award emoji on a comment, eg `thumbsup`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AwardEmoji {
+    pub id: u64,
+    pub name: String,
+    pub user: Author,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Pipeline {
     pub id: u64,
@@ -310,3 +334,47 @@ pub struct Job {
   },
   */
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MergeRequestVersion {
+    pub id: u64,
+    pub head_commit_sha: String,
+    pub base_commit_sha: String,
+    pub start_commit_sha: String,
+    pub created_at: DateTime<Utc>,
+    pub merge_request_id: u64,
+    pub state: String,
+    pub real_size: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MergeRequestChange {
+    pub old_path: String,
+    pub new_path: String,
+    pub a_mode: Option<String>,
+    pub b_mode: Option<String>,
+    pub diff: String,
+    pub new_file: bool,
+    pub renamed_file: bool,
+    pub deleted_file: bool,
+}
+
+/// A merge request together with its diffs.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MergeRequestChanges {
+    pub id: u64,
+    pub iid: u64,
+    pub changes: Vec<MergeRequestChange>,
+}
+
+/// Position of a diff discussion.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiffPosition {
+    pub base_sha: String,
+    pub start_sha: String,
+    pub head_sha: String,
+    pub position_type: String,
+    pub old_path: String,
+    pub new_path: String,
+    pub new_line: u64,
+}
@@ -0,0 +1,206 @@
+/// Commands that can be given to the bot in merge request comments, eg
+/// `@gitlab-bot retry`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Retry the failed jobs of the latest pipeline.
+    Retry,
+    /// Rebase the source branch onto the target branch.
+    Rebase,
+    /// Check the merge request again, ignoring cached data.
+    Recheck,
+    /// Pause reminders and stale warnings for the given number of days.
+    Snooze(i64),
+    Help,
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Command::Retry => "retry",
+            Command::Rebase => "rebase",
+            Command::Recheck => "recheck",
+            Command::Snooze(_) => "snooze",
+            Command::Help => "help",
+        }
+    }
+}
+
+/// Help text, `{bot}` is replaced with the bot username.
+const HELP: &str = "Available commands:\n\n\
+                    * `@{bot} retry`: retry the failed jobs of the latest pipeline\n\
+                    * `@{bot} rebase`: rebase the source branch onto the target branch\n\
+                    * `@{bot} recheck`: check this merge request again\n\
+                    * `@{bot} snooze 3d`: pause reminders and stale warnings, \
+                    eg for 3 days or `2w` for two weeks, up to a year\n\
+                    * `@{bot} help`: show this message";
+
+pub fn help(bot_username: &str) -> String {
+    HELP.replace("{bot}", bot_username)
+}
+
+/// Longest snooze, so the end of a snooze is always a valid time.
+const MAX_SNOOZE_DAYS: i64 = 365;
+
+/// Error message for a snooze duration that can not be used.
+pub fn invalid_duration(arg: &str) -> String {
+    format!("Invalid duration `{}`, eg `snooze 3d`.", arg)
+}
+
+/// Parse a snooze duration like `3d`, `2w` or `3`, in days, up to a year.
+fn parse_days(arg: Option<&str>) -> Result<i64, String> {
+    let arg = match arg {
+        Some(a) => a.to_lowercase(),
+        None => return Err("Missing duration, eg `snooze 3d`.".to_string()),
+    };
+    let (num, factor) = if arg.ends_with('w') {
+        (&arg[..arg.len() - 1], 7)
+    } else if arg.ends_with('d') {
+        (&arg[..arg.len() - 1], 1)
+    } else {
+        (arg.as_str(), 1)
+    };
+    match num.parse::<i64>().ok().and_then(|n| n.checked_mul(factor)) {
+        Some(days) if days > 0 && days <= MAX_SNOOZE_DAYS => Ok(days),
+        _ => Err(invalid_duration(&arg)),
+    }
+}
+
+/// Parse the commands addressed to the bot in a comment.
+///
+/// Each line starting with `@<bot_username>` holds one command. Invalid
+/// commands are returned as an error message for the author.
+pub fn parse(body: &str, bot_username: &str) -> Vec<Result<Command, String>> {
+    let mention = format!("@{}", bot_username);
+    body.lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            if !line.starts_with(&mention) {
+                return None;
+            }
+            let rest = &line[mention.len()..];
+            // Another user whose name starts with the bot name.
+            if rest != "" && !rest.starts_with(char::is_whitespace) {
+                return None;
+            }
+
+            let mut words = rest.split_whitespace();
+            let name = match words.next() {
+                Some(name) => name.to_lowercase(),
+                None => return None,
+            };
+            let cmd = match name.as_str() {
+                "retry" => Ok(Command::Retry),
+                "rebase" => Ok(Command::Rebase),
+                "recheck" => Ok(Command::Recheck),
+                "snooze" => parse_days(words.next()).map(Command::Snooze),
+                "help" => Ok(Command::Help),
+                _ => Err(format!("Unknown command `{}`.", name)),
+            };
+            Some(cmd)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        let cases = vec![
+            ("3d", Ok(3)),
+            ("3D", Ok(3)),
+            ("2w", Ok(14)),
+            ("5", Ok(5)),
+            ("365d", Ok(365)),
+            ("52w", Ok(364)),
+            ("366", Err("Invalid duration `366`, eg `snooze 3d`.")),
+            ("53w", Err("Invalid duration `53w`, eg `snooze 3d`.")),
+            ("0d", Err("Invalid duration `0d`, eg `snooze 3d`.")),
+            ("-3d", Err("Invalid duration `-3d`, eg `snooze 3d`.")),
+            ("99999999999d", Err("Invalid duration `99999999999d`, eg `snooze 3d`.")),
+            (
+                "9223372036854775807w",
+                Err("Invalid duration `9223372036854775807w`, eg `snooze 3d`."),
+            ),
+            (
+                "99999999999999999999",
+                Err("Invalid duration `99999999999999999999`, eg `snooze 3d`."),
+            ),
+            ("d", Err("Invalid duration `d`, eg `snooze 3d`.")),
+            ("3h", Err("Invalid duration `3h`, eg `snooze 3d`.")),
+        ];
+        for (arg, expected) in cases {
+            let expected = expected.map_err(|e: &str| e.to_string());
+            assert_eq!(parse_days(Some(arg)), expected, "{}", arg);
+        }
+        assert_eq!(
+            parse_days(None),
+            Err("Missing duration, eg `snooze 3d`.".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_commands() {
+        let cases = vec![
+            ("@gitlab-bot retry", vec![Ok(Command::Retry)]),
+            ("  @gitlab-bot   REBASE  please", vec![Ok(Command::Rebase)]),
+            ("@gitlab-bot recheck", vec![Ok(Command::Recheck)]),
+            ("@gitlab-bot snooze 2w", vec![Ok(Command::Snooze(14))]),
+            ("@gitlab-bot help", vec![Ok(Command::Help)]),
+            (
+                "@gitlab-bot deploy",
+                vec![Err("Unknown command `deploy`.".to_string())],
+            ),
+            (
+                "@gitlab-bot snooze",
+                vec![Err("Missing duration, eg `snooze 3d`.".to_string())],
+            ),
+            (
+                "@gitlab-bot snooze 99999999999d",
+                vec![
+                    Err("Invalid duration `99999999999d`, eg `snooze 3d`.".to_string()),
+                ],
+            ),
+            (
+                "Thanks!\n@gitlab-bot retry\nand\n@gitlab-bot snooze 3\n@gitlab-bot nope",
+                vec![
+                    Ok(Command::Retry),
+                    Ok(Command::Snooze(3)),
+                    Err("Unknown command `nope`.".to_string()),
+                ],
+            ),
+        ];
+        for (body, expected) in cases {
+            assert_eq!(parse(body, "gitlab-bot"), expected, "{}", body);
+        }
+    }
+
+    #[test]
+    fn ignores_other_lines() {
+        let cases = vec![
+            "",
+            "please retry",
+            // Only lines starting with the mention hold commands.
+            "ping @gitlab-bot retry",
+            "> @gitlab-bot retry",
+            // Users whose name starts with the bot name.
+            "@gitlab-bot2 retry",
+            "@gitlab-bot-staging retry",
+            "@gitlab-bot_old retry",
+            // A mention without a command.
+            "@gitlab-bot",
+            "@gitlab-bot   ",
+        ];
+        for body in cases {
+            assert!(parse(body, "gitlab-bot").is_empty(), "{}", body);
+        }
+    }
+
+    #[test]
+    fn help_uses_bot_name() {
+        let help = help("review-bot");
+        assert!(help.contains("* `@review-bot retry`"));
+        assert!(!help.contains("{bot}"));
+    }
+}
@@ -0,0 +1,332 @@
+use regex::Regex;
+
+use client::types;
+use markers;
+use templates;
+
+lazy_static! {
+    static ref SUBJECT_REGEX: Regex =
+        Regex::new(r"^(?P<kind>[[:alpha:]]+)(?:\((?P<scope>[^()\s]+)\))?!?: \S").unwrap();
+}
+
+/// A commit that does not follow the conventions.
+#[derive(Serialize, Clone, Debug)]
+pub struct InvalidCommit {
+    pub commit: types::Commit,
+    /// The commit title as a markdown code span, which also works for titles
+    /// with backticks.
+    pub title: String,
+    /// What is wrong with the commit.
+    pub problems: Vec<String>,
+}
+
+/// Marker kind of the warning comment about the invalid commits.
+///
+/// It is keyed by the subjects and problems, so the author is warned again
+/// when they change, but not when the same commits are rebased.
+pub fn warning_marker(invalid: &[InvalidCommit]) -> String {
+    let content = invalid
+        .iter()
+        .map(|i| format!("{}\n{}", i.commit.title, i.problems.join("\n")))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    format!("{}:{}", markers::COMMIT_WARNING, markers::content_hash(&content))
+}
+
+/// Prefixes of commits that are meant to be squashed before merging.
+const WIP_PREFIXES: &[&str] = &["fixup!", "squash!", "amend!", "wip:", "wip ", "[wip]"];
+
+/// The `[commits]` section of the repo config.
+///
+/// Commit messages of merge requests are checked against the Conventional
+/// Commits format, `type(scope)!: description`.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct CommitsConfig {
+    /// Allowed types, eg `feat` or `fix`. Any type is allowed if empty.
+    #[serde(default)]
+    pub types: Vec<String>,
+    /// Allowed scopes. Any scope is allowed if empty.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Maximum length of the first line in characters.
+    pub max_subject_length: Option<usize>,
+    /// Allow `fixup!`, `squash!` and WIP commits.
+    pub allow_wip: Option<bool>,
+    /// Require a `Signed-off-by:` trailer.
+    pub require_signoff: Option<bool>,
+    /// Skip merge commits, which have more than one parent.
+    pub skip_merge_commits: Option<bool>,
+}
+
+/// The subject line of a commit in the Conventional Commits format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConventionalSubject {
+    pub kind: String,
+    pub scope: Option<String>,
+}
+
+/// Parse the subject line of a commit, eg `feat(api)!: remove v1`.
+pub fn parse_subject(subject: &str) -> Option<ConventionalSubject> {
+    SUBJECT_REGEX.captures(subject).map(|caps| ConventionalSubject {
+        kind: caps["kind"].to_string(),
+        scope: caps.name("scope").map(|m| m.as_str().to_string()),
+    })
+}
+
+fn is_wip(subject: &str) -> bool {
+    let subject = subject.to_lowercase();
+    WIP_PREFIXES.iter().any(|p| subject.starts_with(p)) || subject.trim() == "wip"
+}
+
+fn has_signoff(message: &str) -> bool {
+    message
+        .lines()
+        .any(|l| l.trim_left().starts_with("Signed-off-by:"))
+}
+
+impl CommitsConfig {
+    pub fn allow_wip(&self) -> bool {
+        self.allow_wip.unwrap_or(false)
+    }
+
+    pub fn require_signoff(&self) -> bool {
+        self.require_signoff.unwrap_or(false)
+    }
+
+    pub fn skip_merge_commits(&self) -> bool {
+        self.skip_merge_commits.unwrap_or(true)
+    }
+
+    /// Check the commits of a merge request, returning the invalid ones.
+    pub fn invalid_commits(&self, commits: &[types::Commit]) -> Vec<InvalidCommit> {
+        commits
+            .iter()
+            .map(|c| InvalidCommit {
+                commit: c.clone(),
+                title: templates::code_span(&c.title),
+                problems: self.check(c),
+            })
+            .filter(|i| !i.problems.is_empty())
+            .collect()
+    }
+
+    /// Check a commit, returning what is wrong with it.
+    pub fn check(&self, commit: &types::Commit) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.skip_merge_commits() && commit.parent_ids.len() > 1 {
+            return problems;
+        }
+        let subject = commit.message.lines().next().unwrap_or("");
+
+        if is_wip(subject) {
+            if !self.allow_wip() {
+                problems.push("work in progress commit, squash it before merging".to_string());
+            }
+        } else {
+            match parse_subject(subject) {
+                Some(parsed) => {
+                    if !self.types.is_empty() && !self.types.contains(&parsed.kind) {
+                        problems.push(format!(
+                            "type `{}` is not one of: {}",
+                            parsed.kind,
+                            self.types.join(", ")
+                        ));
+                    }
+                    if let Some(scope) = parsed.scope.as_ref() {
+                        if !self.scopes.is_empty() && !self.scopes.contains(scope) {
+                            problems.push(format!(
+                                "scope `{}` is not one of: {}",
+                                scope,
+                                self.scopes.join(", ")
+                            ));
+                        }
+                    }
+                }
+                None => problems.push(
+                    "subject does not match `type(scope): description`".to_string(),
+                ),
+            }
+        }
+
+        if let Some(max) = self.max_subject_length {
+            let len = subject.chars().count();
+            if len > max {
+                problems.push(format!(
+                    "subject is {} characters long, the maximum is {}",
+                    len, max
+                ));
+            }
+        }
+        if self.require_signoff() && !has_signoff(&commit.message) {
+            problems.push("missing `Signed-off-by:` trailer".to_string());
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn commit(message: &str, parents: usize) -> types::Commit {
+        types::Commit {
+            author_email: "dev@example.com".to_string(),
+            author_name: "Dev".to_string(),
+            authored_date: Utc::now(),
+            committed_date: Utc::now(),
+            committer_email: "dev@example.com".to_string(),
+            committer_name: "Dev".to_string(),
+            id: "1a2b3c4d5e6f".to_string(),
+            short_id: "1a2b3c4d".to_string(),
+            title: message.lines().next().unwrap_or("").to_string(),
+            message: message.to_string(),
+            parent_ids: (0..parents).map(|i| format!("parent{}", i)).collect(),
+        }
+    }
+
+    fn config() -> CommitsConfig {
+        CommitsConfig {
+            types: vec!["feat".to_string(), "fix".to_string()],
+            scopes: vec!["api".to_string()],
+            max_subject_length: Some(30),
+            ..CommitsConfig::default()
+        }
+    }
+
+    #[test]
+    fn parses_subjects() {
+        let cases: &[(&str, Option<(&str, Option<&str>)>)] = &[
+            ("feat: add login", Some(("feat", None))),
+            ("feat(api): add login", Some(("feat", Some("api")))),
+            ("feat(api)!: remove v1", Some(("feat", Some("api")))),
+            ("feat!: remove v1", Some(("feat", None))),
+            ("feat(api) : add login", None),
+            ("feat:add login", None),
+            ("feat: ", None),
+            ("feat(): add login", None),
+            ("feat(a b): add login", None),
+            ("Add login", None),
+            ("", None),
+        ];
+        for &(subject, expected) in cases {
+            let expected = expected.map(|(kind, scope)| ConventionalSubject {
+                kind: kind.to_string(),
+                scope: scope.map(|s| s.to_string()),
+            });
+            assert_eq!(parse_subject(subject), expected, "{}", subject);
+        }
+    }
+
+    #[test]
+    fn checks_commits() {
+        let wip = "work in progress commit, squash it before merging";
+        let cases: &[(&str, &[&str])] = &[
+            ("feat(api): add login", &[]),
+            ("fix: typo", &[]),
+            ("fixup! feat: add login", &[wip]),
+            ("squash! feat: add login", &[wip]),
+            ("amend! feat: add login", &[wip]),
+            ("WIP: login", &[wip]),
+            ("wip login", &[wip]),
+            ("[WIP] login", &[wip]),
+            ("wip", &[wip]),
+            ("docs: readme", &["type `docs` is not one of: feat, fix"]),
+            ("feat(ui): add login", &["scope `ui` is not one of: api"]),
+            ("Add login", &["subject does not match `type(scope): description`"]),
+            (
+                "feat: this subject line is way too long",
+                &["subject is 39 characters long, the maximum is 30"],
+            ),
+            (
+                "chore(ui): this subject is too long",
+                &[
+                    "type `chore` is not one of: feat, fix",
+                    "scope `ui` is not one of: api",
+                    "subject is 35 characters long, the maximum is 30",
+                ],
+            ),
+            // Only the first line is the subject.
+            ("feat: add login\n\nA long body that is not checked at all.", &[]),
+        ];
+        let config = config();
+        for &(message, expected) in cases {
+            assert_eq!(config.check(&commit(message, 1)), expected, "{}", message);
+        }
+    }
+
+    #[test]
+    fn allows_wip_commits() {
+        let config = CommitsConfig {
+            allow_wip: Some(true),
+            ..config()
+        };
+        let cases: &[(&str, &[&str])] = &[
+            ("fixup! feat: add login", &[]),
+            ("WIP: login", &[]),
+            (
+                "squash! feat: this is way too long",
+                &["subject is 34 characters long, the maximum is 30"],
+            ),
+        ];
+        for &(message, expected) in cases {
+            assert_eq!(config.check(&commit(message, 1)), expected, "{}", message);
+        }
+    }
+
+    #[test]
+    fn requires_signoff() {
+        let config = CommitsConfig {
+            require_signoff: Some(true),
+            ..CommitsConfig::default()
+        };
+        let missing = "missing `Signed-off-by:` trailer";
+        let cases: &[(&str, &[&str])] = &[
+            ("feat: add login\n\nSigned-off-by: Dev <dev@example.com>", &[]),
+            ("feat: add login\n\n  Signed-off-by: Dev <dev@example.com>", &[]),
+            ("feat: add login", &[missing]),
+            ("feat: add login\n\nsigned-off-by: Dev <dev@example.com>", &[missing]),
+            ("feat: add login\n\nNot Signed-off-by: Dev", &[missing]),
+        ];
+        for &(message, expected) in cases {
+            assert_eq!(config.check(&commit(message, 1)), expected, "{}", message);
+        }
+    }
+
+    #[test]
+    fn skips_merge_commits() {
+        let merge = commit("Merge branch 'main' into 'feature'", 2);
+        assert!(config().check(&merge).is_empty());
+
+        let config = CommitsConfig {
+            skip_merge_commits: Some(false),
+            ..config()
+        };
+        assert_eq!(
+            config.check(&merge),
+            vec![
+                "subject does not match `type(scope): description`",
+                "subject is 34 characters long, the maximum is 30",
+            ]
+        );
+    }
+
+    #[test]
+    fn warning_marker_depends_on_problems() {
+        let config = config();
+        let invalid = config.invalid_commits(&[commit("feat: ok", 1), commit("docs: readme", 1)]);
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].title, "`docs: readme`");
+
+        // Rebasing changes the ids, but not the problems.
+        let mut rebased = invalid.clone();
+        rebased[0].commit.short_id = "9f8e7d6c".to_string();
+        assert_eq!(warning_marker(&invalid), warning_marker(&rebased));
+        assert!(warning_marker(&invalid).starts_with("commit_warning:"));
+
+        let changed = config.invalid_commits(&[commit("docs: changelog", 1)]);
+        assert_ne!(warning_marker(&invalid), warning_marker(&changed));
+    }
+}
@@ -0,0 +1,927 @@
+use std::env;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::{self, FromStr};
+
+use chrono::NaiveDate;
+use failure::Error;
+use regex::{self, Regex};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sloggers::types::Severity;
+use toml;
+
+use bot::RepoConfig;
+use calendar::CalendarConfig;
+
+const DEFAULT_INTERVAL_SECS: u64 = 60 * 5;
+/// With webhooks enabled, polling is only a reconciliation sweep for missed
+/// events, so it can run a lot less often.
+const DEFAULT_WEBHOOK_INTERVAL_SECS: u64 = 60 * 30;
+const DEFAULT_CONCURRENCY: usize = 5;
+const DEFAULT_CONFIG_CACHE_TTL_SECS: u64 = 60 * 30;
+const DEFAULT_COMMIT_DEPTH: u64 = 100;
+const DEFAULT_LOG_LEVEL: &str = "trace";
+
+/// One layer of bot settings: the config file, env vars or CLI flags.
+///
+/// Unset values fall through to the layer below.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Base url of the Gitlab instance.
+    pub url: Option<String>,
+    /// API token of the bot user.
+    pub token: Option<String>,
+    /// Seconds between two checks of all merge requests.
+    pub interval: Option<u64>,
+    /// Number of merge requests checked at the same time.
+    pub concurrency: Option<usize>,
+    /// Seconds a loaded repo config is used before it is reloaded.
+    pub config_cache_ttl: Option<u64>,
+    /// Number of commits loaded for the source and target branch.
+    pub commit_depth: Option<u64>,
+    /// One of trace, debug, info, warning, error or critical.
+    pub log_level: Option<String>,
+    pub webhook_addr: Option<String>,
+    pub webhook_secret: Option<String>,
+    pub state_dir: Option<PathBuf>,
+    pub dry_run: Option<bool>,
+    pub dry_run_file: Option<PathBuf>,
+    pub calendar: Option<CalendarConfig>,
+    /// Organization wide defaults of the repo config.
+    pub defaults: Option<toml::Value>,
+    /// Projects or groups outside of its own top-level group a repo config
+    /// may extend.
+    pub extends_allowlist: Option<Vec<String>>,
+}
+
+impl Settings {
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let mut data = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut data))
+            .map_err(|e| format_err!("Could not read config file {}: {}", path.display(), e))?;
+        toml::from_str(&data)
+            .map_err(|e| format_err!("Invalid config file {}: {}", path.display(), e))
+    }
+
+    pub fn from_env() -> Result<Self, Error> {
+        let var = |name: &str| env::var(name).ok();
+        let list = |name: &str| -> Option<Vec<String>> {
+            var(name).map(|v| {
+                v.split(',')
+                    .map(|x| x.trim().to_string())
+                    .filter(|x| x != "")
+                    .collect()
+            })
+        };
+
+        let timezone = var("GITLAB_BOT_TIMEZONE");
+        let working_days = list("GITLAB_BOT_WORKING_DAYS");
+        let holidays = list("GITLAB_BOT_HOLIDAYS");
+        let calendar = if timezone.is_some() || working_days.is_some() || holidays.is_some() {
+            Some(CalendarConfig {
+                timezone,
+                working_days,
+                holidays: holidays
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|d| {
+                        d.parse::<NaiveDate>().map_err(|e| {
+                            format_err!("Invalid date in GITLAB_BOT_HOLIDAYS: {}: {}", d, e)
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            })
+        } else {
+            None
+        };
+
+        Ok(Settings {
+            url: var("GITLAB_BOT_URL"),
+            token: var("GITLAB_BOT_TOKEN"),
+            interval: env_number("GITLAB_BOT_INTERVAL")?,
+            concurrency: env_number("GITLAB_BOT_CONCURRENCY")?,
+            config_cache_ttl: env_number("GITLAB_BOT_CONFIG_CACHE_TTL")?,
+            commit_depth: env_number("GITLAB_BOT_COMMIT_DEPTH")?,
+            log_level: var("GITLAB_BOT_LOG_LEVEL"),
+            webhook_addr: var("GITLAB_BOT_WEBHOOK_ADDR"),
+            webhook_secret: var("GITLAB_BOT_WEBHOOK_SECRET"),
+            state_dir: var("GITLAB_BOT_STATE_DIR").map(PathBuf::from),
+            dry_run: var("GITLAB_BOT_DRY_RUN").map(|v| v == "1" || v.to_lowercase() == "true"),
+            dry_run_file: var("GITLAB_BOT_DRY_RUN_FILE").map(PathBuf::from),
+            calendar,
+            defaults: None,
+            extends_allowlist: list("GITLAB_BOT_EXTENDS_ALLOWLIST"),
+        })
+    }
+
+    /// Put another layer on top. Values set in `over` win.
+    ///
+    /// Calendars are combined with `CalendarConfig::merge`, defaults with
+    /// `merge_values`.
+    pub fn layer(self, over: Settings) -> Settings {
+        let calendar = match (self.calendar, over.calendar) {
+            (Some(base), Some(over)) => Some(base.merge(&over)),
+            (base, over) => over.or(base),
+        };
+        let defaults = match (self.defaults, over.defaults) {
+            (Some(base), Some(over)) => Some(merge_values(base, over)),
+            (base, over) => over.or(base),
+        };
+        Settings {
+            url: over.url.or(self.url),
+            token: over.token.or(self.token),
+            interval: over.interval.or(self.interval),
+            concurrency: over.concurrency.or(self.concurrency),
+            config_cache_ttl: over.config_cache_ttl.or(self.config_cache_ttl),
+            commit_depth: over.commit_depth.or(self.commit_depth),
+            log_level: over.log_level.or(self.log_level),
+            webhook_addr: over.webhook_addr.or(self.webhook_addr),
+            webhook_secret: over.webhook_secret.or(self.webhook_secret),
+            state_dir: over.state_dir.or(self.state_dir),
+            dry_run: over.dry_run.or(self.dry_run),
+            dry_run_file: over.dry_run_file.or(self.dry_run_file),
+            calendar,
+            defaults,
+            extends_allowlist: over.extends_allowlist.or(self.extends_allowlist),
+        }
+    }
+}
+
+fn env_number<T>(name: &str) -> Result<Option<T>, Error>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(v) => v.trim()
+            .parse::<T>()
+            .map(Some)
+            .map_err(|e| format_err!("Invalid {}: {}", name, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn parse_log_level(name: &str) -> Result<Severity, Error> {
+    let level = match name.to_lowercase().as_str() {
+        "trace" => Severity::Trace,
+        "debug" => Severity::Debug,
+        "info" => Severity::Info,
+        "warning" | "warn" => Severity::Warning,
+        "error" => Severity::Error,
+        "critical" => Severity::Critical,
+        _ => bail!("Invalid log level `{}`", name),
+    };
+    Ok(level)
+}
+
+/// Deep merge two TOML values. Tables are merged recursively, everything
+/// else in `over`, including arrays, replaces the value in `base`.
+pub fn merge_values(base: toml::Value, over: toml::Value) -> toml::Value {
+    match (base, over) {
+        (toml::Value::Table(mut base), toml::Value::Table(over)) => {
+            for (key, value) in over {
+                let merged = match base.remove(&key) {
+                    Some(old) => merge_values(old, value),
+                    None => value,
+                };
+                base.insert(key, merged);
+            }
+            toml::Value::Table(base)
+        }
+        (_, over) => over,
+    }
+}
+
+/// A regex setting, compiled once when the config is deserialized.
+///
+/// Invalid patterns never match, `RepoConfig::validate` reports them.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    source: String,
+    regex: Result<Regex, regex::Error>,
+}
+
+impl Pattern {
+    pub fn new<S: Into<String>>(source: S) -> Self {
+        let source = source.into();
+        let regex = Regex::new(&source);
+        Pattern { source, regex }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.regex
+            .as_ref()
+            .map(|re| re.is_match(text))
+            .unwrap_or(false)
+    }
+
+    pub fn error(&self) -> Option<&regex::Error> {
+        self.regex.as_ref().err()
+    }
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Pattern::new("")
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Pattern::new)
+    }
+}
+
+/// A problem found in a repo config.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConfigProblem {
+    /// The setting with the problem, eg `flaky.patterns.0.pattern`.
+    pub key: Option<String>,
+    /// Position of TOML syntax errors, starting at 1.
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+    /// The `extends` reference of the base config the problem comes from.
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+impl ConfigProblem {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        // Some errors span several lines, which does not fit in a list.
+        let message = message
+            .into()
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| *l != "")
+            .collect::<Vec<_>>()
+            .join(" ");
+        ConfigProblem {
+            key: None,
+            line: None,
+            column: None,
+            message,
+            source: None,
+        }
+    }
+
+    pub fn for_key<K: Into<String>, S: Into<String>>(key: K, message: S) -> Self {
+        let mut problem = Self::new(message);
+        problem.key = Some(key.into());
+        problem
+    }
+
+    pub fn from_toml(e: &toml::de::Error) -> Self {
+        let mut message = e.to_string();
+        let mut problem = match e.line_col() {
+            Some((line, col)) => {
+                // The message ends with the line, which is shown separately.
+                let suffix = format!(" at line {}", line + 1);
+                if message.ends_with(&suffix) {
+                    let len = message.len() - suffix.len();
+                    message.truncate(len);
+                }
+                let mut problem = Self::new(message);
+                problem.line = Some(line + 1);
+                problem.column = Some(col + 1);
+                problem
+            }
+            None => Self::new(message),
+        };
+        problem.message = format!("invalid TOML: {}", problem.message);
+        problem
+    }
+
+    pub fn invalid_regex(key: &str, e: &regex::Error) -> Self {
+        // Syntax errors show the pattern with a pointer to the position,
+        // followed by the actual error on the last line.
+        let message = e.to_string();
+        let summary = message
+            .lines()
+            .rev()
+            .map(|l| l.trim())
+            .find(|l| *l != "")
+            .unwrap_or("")
+            .trim_left_matches("error: ")
+            .to_string();
+        Self::for_key(key, format!("invalid regex: {}", summary))
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref key) = self.key {
+            write!(f, "{}: ", key)?;
+        }
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, "line {}, column {}: ", line, column)?;
+        }
+        write!(f, "{}", self.message)?;
+        if let Some(ref source) = self.source {
+            write!(f, " (from `{}`)", source)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a TOML file, with the position of syntax errors.
+pub fn parse_toml(data: &[u8]) -> Result<toml::Value, ConfigProblem> {
+    let text = str::from_utf8(data)
+        .map_err(|e| ConfigProblem::new(format!("not valid UTF-8: {}", e)))?;
+    toml::from_str::<toml::Value>(text).map_err(|e| ConfigProblem::from_toml(&e))
+}
+
+/// Split an `extends` reference like `group/project:path/to/base.toml` into
+/// the project path and the file path.
+pub fn parse_extends(reference: &str) -> Result<(String, String), Error> {
+    let mut parts = reference.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(project), Some(path)) if project != "" && path != "" => {
+            Ok((project.to_string(), path.to_string()))
+        }
+        _ => bail!(
+            "Invalid reference `{}`, expected `group/project:path/to/base.toml`",
+            reference
+        ),
+    }
+}
+
+/// Follows the `extends` references of a repo config through its bases.
+///
+/// Loading the bases is left to the caller:
+///
+/// ```ignore
+/// while let Some(reference) = chain.next() {
+///     chain.push(reference, load(&reference)?);
+/// }
+/// ```
+pub struct ExtendsChain {
+    local: toml::Value,
+    /// Loaded bases with their reference, the innermost first.
+    bases: Vec<(String, toml::Value)>,
+    problems: Vec<ConfigProblem>,
+    done: bool,
+}
+
+impl ExtendsChain {
+    pub fn new(local: toml::Value) -> Self {
+        ExtendsChain {
+            local,
+            bases: Vec::new(),
+            problems: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// Get the reference of the next base to load.
+    ///
+    /// Returns None at the end of the chain and on a cycle, which is
+    /// reported as a problem.
+    pub fn next(&mut self) -> Option<String> {
+        if self.done {
+            return None;
+        }
+        let reference = self.bases
+            .last()
+            .map(|&(_, ref base)| base)
+            .unwrap_or(&self.local)
+            .get("extends")
+            .and_then(|v| v.as_str())
+            .map(|r| r.to_string());
+        let reference = match reference {
+            Some(reference) => reference,
+            None => {
+                self.done = true;
+                return None;
+            }
+        };
+        if self.bases.iter().any(|&(ref r, _)| *r == reference) {
+            let mut chain = self.bases
+                .iter()
+                .map(|&(ref r, _)| r.clone())
+                .collect::<Vec<_>>();
+            chain.push(reference);
+            self.fail(format!("inheritance cycle: {}", chain.join(" -> ")));
+            return None;
+        }
+        Some(reference)
+    }
+
+    /// Add the base loaded for the reference returned by `next`.
+    pub fn push(&mut self, reference: String, base: toml::Value) {
+        self.bases.push((reference, base));
+    }
+
+    /// Cut the chain at the last loaded config, with a problem in its
+    /// `extends` setting.
+    pub fn fail<S: Into<String>>(&mut self, message: S) {
+        let mut problem = ConfigProblem::for_key("extends", message);
+        problem.source = self.bases.last().map(|&(ref r, _)| r.clone());
+        self.problems.push(problem);
+        self.done = true;
+    }
+
+    /// Merge the loaded configs, each one wins over the one it extends.
+    pub fn resolve(self) -> ResolvedConfig {
+        let mut merged: Option<toml::Value> = None;
+        for &(_, ref base) in self.bases.iter().rev() {
+            merged = Some(match merged {
+                Some(outer) => merge_values(outer, base.clone()),
+                None => base.clone(),
+            });
+        }
+        let value = match merged {
+            Some(outer) => merge_values(outer, self.local.clone()),
+            None => self.local.clone(),
+        };
+        ResolvedConfig {
+            value,
+            problems: self.problems,
+            local: self.local,
+            bases: self.bases,
+        }
+    }
+}
+
+/// A repo config merged with the bases it extends.
+pub struct ResolvedConfig {
+    pub value: toml::Value,
+    /// Problems of the chain, eg a base that could not be loaded.
+    pub problems: Vec<ConfigProblem>,
+    local: toml::Value,
+    bases: Vec<(String, toml::Value)>,
+}
+
+impl ResolvedConfig {
+    /// Get the reference of the base that declares a setting, eg
+    /// `templates.files.report`.
+    ///
+    /// Returns None if the local config declares it, or no config does.
+    pub fn source(&self, key: &str) -> Option<&str> {
+        if lookup(&self.local, key).is_some() {
+            return None;
+        }
+        self.bases
+            .iter()
+            .find(|&&(_, ref base)| lookup(base, key).is_some())
+            .map(|&(ref reference, _)| reference.as_str())
+    }
+
+    /// Set the source of problems with settings declared in a base.
+    pub fn tag(&self, problems: &mut [ConfigProblem]) {
+        for problem in problems.iter_mut().filter(|p| p.source.is_none()) {
+            problem.source = match problem.key {
+                Some(ref key) => self.source(key).map(|s| s.to_string()),
+                None => None,
+            };
+        }
+    }
+}
+
+/// Get a value by a dotted key path, numbers index arrays.
+fn lookup<'a>(value: &'a toml::Value, key: &str) -> Option<&'a toml::Value> {
+    key.split('.').fold(Some(value), |value, segment| match value {
+        Some(&toml::Value::Table(ref table)) => table.get(segment),
+        Some(&toml::Value::Array(ref array)) => {
+            segment.parse::<usize>().ok().and_then(|i| array.get(i))
+        }
+        _ => None,
+    })
+}
+
+/// Deserialize a repo config, collecting unknown keys and invalid settings.
+///
+/// Returns no config if the value does not fit the structure of the repo
+/// config, eg a string instead of a number.
+pub fn deserialize_repo_config(value: toml::Value) -> (Option<RepoConfig>, Vec<ConfigProblem>) {
+    let mut problems = Vec::new();
+    let res: Result<RepoConfig, _> = ::serde_ignored::deserialize(value, |path| {
+        problems.push(ConfigProblem::for_key(path.to_string(), "unknown key"));
+    });
+    match res {
+        Ok(config) => {
+            problems.extend(config.validate());
+            (Some(config), problems)
+        }
+        Err(e) => {
+            problems.push(ConfigProblem::new(e.to_string()));
+            (None, problems)
+        }
+    }
+}
+
+/// Settings of the bot.
+///
+/// Loaded from a TOML file, overridden by env vars, overridden by CLI flags.
+#[derive(Clone)]
+pub struct Config {
+    pub endpoint: String,
+    pub token: String,
+    /// Seconds between two checks of all merge requests.
+    pub interval: u64,
+    pub concurrency: usize,
+    pub config_cache_ttl: u64,
+    pub commit_depth: u64,
+    pub log_level: Severity,
+    pub webhook_addr: Option<SocketAddr>,
+    pub webhook_secret: Option<String>,
+    pub state_dir: Option<PathBuf>,
+    pub calendar: CalendarConfig,
+    /// Record changes instead of sending them to Gitlab.
+    pub dry_run: bool,
+    /// JSONL file for the changes recorded in dry-run mode.
+    pub dry_run_file: Option<PathBuf>,
+    /// Organization wide defaults of the repo config, a TOML table.
+    pub repo_defaults: toml::Value,
+    /// Projects or groups, besides the own top-level group, repo configs
+    /// may extend.
+    pub extends_allowlist: Vec<String>,
+}
+
+impl Config {
+    /// Load the layered settings.
+    ///
+    /// The config file is given with `--config` or `GITLAB_BOT_CONFIG` and
+    /// is optional.
+    pub fn load(path: Option<&Path>, flags: Settings) -> Result<Self, Error> {
+        let path = path.map(PathBuf::from)
+            .or(env::var_os("GITLAB_BOT_CONFIG").map(PathBuf::from));
+        let file = match path {
+            Some(path) => Settings::from_file(&path)?,
+            None => Settings::default(),
+        };
+        Self::from_settings(file.layer(Settings::from_env()?).layer(flags))
+    }
+
+    pub fn from_settings(settings: Settings) -> Result<Self, Error> {
+        let endpoint = settings.url.ok_or_else(|| {
+            format_err!("Missing Gitlab url: set `url` in the config file or GITLAB_BOT_URL")
+        })?;
+        let token = settings.token.ok_or_else(|| {
+            format_err!("Missing API token: set `token` in the config file or GITLAB_BOT_TOKEN")
+        })?;
+
+        let webhook_addr = match settings.webhook_addr {
+            Some(addr) => Some(addr.parse::<SocketAddr>()
+                .map_err(|e| format_err!("Invalid webhook address `{}`: {}", addr, e))?),
+            None => None,
+        };
+        // Without a secret, anyone could trigger checks and commands.
+        let has_secret = settings
+            .webhook_secret
+            .as_ref()
+            .map(|s| s != "")
+            .unwrap_or(false);
+        if webhook_addr.is_some() && !has_secret {
+            bail!(
+                "Missing webhook secret: set `webhook_secret` in the config file or \
+                 GITLAB_BOT_WEBHOOK_SECRET to use the webhook listener"
+            );
+        }
+
+        let calendar = settings.calendar.unwrap_or_default();
+        // Fail early on invalid settings.
+        calendar.calendar()?;
+
+        let repo_defaults = settings
+            .defaults
+            .unwrap_or(toml::Value::Table(toml::value::Table::new()));
+        if !repo_defaults.is_table() {
+            bail!("Invalid `defaults`: expected a table");
+        }
+        let (defaults, problems) = deserialize_repo_config(repo_defaults.clone());
+        if defaults.is_none() || !problems.is_empty() {
+            let problems = problems
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>();
+            bail!("Invalid `defaults`: {}", problems.join("; "));
+        }
+
+        let default_interval = if webhook_addr.is_some() {
+            DEFAULT_WEBHOOK_INTERVAL_SECS
+        } else {
+            DEFAULT_INTERVAL_SECS
+        };
+        let log_level = parse_log_level(settings
+            .log_level
+            .as_ref()
+            .map(|l| l.as_str())
+            .unwrap_or(DEFAULT_LOG_LEVEL))?;
+        let concurrency = settings.concurrency.unwrap_or(DEFAULT_CONCURRENCY);
+        if concurrency == 0 {
+            bail!("Invalid concurrency: must be at least 1");
+        }
+
+        Ok(Config {
+            endpoint,
+            token,
+            interval: settings.interval.unwrap_or(default_interval),
+            concurrency,
+            config_cache_ttl: settings
+                .config_cache_ttl
+                .unwrap_or(DEFAULT_CONFIG_CACHE_TTL_SECS),
+            commit_depth: settings.commit_depth.unwrap_or(DEFAULT_COMMIT_DEPTH),
+            log_level,
+            webhook_addr,
+            webhook_secret: settings.webhook_secret,
+            state_dir: settings.state_dir,
+            calendar,
+            dry_run: settings.dry_run.unwrap_or(false),
+            dry_run_file: settings.dry_run_file,
+            repo_defaults,
+            extends_allowlist: settings.extends_allowlist.unwrap_or_default(),
+        })
+    }
+
+    /// Enable the dry-run mode.
+    pub fn with_dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
+
+    /// Deserialize a parsed repo config on top of the organization wide
+    /// defaults.
+    ///
+    /// Returns no config if the file is broken. Problems are returned in
+    /// both cases.
+    pub fn repo_config(&self, repo: toml::Value) -> (Option<RepoConfig>, Vec<ConfigProblem>) {
+        deserialize_repo_config(merge_values(self.repo_defaults.clone(), repo))
+    }
+
+    /// Check if a repo config in a project may extend a config in another
+    /// project.
+    ///
+    /// Projects in the same top-level group are allowed, others only if
+    /// they or one of their groups are in `extends_allowlist`.
+    pub fn extends_allowed(&self, local_project: &str, project: &str) -> bool {
+        let project = project.trim_matches('/').to_lowercase();
+        let top_level = |path: &str| path.split('/').next().unwrap_or("").to_string();
+        if top_level(&project) == top_level(&local_project.trim_matches('/').to_lowercase()) {
+            return true;
+        }
+        self.extends_allowlist.iter().any(|allowed| {
+            let allowed = allowed.trim_matches('/').to_lowercase();
+            allowed != "" && (project == allowed || project.starts_with(&format!("{}/", allowed)))
+        })
+    }
+
+    /// Repo config of projects without a `.gitlab-bot.toml`.
+    pub fn default_repo_config(&self) -> RepoConfig {
+        self.repo_defaults
+            .clone()
+            .try_into::<RepoConfig>()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn value(text: &str) -> toml::Value {
+        toml::from_str(text).unwrap()
+    }
+
+    /// Follow the chain with the bases in `files`, missing ones fail.
+    fn resolve(local: &str, files: &[(&str, &str)]) -> ResolvedConfig {
+        let files = files.iter().cloned().collect::<HashMap<_, _>>();
+        let mut chain = ExtendsChain::new(value(local));
+        while let Some(reference) = chain.next() {
+            match files.get(reference.as_str()) {
+                Some(text) => chain.push(reference.clone(), value(text)),
+                None => chain.fail(format!("could not load `{}`: not found", reference)),
+            }
+        }
+        chain.resolve()
+    }
+
+    #[test]
+    fn merge_values_merges_tables() {
+        let base = value(
+            r#"
+            a = 1
+            b = "base"
+            [nested]
+            x = 1
+            y = 2
+            "#,
+        );
+        let over = value(
+            r#"
+            b = "over"
+            [nested]
+            y = 3
+            z = 4
+            "#,
+        );
+        let expected = value(
+            r#"
+            a = 1
+            b = "over"
+            [nested]
+            x = 1
+            y = 3
+            z = 4
+            "#,
+        );
+        assert_eq!(merge_values(base, over), expected);
+    }
+
+    #[test]
+    fn merge_values_replaces_arrays_and_mixed_types() {
+        let base = value(
+            r#"
+            list = [1, 2, 3]
+            table = { a = 1 }
+            scalar = 1
+            "#,
+        );
+        let over = value(
+            r#"
+            list = [4]
+            table = "replaced"
+            scalar = { b = 2 }
+            "#,
+        );
+        let expected = value(
+            r#"
+            list = [4]
+            table = "replaced"
+            scalar = { b = 2 }
+            "#,
+        );
+        assert_eq!(merge_values(base, over), expected);
+    }
+
+    #[test]
+    fn chain_merges_bases_in_order() {
+        let resolved = resolve(
+            r#"
+            extends = "g/a:base.toml"
+            local = 1
+            "#,
+            &[
+                (
+                    "g/a:base.toml",
+                    r#"
+                    extends = "g/b:base.toml"
+                    shared = "a"
+                    from_a = 1
+                    "#,
+                ),
+                (
+                    "g/b:base.toml",
+                    r#"
+                    shared = "b"
+                    from_b = 1
+                    "#,
+                ),
+            ],
+        );
+        assert!(resolved.problems.is_empty());
+        let expected = value(
+            r#"
+            extends = "g/a:base.toml"
+            local = 1
+            shared = "a"
+            from_a = 1
+            from_b = 1
+            "#,
+        );
+        assert_eq!(resolved.value, expected);
+    }
+
+    #[test]
+    fn chain_detects_cycles() {
+        let resolved = resolve(
+            r#"extends = "g/a:base.toml""#,
+            &[
+                ("g/a:base.toml", r#"extends = "g/b:base.toml""#),
+                ("g/b:base.toml", r#"extends = "g/a:base.toml""#),
+            ],
+        );
+        let mut expected = ConfigProblem::for_key(
+            "extends",
+            "inheritance cycle: g/a:base.toml -> g/b:base.toml -> g/a:base.toml",
+        );
+        expected.source = Some("g/b:base.toml".to_string());
+        assert_eq!(resolved.problems, vec![expected]);
+    }
+
+    #[test]
+    fn chain_detects_self_references() {
+        let resolved = resolve(
+            r#"extends = "g/a:base.toml""#,
+            &[("g/a:base.toml", r#"extends = "g/a:base.toml""#)],
+        );
+        assert_eq!(resolved.problems.len(), 1);
+        assert_eq!(
+            resolved.problems[0].message,
+            "inheritance cycle: g/a:base.toml -> g/a:base.toml"
+        );
+    }
+
+    #[test]
+    fn chain_is_cut_at_missing_bases() {
+        let resolved = resolve(
+            r#"extends = "g/a:base.toml""#,
+            &[("g/a:base.toml", "extends = \"g/missing:base.toml\"\nfrom_a = 1")],
+        );
+        let mut expected = ConfigProblem::for_key(
+            "extends",
+            "could not load `g/missing:base.toml`: not found",
+        );
+        expected.source = Some("g/a:base.toml".to_string());
+        assert_eq!(resolved.problems, vec![expected]);
+        assert_eq!(resolved.value.get("from_a"), Some(&toml::Value::Integer(1)));
+    }
+
+    #[test]
+    fn source_is_the_declaring_layer() {
+        let resolved = resolve(
+            r#"
+            extends = "g/a:base.toml"
+            [templates.files]
+            report = "local.hbs"
+            "#,
+            &[
+                (
+                    "g/a:base.toml",
+                    r#"
+                    extends = "g/b:base.toml"
+                    [templates.files]
+                    report = "a.hbs"
+                    stale_warning = "a.hbs"
+                    "#,
+                ),
+                (
+                    "g/b:base.toml",
+                    r#"
+                    [templates.files]
+                    command_reply = "b.hbs"
+                    [[flaky.patterns]]
+                    pattern = "("
+                    "#,
+                ),
+            ],
+        );
+        assert_eq!(resolved.source("templates.files.report"), None);
+        assert_eq!(
+            resolved.source("templates.files.stale_warning"),
+            Some("g/a:base.toml")
+        );
+        assert_eq!(
+            resolved.source("templates.files.command_reply"),
+            Some("g/b:base.toml")
+        );
+        assert_eq!(resolved.source("unknown"), None);
+
+        let mut problems = vec![
+            ConfigProblem::for_key("flaky.patterns.0.pattern", "invalid regex"),
+            ConfigProblem::for_key("templates.files.report", "not found"),
+        ];
+        resolved.tag(&mut problems);
+        assert_eq!(problems[0].source, Some("g/b:base.toml".to_string()));
+        assert_eq!(problems[1].source, None);
+        assert_eq!(
+            problems[0].to_string(),
+            "flaky.patterns.0.pattern: invalid regex (from `g/b:base.toml`)"
+        );
+    }
+
+    #[test]
+    fn extends_allowed_in_group_or_allowlist() {
+        let config = Config::from_settings(Settings {
+            url: Some("https://gitlab.example.com".to_string()),
+            token: Some("token".to_string()),
+            extends_allowlist: Some(vec!["shared/configs".to_string(), "tools/".to_string()]),
+            ..Settings::default()
+        }).unwrap();
+        assert!(config.extends_allowed("group/app", "group/sub/base"));
+        assert!(config.extends_allowed("Group/app", "group/base"));
+        assert!(config.extends_allowed("group/app", "shared/configs"));
+        assert!(config.extends_allowed("group/app", "tools/bot/base"));
+        assert!(!config.extends_allowed("group/app", "shared/other"));
+        assert!(!config.extends_allowed("group/app", "shared/configs-fork"));
+        assert!(!config.extends_allowed("group/app", "other/base"));
+    }
+}
@@ -0,0 +1,162 @@
+use regex::Regex;
+
+lazy_static! {
+    static ref TASK_REGEX: Regex = Regex::new(r"^\s*(?:[-*+]|\d+[.)])\s+\[ \]").unwrap();
+    static ref ISSUE_REFERENCE_REGEX: Regex = Regex::new(
+        r"(?ix)
+        \b(?:clos(?:e[sd]?|ing)|fix(?:e[sd]|ing)?|resolv(?:e[sd]?|ing)|implement(?:s|ed|ing)?)
+        :?\s+
+        (?:(?:[\w.-]+(?:/[\w.-]+)*)?\#\d+|https?://\S+/issues/\d+)",
+    ).unwrap();
+}
+
+/// Lines of a markdown text outside of fenced code blocks.
+fn prose_lines(text: &str) -> Vec<&str> {
+    let mut in_code = false;
+    text.lines()
+        .filter(|line| {
+            let trimmed = line.trim_left();
+            if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                in_code = !in_code;
+                return false;
+            }
+            !in_code
+        })
+        .collect()
+}
+
+/// Length of the description in characters, without surrounding whitespace.
+pub fn length(description: &str) -> usize {
+    description.trim().chars().count()
+}
+
+/// Get the required headings missing from the description.
+///
+/// Headings are compared case insensitively, so `## testing` matches a
+/// required `## Testing`.
+pub fn missing_headings<'a>(description: &str, headings: &'a [String]) -> Vec<&'a str> {
+    let lines = prose_lines(description)
+        .into_iter()
+        .map(|l| l.trim().trim_right_matches('#').trim().to_lowercase())
+        .collect::<Vec<_>>();
+    headings
+        .iter()
+        .filter(|h| !lines.contains(&h.trim().to_lowercase()))
+        .map(|h| h.as_str())
+        .collect()
+}
+
+/// Count the unchecked task list items, eg `- [ ] Update docs`.
+pub fn unchecked_tasks(description: &str) -> usize {
+    prose_lines(description)
+        .into_iter()
+        .filter(|l| TASK_REGEX.is_match(l))
+        .count()
+}
+
+/// Check for a reference that closes an issue, eg `Closes #123`,
+/// `Fixes group/project#45` or `Resolves https://gitlab.com/a/b/issues/6`.
+pub fn has_issue_reference(description: &str) -> bool {
+    prose_lines(description)
+        .into_iter()
+        .any(|l| ISSUE_REFERENCE_REGEX.is_match(l))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headings(names: &[&str]) -> Vec<String> {
+        names.iter().map(|h| h.to_string()).collect()
+    }
+
+    #[test]
+    fn finds_headings() {
+        let required = headings(&["## Summary", "## Testing"]);
+        let cases: &[(&str, &[&str])] = &[
+            ("## Summary\nText\n\n## Testing\nText", &[]),
+            ("  ## summary  \n## TESTING ##", &[]),
+            ("## Summary", &["## Testing"]),
+            ("## Summary and ## Testing", &["## Summary", "## Testing"]),
+            ("### Summary\n# Testing", &["## Summary", "## Testing"]),
+            ("", &["## Summary", "## Testing"]),
+        ];
+        for &(description, expected) in cases {
+            assert_eq!(missing_headings(description, &required), expected, "{}", description);
+        }
+    }
+
+    #[test]
+    fn ignores_headings_in_code_fences() {
+        let required = headings(&["## Testing"]);
+        let cases: &[(&str, &[&str])] = &[
+            ("```\n## Testing\n```", &["## Testing"]),
+            ("~~~\n## Testing\n~~~", &["## Testing"]),
+            ("```markdown\n## Testing\n```", &["## Testing"]),
+            ("  ```\n## Testing\n  ```", &["## Testing"]),
+            // Unclosed fences run to the end.
+            ("```\ncode\n\n## Testing", &["## Testing"]),
+            ("```\ncode\n```\n## Testing", &[]),
+            ("```\na\n```\n```\nb\n```\n## Testing", &[]),
+        ];
+        for &(description, expected) in cases {
+            assert_eq!(missing_headings(description, &required), expected, "{}", description);
+        }
+    }
+
+    #[test]
+    fn counts_unchecked_tasks() {
+        let cases: &[(&str, usize)] = &[
+            ("- [ ] Update docs", 1),
+            ("* [ ] Update docs", 1),
+            ("+ [ ] Update docs", 1),
+            ("1. [ ] Update docs", 1),
+            ("12) [ ] Update docs", 1),
+            ("  - [ ] Nested", 1),
+            ("- [ ]", 1),
+            ("- [x] Done", 0),
+            ("- [X] Done", 0),
+            ("-[ ] No space after the bullet", 0),
+            ("- [] Not a checkbox", 0),
+            ("[ ] No bullet", 0),
+            ("Text with - [ ] inside", 0),
+            ("- [ ] One\n- [x] Two\n* [ ] Three\n1. [ ] Four", 3),
+            ("```\n- [ ] In code\n```\n- [ ] Outside", 1),
+        ];
+        for &(description, expected) in cases {
+            assert_eq!(unchecked_tasks(description), expected, "{}", description);
+        }
+    }
+
+    #[test]
+    fn finds_issue_references() {
+        let cases: &[(&str, bool)] = &[
+            ("Closes #123", true),
+            ("closes #123", true),
+            ("Close #1, close #2", true),
+            ("Closed #1", true),
+            ("Closing: #7", true),
+            ("Fix #1", true),
+            ("Fixed #1", true),
+            ("Fixes group/project#45", true),
+            ("Fixes group/sub.group/my-project#45", true),
+            ("Resolves https://gitlab.com/a/b/issues/6", true),
+            ("Resolves https://gitlab.com/a/b/-/issues/6", true),
+            ("Resolving #10", true),
+            ("Implements #8", true),
+            ("This change fixes #3 in the parser", true),
+            ("See #123", false),
+            ("Closes 123", false),
+            ("Closes#123", false),
+            ("Closes !12", false),
+            ("Prefixes #12", false),
+            ("Related to https://gitlab.com/a/b/issues/6", false),
+            ("Fixes https://gitlab.com/a/b/merge_requests/6", false),
+            ("```\nCloses #1\n```", false),
+            ("", false),
+        ];
+        for &(description, expected) in cases {
+            assert_eq!(has_issue_reference(description), expected, "{}", description);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::{HashMap, HashSet};
+
+use client::types;
+
+/// Parse the start line of the new file from a hunk header like
+/// `@@ -10,7 +12,8 @@ fn foo() {`.
+fn hunk_new_start(header: &str) -> Option<u64> {
+    let plus = header.find('+')?;
+    let rest = &header[plus + 1..];
+    let end = rest.find(|c: char| c == ',' || c == ' ')
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+/// Get the line numbers of all added or modified lines in a unified diff,
+/// counted in the new version of the file.
+pub fn added_lines(diff: &str) -> HashSet<u64> {
+    let mut lines = HashSet::new();
+    // Line number of the next line in the new file, if inside a hunk.
+    let mut current: Option<u64> = None;
+
+    for line in diff.lines() {
+        if line.starts_with("@@") {
+            current = hunk_new_start(line);
+            continue;
+        }
+        let number = match current {
+            Some(n) => n,
+            None => continue,
+        };
+        if line.starts_with('+') {
+            lines.insert(number);
+            current = Some(number + 1);
+        } else if line.starts_with('-') || line.starts_with('\\') {
+            // Removed lines and "\ No newline at end of file" do not exist
+            // in the new file.
+        } else {
+            current = Some(number + 1);
+        }
+    }
+
+    lines
+}
+
+/// Get the added lines of all changed files of a merge request, by new path.
+pub fn changed_lines(changes: &[types::MergeRequestChange]) -> HashMap<String, HashSet<u64>> {
+    changes
+        .iter()
+        .filter(|c| !c.deleted_file)
+        .map(|c| (c.new_path.clone(), added_lines(&c.diff)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(numbers: &[u64]) -> HashSet<u64> {
+        numbers.iter().cloned().collect()
+    }
+
+    fn change(old_path: &str, new_path: &str, diff: &str) -> types::MergeRequestChange {
+        types::MergeRequestChange {
+            old_path: old_path.to_string(),
+            new_path: new_path.to_string(),
+            a_mode: None,
+            b_mode: None,
+            diff: diff.to_string(),
+            new_file: false,
+            renamed_file: old_path != new_path,
+            deleted_file: false,
+        }
+    }
+
+    #[test]
+    fn hunk_headers() {
+        let cases = vec![
+            ("@@ -10,7 +12,8 @@ fn foo() {", Some(12)),
+            ("@@ -1 +1 @@", Some(1)),
+            ("@@ -0,0 +1,3 @@", Some(1)),
+            ("@@ -5,2 +4 @@", Some(4)),
+            ("@@ -3,1 +0,0 @@", Some(0)),
+            ("@@ -1,2 @@", None),
+            ("@@ -1,2 +x,2 @@", None),
+        ];
+        for (header, expected) in cases {
+            assert_eq!(hunk_new_start(header), expected, "{}", header);
+        }
+    }
+
+    #[test]
+    fn lines_of_several_hunks() {
+        let diff = "@@ -1,3 +1,4 @@\n use std::fs;\n+use std::io;\n \n fn main() {\n\
+                    @@ -20,4 +21,5 @@ fn main() {\n     let a = 1;\n-    let b = 2;\n\
+                    +    let b = 3;\n+    let c = 4;\n     a + b\n }\n";
+        assert_eq!(added_lines(diff), lines(&[2, 22, 23]));
+    }
+
+    #[test]
+    fn lines_of_removed_only_hunks() {
+        let diff = "@@ -1,4 +1,2 @@\n a\n-b\n-c\n d\n@@ -10,2 +8,1 @@\n x\n-y\n";
+        assert_eq!(added_lines(diff), lines(&[]));
+    }
+
+    #[test]
+    fn lines_without_newline_at_end() {
+        let diff = "@@ -1,2 +1,3 @@\n a\n-b\n\\ No newline at end of file\n+b\n+c\n\
+                    \\ No newline at end of file\n";
+        assert_eq!(added_lines(diff), lines(&[2, 3]));
+    }
+
+    #[test]
+    fn lines_before_first_hunk_are_ignored() {
+        let diff = "--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1 +1 @@\n-a\n+b\n";
+        assert_eq!(added_lines(diff), lines(&[1]));
+        assert_eq!(added_lines(""), lines(&[]));
+    }
+
+    #[test]
+    fn changed_lines_by_new_path() {
+        let mut new_file = change("src/new.rs", "src/new.rs", "@@ -0,0 +1,2 @@\n+a\n+b\n");
+        new_file.new_file = true;
+        let renamed = change("src/old.rs", "src/renamed.rs", "@@ -3,2 +3,2 @@\n-x\n+y\n z\n");
+        let moved = change("src/a.rs", "src/b.rs", "");
+        let mut deleted = change("src/gone.rs", "src/gone.rs", "@@ -1,1 +0,0 @@\n-a\n");
+        deleted.deleted_file = true;
+
+        let changed = changed_lines(&[new_file, renamed, moved, deleted]);
+        assert_eq!(changed.len(), 3);
+        assert_eq!(changed["src/new.rs"], lines(&[1, 2]));
+        assert_eq!(changed["src/renamed.rs"], lines(&[3]));
+        assert_eq!(changed["src/b.rs"], lines(&[]));
+        assert!(!changed.contains_key("src/old.rs"));
+        assert!(!changed.contains_key("src/gone.rs"));
+    }
+}
@@ -0,0 +1,22 @@
+use config::Pattern;
+
+/// A `[[failure_hints]]` rule of the repo config.
+///
+/// Failed jobs with a trace matching the pattern are labeled with the
+/// category and hint in the report.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct FailureHint {
+    /// Regex matched against the job trace.
+    pub pattern: Pattern,
+    /// Kind of failure, eg "compile error", "test failure", "lint" or "infra".
+    pub category: String,
+    /// Markdown explaining how to fix the failure, eg a runbook link.
+    pub hint: Option<String>,
+}
+
+/// Find the first rule matching a job trace.
+pub fn classify<'a>(hints: &'a [FailureHint], trace: &str) -> Option<&'a FailureHint> {
+    hints
+        .iter()
+        .find(|h| h.pattern.is_match(trace))
+}
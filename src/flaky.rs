@@ -0,0 +1,55 @@
+use config::Pattern;
+
+const DEFAULT_MAX_RETRIES: usize = 2;
+
+/// Marker of the action recording an automatic retry of a job.
+pub fn retry_marker(pipeline_id: u64, job_id: u64) -> String {
+    format!("{}job-{}", pipeline_marker_prefix(pipeline_id), job_id)
+}
+
+/// Common prefix of the retry markers of a pipeline.
+pub fn pipeline_marker_prefix(pipeline_id: u64) -> String {
+    format!("flaky_retry:pipeline-{}:", pipeline_id)
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct FlakyPattern {
+    /// Regex matched against the job trace.
+    pub pattern: Pattern,
+    /// Explanation shown in the report, eg "network timeout".
+    pub reason: Option<String>,
+}
+
+impl FlakyPattern {
+    pub fn reason(&self) -> String {
+        self.reason
+            .clone()
+            .unwrap_or(format!("matched `{}`", self.pattern.as_str()))
+    }
+}
+
+/// The `[flaky]` section of the repo config.
+///
+/// Failed jobs with a trace matching one of the patterns are retried
+/// automatically.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct FlakyConfig {
+    #[serde(default)]
+    pub patterns: Vec<FlakyPattern>,
+    /// Maximum number of automatic retries per pipeline.
+    pub max_retries: Option<usize>,
+}
+
+impl FlakyConfig {
+    pub fn max_retries(&self) -> usize {
+        self.max_retries.unwrap_or(DEFAULT_MAX_RETRIES)
+    }
+
+    /// Get the reason of the first pattern matching a job trace.
+    pub fn matches(&self, trace: &str) -> Option<String> {
+        self.patterns
+            .iter()
+            .find(|p| p.pattern.is_match(trace))
+            .map(|p| p.reason())
+    }
+}
@@ -1,21 +1,32 @@
 #![feature(proc_macro, conservative_impl_trait, generators)]
 
 extern crate chrono;
+extern crate chrono_tz;
+#[macro_use]
+extern crate clap;
 #[macro_use]
 extern crate failure;
 extern crate futures_await as futures;
+extern crate handlebars;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
 extern crate regex;
 extern crate reqwest;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_ignored;
 #[macro_use]
 extern crate serde_json;
 #[macro_use]
 extern crate slog;
 extern crate sloggers;
+#[cfg(test)]
+extern crate tempdir;
 extern crate tokio_core;
 extern crate toml;
+extern crate xml;
 
 // Needed for static musl build.
 // See https://github.com/emk/rust-musl-builder
@@ -23,16 +34,32 @@ extern crate openssl_probe;
 
 mod client;
 mod bot;
-
-use tokio_core::reactor::Core;
+mod calendar;
+mod cli;
+mod commands;
+mod commits;
+mod config;
+mod description;
+mod diff;
+mod failure_hints;
+mod flaky;
+mod markers;
+mod reminders;
+mod reports;
+mod stale;
+mod store;
+mod templates;
+mod trace;
+mod webhook;
 
 fn main() {
     // Needed for static musl build.
     // See https://github.com/emk/rust-musl-builder
     openssl_probe::init_ssl_cert_env_vars();
 
-    let mut core = Core::new().unwrap();
-
-    let bot = bot::Bot::from_env(core.handle()).unwrap();
-    core.run(bot.run()).unwrap();
+    let matches = cli::app().get_matches();
+    if let Err(e) = cli::execute(&matches) {
+        eprintln!("Error: {}", e);
+        ::std::process::exit(1);
+    }
 }
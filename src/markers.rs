@@ -0,0 +1,228 @@
+use serde_json;
+
+const PREFIX: &str = "<!-- gitlab-bot:";
+const SUFFIX: &str = " -->";
+
+/// Current version of the marker format.
+pub const VERSION: u32 = 1;
+
+pub const REPORT: &str = "report";
+pub const REMINDER: &str = "reminder";
+pub const TITLE_WARNING: &str = "title_warning";
+pub const BRANCH_NAME_WARNING: &str = "branch_name_warning";
+/// Followed by a hash of the commit problems, eg `commit_warning:84a5...`.
+pub const COMMIT_WARNING: &str = "commit_warning";
+pub const COMMAND_REPLY: &str = "command_reply";
+
+/// Metadata that identifies a bot comment.
+///
+/// It is embedded as a hidden HTML comment at the end of the comment, eg
+/// `<!-- gitlab-bot:{"kind":"report","v":1} -->`. Bot versions before the
+/// hidden markers used a visible `[kind]` line instead, which is still
+/// recognized.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Marker {
+    /// What the comment is about, eg `report` or `reminder:step-2`.
+    pub kind: String,
+    /// Format version, 0 for visible legacy markers.
+    pub v: u32,
+    /// Head commit the comment was written for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipeline_id: Option<u64>,
+    /// Hash of the comment without the marker.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Comment with commands the comment replies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note_id: Option<u64>,
+}
+
+impl Marker {
+    pub fn new<S: Into<String>>(kind: S) -> Self {
+        Marker {
+            kind: kind.into(),
+            v: VERSION,
+            sha: None,
+            pipeline_id: None,
+            hash: None,
+            note_id: None,
+        }
+    }
+
+    pub fn with_sha(mut self, sha: String) -> Self {
+        self.sha = Some(sha);
+        self
+    }
+
+    pub fn with_pipeline(self, sha: String, pipeline_id: u64) -> Self {
+        let mut marker = self.with_sha(sha);
+        marker.pipeline_id = Some(pipeline_id);
+        marker
+    }
+
+    /// Set the hash of the comment content.
+    pub fn with_hash(mut self, content: &str) -> Self {
+        self.hash = Some(content_hash(content));
+        self
+    }
+
+    pub fn with_note_id(mut self, note_id: u64) -> Self {
+        self.note_id = Some(note_id);
+        self
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.v == 0
+    }
+
+    /// Render the hidden HTML comment.
+    pub fn render(&self) -> String {
+        let json = serde_json::to_string(self).expect("Could not serialize marker");
+        // `-->` would end the HTML comment early.
+        format!("{}{}{}", PREFIX, json.replace("-->", "--\\u003e"), SUFFIX)
+    }
+
+    /// Append the marker to a comment, hashing the content if no hash is set.
+    pub fn embed(&self, content: &str) -> String {
+        let content = content.trim_right();
+        let marker = match self.hash {
+            Some(_) => self.clone(),
+            None => self.clone().with_hash(content),
+        };
+        format!("{}\n\n{}", content, marker.render())
+    }
+
+    /// Parse the marker of a comment.
+    pub fn parse(body: &str) -> Option<Marker> {
+        if let Some(start) = body.rfind(PREFIX) {
+            let rest = &body[start + PREFIX.len()..];
+            if let Some(end) = rest.find(SUFFIX) {
+                if let Ok(marker) = serde_json::from_str(&rest[..end]) {
+                    return Some(marker);
+                }
+            }
+        }
+        Self::parse_legacy(body)
+    }
+
+    /// Parse a visible marker on the last line, eg `[report]`.
+    fn parse_legacy(body: &str) -> Option<Marker> {
+        let last = body.lines().rev().map(|l| l.trim()).find(|l| *l != "")?;
+        if last.len() > 2 && last.starts_with('[') && last.ends_with(']') && !last.contains(' ') {
+            let mut marker = Marker::new(&last[1..last.len() - 1]);
+            marker.v = 0;
+            Some(marker)
+        } else {
+            None
+        }
+    }
+}
+
+/// Convert a legacy `[kind]` marker, eg from an old action log, to the kind.
+pub fn normalize_kind(kind: &str) -> String {
+    if kind.len() > 2 && kind.starts_with('[') && kind.ends_with(']') {
+        kind[1..kind.len() - 1].to_string()
+    } else {
+        kind.to_string()
+    }
+}
+
+/// FNV-1a hash of the content, which is stable across Rust versions unlike
+/// the std hasher.
+pub fn content_hash(content: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in content.trim().bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_marker_round_trips() {
+        let marker = Marker::new(REPORT).with_pipeline("1a2b3c4d".to_string(), 42);
+        let body = marker.embed("## Report\n\nAll good.\n\n");
+
+        assert!(body.starts_with("## Report\n\nAll good.\n\n<!-- gitlab-bot:{"));
+        assert!(body.ends_with(" -->"));
+        let parsed = Marker::parse(&body).unwrap();
+        assert_eq!(parsed, marker.with_hash("## Report\n\nAll good."));
+        assert!(!parsed.is_legacy());
+    }
+
+    #[test]
+    fn reply_marker_keeps_note_id() {
+        let body = Marker::new(COMMAND_REPLY).with_note_id(11).embed("Done.");
+        assert!(body.contains("\"note_id\":11"));
+        assert_eq!(Marker::parse(&body).unwrap().note_id, Some(11));
+        // Other markers do not serialize the field.
+        let body = Marker::new(REPORT).embed("Report");
+        assert!(!body.contains("note_id"));
+        assert_eq!(Marker::parse(&body).unwrap().note_id, None);
+    }
+
+    #[test]
+    fn kind_cannot_end_the_html_comment() {
+        let marker = Marker::new("odd-->kind");
+        let body = marker.embed("text");
+        assert_eq!(body.matches("-->").count(), 1);
+        assert_eq!(Marker::parse(&body).unwrap().kind, "odd-->kind");
+    }
+
+    #[test]
+    fn last_marker_wins() {
+        let quoted = Marker::new(REMINDER).embed("> quoted");
+        let body = Marker::new(COMMAND_REPLY).embed(&quoted);
+        assert_eq!(Marker::parse(&body).unwrap().kind, COMMAND_REPLY);
+    }
+
+    #[test]
+    fn hash_ignores_surrounding_whitespace() {
+        let a = Marker::new(REPORT).with_hash("text");
+        let b = Marker::new(REPORT).with_hash("\ntext  \n");
+        assert_eq!(a.hash, b.hash);
+        assert_ne!(a.hash, Marker::new(REPORT).with_hash("other").hash);
+        // FNV-1a of an empty input is the offset basis.
+        assert_eq!(content_hash(""), "cbf29ce484222325");
+        assert_eq!(content_hash("a"), "af63dc4c8601ec8c");
+    }
+
+    #[test]
+    fn legacy_markers() {
+        let marker = Marker::parse("@alice friendly reminder\n\n[reminder]\n").unwrap();
+        assert_eq!(marker.kind, REMINDER);
+        assert!(marker.is_legacy());
+
+        let marker = Marker::parse("Invalid title.\n[title_warning]").unwrap();
+        assert_eq!(marker.kind, TITLE_WARNING);
+
+        // Only a single word on the last line is a marker.
+        assert_eq!(Marker::parse("[reminder]\nthanks"), None);
+        assert_eq!(Marker::parse("see [the docs]"), None);
+        assert_eq!(Marker::parse("[]"), None);
+        assert_eq!(Marker::parse(""), None);
+    }
+
+    #[test]
+    fn broken_hidden_marker_falls_back_to_legacy() {
+        let body = "text\n<!-- gitlab-bot:{broken -->\n[report]";
+        let marker = Marker::parse(body).unwrap();
+        assert_eq!(marker.kind, REPORT);
+        assert!(marker.is_legacy());
+
+        assert_eq!(Marker::parse("<!-- gitlab-bot:{\"v\":1} -->"), None);
+    }
+
+    #[test]
+    fn legacy_action_kinds_are_normalized() {
+        assert_eq!(normalize_kind("[report]"), "report");
+        assert_eq!(normalize_kind("report"), "report");
+        assert_eq!(normalize_kind("[]"), "[]");
+    }
+}
@@ -0,0 +1,457 @@
+use failure::Error;
+
+use client::types;
+use config::ConfigProblem;
+use templates;
+
+const DEFAULT_DAYS: i64 = 5;
+
+const DEFAULT_MESSAGE: &str = "{{users}} friendly reminder: this merge request has not been \
+                               updated for {{days}} days!\nLet's get going! ;)";
+
+const DEFAULT_STEP_MESSAGE: &str = "{{users}} this merge request has not been updated for \
+                                    {{days}} working days.";
+
+/// Who gets pinged by a reminder.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReminderPing {
+    Author,
+    /// The assignee, or the author if nobody is assigned.
+    Assignee,
+    /// The users listed in `users`.
+    Users,
+}
+
+impl Default for ReminderPing {
+    fn default() -> Self {
+        ReminderPing::Author
+    }
+}
+
+/// Resolve the usernames to ping.
+fn recipients(ping: &[ReminderPing], users: &[String], mr: &types::MergeRequest) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for p in ping {
+        let new = match *p {
+            ReminderPing::Author => vec![mr.author.username.clone()],
+            ReminderPing::Assignee => vec![
+                mr.assignee
+                    .as_ref()
+                    .map(|a| a.username.clone())
+                    .unwrap_or(mr.author.username.clone()),
+            ],
+            ReminderPing::Users => users
+                .iter()
+                .map(|u| u.trim_left_matches('@').to_string())
+                .collect(),
+        };
+        for name in new {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+fn render_message(
+    message: &str,
+    users: &[String],
+    days: i64,
+    mr: &types::MergeRequest,
+) -> Result<String, Error> {
+    let users = users
+        .iter()
+        .map(|u| format!("@{}", u))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let context = templates::MessageContext {
+        merge_request: mr.clone(),
+        users,
+        days,
+    };
+    templates::render_message(message, &context)
+}
+
+/// A step of the reminder escalation ladder.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct EscalationStep {
+    /// Working days without activity before the step fires.
+    pub days: i64,
+    /// Who to ping.
+    #[serde(default)]
+    pub ping: Vec<ReminderPing>,
+    /// Usernames to ping with `"users"` in `ping`, eg a team lead.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Message template, with the same context as the reminder message.
+    pub message: Option<String>,
+    /// Labels to add to the merge request.
+    #[serde(default)]
+    pub add_labels: Vec<String>,
+    /// Close the merge request like a stale one, so it is reopened on
+    /// replies.
+    #[serde(default)]
+    pub close: bool,
+}
+
+impl EscalationStep {
+    /// Marker kind of the comment posted for the step with the given index.
+    pub fn marker(index: usize) -> String {
+        format!("reminder:step-{}", index + 1)
+    }
+
+    pub fn message(&self, mr: &types::MergeRequest) -> Result<String, Error> {
+        render_message(
+            self.message
+                .as_ref()
+                .map(|m| m.as_str())
+                .unwrap_or(DEFAULT_STEP_MESSAGE),
+            &recipients(&self.ping, &self.users, mr),
+            self.days,
+            mr,
+        )
+    }
+}
+
+/// The `[reminders]` section of the repo config.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct ReminderConfig {
+    /// Set to false to disable reminders.
+    pub enabled: Option<bool>,
+    /// Number of days without activity before a reminder is posted.
+    pub days: Option<i64>,
+    #[serde(default)]
+    pub ping: ReminderPing,
+    /// Usernames to ping with `ping = "users"`.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Handlebars message template, see `templates::MessageContext`.
+    /// `{{users}}` are the pinged users, `{{days}}` the number of idle days.
+    pub message: Option<String>,
+    /// Do not remind on work in progress merge requests.
+    #[serde(default)]
+    pub skip_wip: bool,
+    /// Do not remind on merge requests with any of these labels.
+    #[serde(default)]
+    pub skip_labels: Vec<String>,
+    /// Maximum number of reminders without activity in between.
+    pub max_count: Option<usize>,
+    /// Escalation ladder. If set, it replaces the repeated reminder and each
+    /// step fires at most once per merge request.
+    #[serde(default)]
+    pub escalation: Vec<EscalationStep>,
+}
+
+impl ReminderConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    pub fn days(&self) -> i64 {
+        self.days.unwrap_or(DEFAULT_DAYS)
+    }
+
+    /// Check if reminders are skipped for a merge request.
+    pub fn is_skipped(&self, mr: &types::MergeRequest) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        if self.skip_wip && mr.work_in_progress {
+            return true;
+        }
+        mr.labels.iter().any(|l| self.skip_labels.contains(l))
+    }
+
+    /// Check if another reminder may be posted after `count` reminders
+    /// without activity in between.
+    pub fn below_max_count(&self, count: usize) -> bool {
+        self.max_count.map(|max| count < max).unwrap_or(true)
+    }
+
+    /// Usernames of the users to ping.
+    pub fn recipients(&self, mr: &types::MergeRequest) -> Vec<String> {
+        recipients(&[self.ping], &self.users, mr)
+    }
+
+    /// Render the reminder message.
+    pub fn message(&self, mr: &types::MergeRequest) -> Result<String, Error> {
+        render_message(
+            self.message
+                .as_ref()
+                .map(|m| m.as_str())
+                .unwrap_or(DEFAULT_MESSAGE),
+            &self.recipients(mr),
+            self.days(),
+            mr,
+        )
+    }
+
+    /// Find message templates with syntax errors.
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        if let Some(message) = self.message.as_ref() {
+            if let Err(e) = templates::check("message", message) {
+                problems.push(ConfigProblem::for_key("reminders.message", e.to_string()));
+            }
+        }
+        for (index, step) in self.escalation.iter().enumerate() {
+            if let Some(message) = step.message.as_ref() {
+                if let Err(e) = templates::check("message", message) {
+                    let key = format!("reminders.escalation.{}.message", index);
+                    problems.push(ConfigProblem::for_key(key, e.to_string()));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Index of the highest escalation step that is due after the given
+    /// number of idle working days.
+    pub fn due_step(&self, idle_days: i64) -> Option<usize> {
+        self.escalation
+            .iter()
+            .enumerate()
+            .filter(|&(_, step)| idle_days >= step.days)
+            .max_by_key(|&(_, step)| step.days)
+            .map(|(index, _)| index)
+    }
+
+    /// Indexes of the escalation steps at or above the given step.
+    ///
+    /// If any of them already fired, the step must not fire again, which
+    /// also covers steps that were skipped while the bot was not running.
+    pub fn steps_from(&self, index: usize) -> Vec<usize> {
+        let days = self.escalation[index].days;
+        self.escalation
+            .iter()
+            .enumerate()
+            .filter(|&(_, step)| step.days >= days)
+            .map(|(index, _)| index)
+            .collect()
+    }
+}
+
+    /// Index of the escalation step to fire after the given number of idle
+    /// working days, if neither it nor a higher step fired before.
+    ///
+    /// `fired` checks if a step with the given marker was ever posted.
+    pub fn step_to_fire<F>(&self, idle_days: i64, fired: F) -> Option<usize>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let index = self.due_step(idle_days)?;
+        let any_fired = self.steps_from(index)
+            .into_iter()
+            .any(|i| fired(&EscalationStep::marker(i)));
+        if any_fired {
+            None
+        } else {
+            Some(index)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use serde_json;
+
+    use super::*;
+
+    fn merge_request(assignee: Option<&str>, labels: &[&str], wip: bool) -> types::MergeRequest {
+        let fixture: serde_json::Value =
+            serde_json::from_str(include_str!("fixtures/merge_request.json")).unwrap();
+        let mut value = fixture["request"].clone();
+        if let Some(username) = assignee {
+            let mut user = value["author"].clone();
+            user["username"] = json!(username);
+            value["assignee"] = user;
+        }
+        value["labels"] = json!(labels);
+        value["work_in_progress"] = json!(wip);
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn step(days: i64) -> EscalationStep {
+        EscalationStep {
+            days,
+            ..EscalationStep::default()
+        }
+    }
+
+    fn escalation() -> ReminderConfig {
+        ReminderConfig {
+            escalation: vec![step(3), step(10), step(5)],
+            ..ReminderConfig::default()
+        }
+    }
+
+    #[test]
+    fn due_steps() {
+        let config = escalation();
+        let cases = vec![
+            (0, None),
+            (2, None),
+            (3, Some(0)),
+            (4, Some(0)),
+            (5, Some(2)),
+            (9, Some(2)),
+            (10, Some(1)),
+            (100, Some(1)),
+        ];
+        for (idle_days, expected) in cases {
+            assert_eq!(config.due_step(idle_days), expected, "{}", idle_days);
+        }
+        assert_eq!(ReminderConfig::default().due_step(100), None);
+    }
+
+    #[test]
+    fn steps_at_or_above() {
+        let config = escalation();
+        assert_eq!(config.steps_from(0), vec![0, 1, 2]);
+        assert_eq!(config.steps_from(2), vec![1, 2]);
+        assert_eq!(config.steps_from(1), vec![1]);
+    }
+
+    #[test]
+    fn fires_each_step_once() {
+        let config = escalation();
+        let cases = vec![
+            (4, vec![], Some(0)),
+            (4, vec!["reminder:step-1"], None),
+            (5, vec!["reminder:step-1"], Some(2)),
+            (5, vec!["reminder:step-3"], None),
+            (10, vec!["reminder:step-1", "reminder:step-3"], Some(1)),
+            (10, vec!["reminder:step-2"], None),
+            // A higher step fired before the last activity was reset.
+            (4, vec!["reminder:step-2"], None),
+            (2, vec![], None),
+            // Other markers do not count.
+            (4, vec!["reminder", "reminder:step-10"], Some(0)),
+        ];
+        for (idle_days, fired, expected) in cases {
+            let markers = fired.iter().cloned().collect::<HashSet<_>>();
+            assert_eq!(
+                config.step_to_fire(idle_days, |m| markers.contains(m)),
+                expected,
+                "{} {:?}",
+                idle_days,
+                fired
+            );
+        }
+    }
+
+    #[test]
+    fn step_markers() {
+        assert_eq!(EscalationStep::marker(0), "reminder:step-1");
+        assert_eq!(EscalationStep::marker(2), "reminder:step-3");
+    }
+
+    #[test]
+    fn max_count() {
+        let config = ReminderConfig::default();
+        assert!(config.below_max_count(100));
+
+        let config = ReminderConfig {
+            max_count: Some(2),
+            ..ReminderConfig::default()
+        };
+        assert!(config.below_max_count(0));
+        assert!(config.below_max_count(1));
+        assert!(!config.below_max_count(2));
+        assert!(!config.below_max_count(3));
+
+        let config = ReminderConfig {
+            max_count: Some(0),
+            ..ReminderConfig::default()
+        };
+        assert!(!config.below_max_count(0));
+    }
+
+    #[test]
+    fn resolves_recipients() {
+        let unassigned = merge_request(None, &[], false);
+        let assigned = merge_request(Some("lead"), &[], false);
+        let users = vec!["@alice".to_string(), "bob".to_string(), "dev".to_string()];
+        let cases = vec![
+            (vec![ReminderPing::Author], &assigned, vec!["dev"]),
+            (vec![ReminderPing::Assignee], &assigned, vec!["lead"]),
+            (vec![ReminderPing::Assignee], &unassigned, vec!["dev"]),
+            (vec![ReminderPing::Users], &assigned, vec!["alice", "bob", "dev"]),
+            (
+                vec![ReminderPing::Assignee, ReminderPing::Author],
+                &assigned,
+                vec!["lead", "dev"],
+            ),
+            // Everyone is pinged once.
+            (
+                vec![ReminderPing::Author, ReminderPing::Users, ReminderPing::Assignee],
+                &unassigned,
+                vec!["dev", "alice", "bob"],
+            ),
+            (vec![], &assigned, vec![]),
+        ];
+        for (ping, mr, expected) in cases {
+            assert_eq!(recipients(&ping, &users, mr), expected, "{:?}", ping);
+        }
+
+        let config = ReminderConfig {
+            ping: ReminderPing::Assignee,
+            ..ReminderConfig::default()
+        };
+        assert_eq!(config.recipients(&assigned), vec!["lead"]);
+    }
+
+    #[test]
+    fn renders_messages() {
+        let mr = merge_request(Some("lead"), &[], false);
+        let config = ReminderConfig {
+            ping: ReminderPing::Users,
+            users: vec!["alice".to_string(), "bob".to_string()],
+            days: Some(3),
+            message: Some("{{users}}: {{merge_request.title}} idle for {{days}} days".to_string()),
+            ..ReminderConfig::default()
+        };
+        assert_eq!(
+            config.message(&mr).unwrap(),
+            "@alice @bob: Add login idle for 3 days"
+        );
+
+        let step = EscalationStep {
+            ping: vec![ReminderPing::Author, ReminderPing::Assignee],
+            ..step(10)
+        };
+        assert_eq!(
+            step.message(&mr).unwrap(),
+            "@dev @lead this merge request has not been updated for 10 working days."
+        );
+    }
+
+    #[test]
+    fn skip_rules() {
+        let config = ReminderConfig {
+            skip_wip: true,
+            skip_labels: vec!["on hold".to_string()],
+            ..ReminderConfig::default()
+        };
+        let cases = vec![
+            (merge_request(None, &["backend"], false), false),
+            (merge_request(None, &["backend", "on hold"], false), true),
+            (merge_request(None, &[], true), true),
+        ];
+        for (mr, skipped) in cases {
+            assert_eq!(config.is_skipped(&mr), skipped, "{:?}", mr.labels);
+        }
+
+        let wip = merge_request(None, &["on hold"], true);
+        assert!(!ReminderConfig::default().is_skipped(&wip));
+        let disabled = ReminderConfig {
+            enabled: Some(false),
+            ..ReminderConfig::default()
+        };
+        assert!(disabled.is_skipped(&merge_request(None, &[], false)));
+    }
+}
@@ -0,0 +1,308 @@
+use failure::Error;
+
+#[derive(Deserialize, Debug)]
+struct CargoMessage {
+    reason: String,
+    message: Option<CompilerMessage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DiagnosticCode {
+    code: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct DiagnosticSpan {
+    file_name: String,
+    line_start: u64,
+    is_primary: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompilerMessage {
+    message: String,
+    code: Option<DiagnosticCode>,
+    level: String,
+    spans: Vec<DiagnosticSpan>,
+    rendered: Option<String>,
+}
+
+/// A compiler warning or error at a source location.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    /// `warning` or `error`.
+    pub level: String,
+    pub message: String,
+    /// Lint or error code, eg `unused_variables` or `E0308`.
+    pub code: Option<String>,
+    pub file: String,
+    pub line: u64,
+    /// The diagnostic as printed by the compiler.
+    pub rendered: Option<String>,
+}
+
+impl Diagnostic {
+    /// Marker kind that identifies the diagnostic in bot comments.
+    pub fn marker(&self) -> String {
+        format!(
+            "diagnostic:{}:{}:{}",
+            self.file,
+            self.line,
+            self.code.as_ref().unwrap_or(&self.level)
+        )
+    }
+
+    /// Render the body of a diff discussion.
+    pub fn render(&self) -> String {
+        let icon = if self.level == "error" {
+            ":x:"
+        } else {
+            ":warning:"
+        };
+        let mut body = format!("{} **{}**: {}", icon, self.level, self.message);
+        if let Some(code) = self.code.as_ref() {
+            body.push_str(&format!(" (`{}`)", code));
+        }
+        body.push_str("\n\n");
+        if let Some(rendered) = self.rendered.as_ref() {
+            body.push_str(&format!("```text\n{}\n```\n\n", rendered.trim_right()));
+        }
+        body
+    }
+}
+
+/// Parse the output of `cargo build/clippy --message-format=json`.
+///
+/// Lines that are not JSON compiler messages are ignored, so the artifact
+/// can be the full job output.
+pub fn parse(data: &[u8]) -> Result<Vec<Diagnostic>, Error> {
+    let content = String::from_utf8_lossy(data);
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    let mut found_messages = false;
+
+    for line in content.lines() {
+        let line = line.trim();
+        if !line.starts_with('{') {
+            continue;
+        }
+        let msg = match ::serde_json::from_str::<CargoMessage>(line) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        found_messages = true;
+        if msg.reason != "compiler-message" {
+            continue;
+        }
+        let msg = match msg.message {
+            Some(m) => m,
+            None => continue,
+        };
+        if msg.level != "warning" && msg.level != "error" {
+            continue;
+        }
+        // Summaries like "aborting due to previous error" have no spans.
+        let span = match msg.spans.iter().find(|s| s.is_primary) {
+            Some(s) => s,
+            None => continue,
+        };
+
+        let diagnostic = Diagnostic {
+            level: msg.level.clone(),
+            message: msg.message.clone(),
+            code: msg.code.as_ref().map(|c| c.code.clone()),
+            file: span.file_name.clone(),
+            line: span.line_start,
+            rendered: msg.rendered.clone(),
+        };
+        // Diagnostics are repeated for each crate that includes the file.
+        if !diagnostics.contains(&diagnostic) {
+            diagnostics.push(diagnostic);
+        }
+    }
+
+    if !found_messages {
+        bail!("No cargo JSON messages found. Was the build run with --message-format=json?");
+    }
+
+    Ok(diagnostics)
+}
+
+/// Render a summary line of the diagnostics.
+///
+/// `on_changed_lines` is the number of diagnostics that point to lines
+/// changed in the merge request.
+pub fn summary(title: &str, diagnostics: &[Diagnostic], on_changed_lines: usize) -> String {
+    let errors = diagnostics.iter().filter(|d| d.level == "error").count();
+    let warnings = diagnostics.len() - errors;
+    let icon = if errors > 0 {
+        ":x:"
+    } else if warnings > 0 {
+        ":warning:"
+    } else {
+        ":white_check_mark:"
+    };
+    let mut s = format!(
+        "{} **{}**: {} errors, {} warnings",
+        icon, title, errors, warnings
+    );
+    if on_changed_lines > 0 {
+        s.push_str(&format!(" ({} on changed lines)", on_changed_lines));
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn message(level: &str, code: Option<&str>, spans: Value) -> String {
+        json!({
+            "reason": "compiler-message",
+            "package_id": "app 0.1.0 (path+file:///builds/group/app)",
+            "target": {"kind": ["lib"], "name": "app"},
+            "message": {
+                "message": format!("{} message", level),
+                "code": code.map(|c| json!({"code": c, "explanation": null})),
+                "level": level,
+                "spans": spans,
+                "children": [],
+                "rendered": format!("{}: {} message\n", level, level),
+            },
+        }).to_string()
+    }
+
+    fn span(file: &str, line: u64, is_primary: bool) -> Value {
+        json!({
+            "file_name": file,
+            "line_start": line,
+            "line_end": line,
+            "column_start": 1,
+            "column_end": 5,
+            "is_primary": is_primary,
+            "label": null,
+        })
+    }
+
+    #[test]
+    fn parses_diagnostics() {
+        let output = [
+            "   Compiling app v0.1.0 (file:///builds/group/app)".to_string(),
+            message(
+                "warning",
+                Some("unused_variables"),
+                json!([span("src/lib.rs", 3, true)]),
+            ),
+            "{\"reason\":\"compiler-artifact\",\"package_id\":\"app\"}".to_string(),
+            "{not json".to_string(),
+            message(
+                "error",
+                Some("E0308"),
+                json!([span("src/macros.rs", 1, false), span("src/main.rs", 7, true)]),
+            ),
+            "error: Could not compile `app`.".to_string(),
+        ].join("\n");
+
+        let diagnostics = parse(output.as_bytes()).unwrap();
+        assert_eq!(
+            diagnostics,
+            vec![
+                Diagnostic {
+                    level: "warning".to_string(),
+                    message: "warning message".to_string(),
+                    code: Some("unused_variables".to_string()),
+                    file: "src/lib.rs".to_string(),
+                    line: 3,
+                    rendered: Some("warning: warning message\n".to_string()),
+                },
+                Diagnostic {
+                    level: "error".to_string(),
+                    message: "error message".to_string(),
+                    code: Some("E0308".to_string()),
+                    file: "src/main.rs".to_string(),
+                    line: 7,
+                    rendered: Some("error: error message\n".to_string()),
+                },
+            ]
+        );
+        assert_eq!(
+            diagnostics[0].marker(),
+            "diagnostic:src/lib.rs:3:unused_variables"
+        );
+    }
+
+    #[test]
+    fn skips_notes_and_messages_without_primary_span() {
+        let output = [
+            message("note", None, json!([span("src/lib.rs", 1, true)])),
+            message("error", None, json!([])),
+            message("warning", None, json!([span("src/lib.rs", 2, false)])),
+            message("error", None, json!([span("src/lib.rs", 4, true)])),
+        ].join("\n");
+
+        let diagnostics = parse(output.as_bytes()).unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, 4);
+        assert_eq!(diagnostics[0].code, None);
+        assert_eq!(diagnostics[0].marker(), "diagnostic:src/lib.rs:4:error");
+    }
+
+    #[test]
+    fn deduplicates_diagnostics() {
+        let warning = message(
+            "warning",
+            Some("dead_code"),
+            json!([span("src/lib.rs", 3, true)]),
+        );
+        let other_line = message(
+            "warning",
+            Some("dead_code"),
+            json!([span("src/lib.rs", 4, true)]),
+        );
+        let output = [warning.clone(), warning.clone(), other_line, warning].join("\n");
+
+        let lines = parse(output.as_bytes())
+            .unwrap()
+            .iter()
+            .map(|d| d.line)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![3, 4]);
+    }
+
+    #[test]
+    fn requires_json_messages() {
+        assert!(parse(b"").is_err());
+        assert!(parse(b"   Compiling app v0.1.0\nerror: aborting\n{broken").is_err());
+        let artifacts = "{\"reason\":\"build-script-executed\",\"package_id\":\"app\"}";
+        assert!(parse(artifacts.as_bytes()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn summaries() {
+        let diagnostic = |level: &str| Diagnostic {
+            level: level.to_string(),
+            message: "message".to_string(),
+            code: None,
+            file: "src/lib.rs".to_string(),
+            line: 1,
+            rendered: None,
+        };
+        let cases = vec![
+            (vec![], 0, ":white_check_mark: **Clippy**: 0 errors, 0 warnings"),
+            (
+                vec![diagnostic("warning")],
+                1,
+                ":warning: **Clippy**: 0 errors, 1 warnings (1 on changed lines)",
+            ),
+            (
+                vec![diagnostic("error"), diagnostic("warning")],
+                0,
+                ":x: **Clippy**: 1 errors, 1 warnings",
+            ),
+        ];
+        for (diagnostics, on_changed_lines, expected) in cases {
+            assert_eq!(summary("Clippy", &diagnostics, on_changed_lines), expected);
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use failure::Error;
+use xml::reader::{EventReader, XmlEvent};
+
+use super::{ReportFormat, Rendered};
+use templates::ValidationItem;
+
+/// Coverage totals, percentages are in the range 0-100.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Coverage {
+    pub lines: Option<f64>,
+    pub branches: Option<f64>,
+}
+
+/// Coverage of a merge request compared to its target branch.
+#[derive(Clone, Debug)]
+pub struct CoverageChange {
+    pub current: Coverage,
+    /// Coverage of the latest successful pipeline on the target branch.
+    pub base: Option<Coverage>,
+}
+
+impl CoverageChange {
+    /// Change of the line coverage in percentage points.
+    pub fn line_delta(&self) -> Option<f64> {
+        match (self.current.lines, self.base.as_ref().and_then(|b| b.lines)) {
+            (Some(current), Some(base)) => Some(current - base),
+            _ => None,
+        }
+    }
+
+    pub fn branch_delta(&self) -> Option<f64> {
+        match (
+            self.current.branches,
+            self.base.as_ref().and_then(|b| b.branches),
+        ) {
+            (Some(current), Some(base)) => Some(current - base),
+            _ => None,
+        }
+    }
+}
+
+fn percent(covered: u64, valid: u64) -> Option<f64> {
+    if valid == 0 {
+        None
+    } else {
+        Some(covered as f64 / valid as f64 * 100.0)
+    }
+}
+
+/// Parse the totals of a Cobertura XML report.
+///
+/// Uses the `lines-covered`/`lines-valid` counts of the root element if
+/// present, and the `line-rate`/`branch-rate` attributes otherwise.
+pub fn parse_cobertura(data: &[u8]) -> Result<Coverage, Error> {
+    for event in EventReader::new(data) {
+        match event.map_err(|e| format_err!("Invalid Cobertura XML: {}", e))? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                if name.local_name != "coverage" {
+                    bail!("Invalid Cobertura XML: root element must be <coverage>");
+                }
+                let get = |key: &str| -> Option<f64> {
+                    attributes
+                        .iter()
+                        .find(|a| a.name.local_name == key)
+                        .and_then(|a| a.value.parse::<f64>().ok())
+                };
+                let from_counts = |covered: &str, valid: &str| -> Option<f64> {
+                    match (get(covered), get(valid)) {
+                        (Some(c), Some(v)) => percent(c as u64, v as u64),
+                        _ => None,
+                    }
+                };
+
+                let lines = from_counts("lines-covered", "lines-valid")
+                    .or_else(|| get("line-rate").map(|r| r * 100.0));
+                let branches = from_counts("branches-covered", "branches-valid")
+                    .or_else(|| get("branch-rate").map(|r| r * 100.0));
+                return Ok(Coverage { lines, branches });
+            }
+            _ => {}
+        }
+    }
+    bail!("Invalid Cobertura XML: missing <coverage> element")
+}
+
+/// Parse the totals of an lcov tracefile.
+pub fn parse_lcov(data: &[u8]) -> Result<Coverage, Error> {
+    let content = String::from_utf8_lossy(data);
+
+    let (mut lines_found, mut lines_hit) = (0, 0);
+    let (mut branches_found, mut branches_hit) = (0, 0);
+    let mut records = 0;
+
+    for line in content.lines() {
+        let line = line.trim();
+        let (key, value) = match line.find(':') {
+            Some(pos) => (&line[..pos], &line[pos + 1..]),
+            None => {
+                if line == "end_of_record" {
+                    records += 1;
+                }
+                continue;
+            }
+        };
+        let parse = |v: &str| -> Result<u64, Error> {
+            v.parse::<u64>()
+                .map_err(|_| format_err!("Invalid lcov line: `{}`", line))
+        };
+        match key {
+            "LF" => lines_found += parse(value)?,
+            "LH" => lines_hit += parse(value)?,
+            "BRF" => branches_found += parse(value)?,
+            "BRH" => branches_hit += parse(value)?,
+            _ => {}
+        }
+    }
+
+    if records == 0 {
+        bail!("Invalid lcov file: no records found");
+    }
+
+    Ok(Coverage {
+        lines: percent(lines_hit, lines_found),
+        branches: percent(branches_hit, branches_found),
+    })
+}
+
+pub fn parse(format: ReportFormat, data: &[u8]) -> Result<Coverage, Error> {
+    match format {
+        ReportFormat::Cobertura => parse_cobertura(data),
+        ReportFormat::Lcov => parse_lcov(data),
+        other => bail!("{:?} is not a coverage format", other),
+    }
+}
+
+fn format_value(value: Option<f64>, delta: Option<f64>) -> String {
+    match (value, delta) {
+        (Some(v), Some(d)) => format!("{:.2}% ({:+.2}%)", v, d),
+        (Some(v), None) => format!("{:.2}%", v),
+        (None, _) => "n/a".to_string(),
+    }
+}
+
+/// Render a coverage summary line.
+pub fn render(title: &str, change: CoverageChange, base_branch: &str) -> Rendered {
+    let mut summary = format!(
+        ":bar_chart: **{}**: lines {}, branches {}",
+        title,
+        format_value(change.current.lines, change.line_delta()),
+        format_value(change.current.branches, change.branch_delta()),
+    );
+    if change.base.is_some() {
+        summary.push_str(&format!(" compared to `{}`", base_branch));
+    }
+
+    Rendered {
+        summary: Some(summary),
+        body: None,
+        coverage: Some(change),
+    }
+}
+
+/// Check the line coverage against the `min_coverage` of the repo config.
+///
+/// A missing or failed coverage report does not meet the minimum.
+pub fn min_coverage_item(min: f64, change: Option<&CoverageChange>) -> ValidationItem {
+    let label = format!("Coverage is at least {}%", min);
+    match change.and_then(|c| c.current.lines) {
+        Some(lines) => {
+            ValidationItem::with_detail(label, lines >= min, format!("is {:.2}%", lines))
+        }
+        None => ValidationItem::with_detail(label, false, "is unknown".to_string()),
+    }
+}
+
+/// Check the line coverage change against the `max_coverage_drop` of the
+/// repo config, if the coverage of the target branch is known.
+pub fn max_coverage_drop_item(max_drop: f64, change: &CoverageChange) -> Option<ValidationItem> {
+    change.line_delta().map(|delta| {
+        ValidationItem::with_detail(
+            format!("Coverage dropped by at most {}%", max_drop),
+            -delta <= max_drop,
+            format!("dropped by {:.2}%", -delta),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: Option<f64>, expected: Option<f64>) {
+        match (actual, expected) {
+            (Some(a), Some(e)) => assert!((a - e).abs() < 1e-9, "{} != {}", a, e),
+            _ => assert_eq!(actual, expected),
+        }
+    }
+
+    fn change(current: Option<f64>, base: Option<f64>) -> CoverageChange {
+        CoverageChange {
+            current: Coverage {
+                lines: current,
+                branches: None,
+            },
+            base: base.map(|lines| Coverage {
+                lines: Some(lines),
+                branches: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn cobertura_prefers_counts() {
+        let coverage = parse_cobertura(include_bytes!("fixtures/cobertura.xml")).unwrap();
+        assert_close(coverage.lines, Some(75.0));
+        assert_close(coverage.branches, Some(75.0));
+    }
+
+    #[test]
+    fn cobertura_falls_back_to_rates() {
+        let coverage = parse_cobertura(include_bytes!("fixtures/cobertura_rates.xml")).unwrap();
+        assert_close(coverage.lines, Some(81.25));
+        assert_eq!(coverage.branches, None);
+    }
+
+    #[test]
+    fn cobertura_without_lines() {
+        let coverage =
+            parse_cobertura(b"<coverage lines-valid=\"0\" lines-covered=\"0\"/>").unwrap();
+        assert_eq!(coverage, Coverage::default());
+        // Zero counts fall back to the rate.
+        let coverage =
+            parse_cobertura(b"<coverage lines-valid=\"0\" lines-covered=\"0\" line-rate=\"1\"/>")
+                .unwrap();
+        assert_close(coverage.lines, Some(100.0));
+    }
+
+    #[test]
+    fn rejects_invalid_cobertura() {
+        let cases: &[&[u8]] = &[
+            b"<report line-rate=\"0.5\"/>",
+            b"<?xml version=\"1.0\" ?>",
+            b"<coverage",
+            b"",
+        ];
+        for case in cases {
+            assert!(
+                parse_cobertura(case).is_err(),
+                "{}",
+                String::from_utf8_lossy(case)
+            );
+        }
+        let err = parse_cobertura(b"<report/>").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid Cobertura XML: root element must be <coverage>"
+        );
+    }
+
+    #[test]
+    fn lcov_sums_records() {
+        let coverage = parse_lcov(include_bytes!("fixtures/lcov.info")).unwrap();
+        assert_close(coverage.lines, Some(80.0));
+        assert_close(coverage.branches, Some(50.0));
+    }
+
+    #[test]
+    fn lcov_without_branches() {
+        let coverage = parse_lcov(include_bytes!("fixtures/lcov_no_branches.info")).unwrap();
+        assert_close(coverage.lines, Some(25.0));
+        assert_eq!(coverage.branches, None);
+
+        let coverage = parse_lcov(b"SF:src/empty.js\nLF:0\nLH:0\nend_of_record\n").unwrap();
+        assert_eq!(coverage, Coverage::default());
+    }
+
+    #[test]
+    fn rejects_invalid_lcov() {
+        assert!(parse_lcov(b"").is_err());
+        assert!(parse_lcov(b"SF:src/login.js\nLF:2\nLH:1\n").is_err());
+        let err = parse_lcov(b"LF:many\nend_of_record\n").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid lcov line: `LF:many`");
+    }
+
+    #[test]
+    fn parse_by_format() {
+        let data = include_bytes!("fixtures/lcov.info");
+        assert_close(parse(ReportFormat::Lcov, data).unwrap().lines, Some(80.0));
+        assert!(parse(ReportFormat::Cobertura, data).is_err());
+        assert!(parse(ReportFormat::Junit, data).is_err());
+    }
+
+    #[test]
+    fn deltas() {
+        let c = CoverageChange {
+            current: Coverage {
+                lines: Some(80.0),
+                branches: Some(50.0),
+            },
+            base: Some(Coverage {
+                lines: Some(82.5),
+                branches: None,
+            }),
+        };
+        assert_close(c.line_delta(), Some(-2.5));
+        assert_eq!(c.branch_delta(), None);
+        assert_eq!(change(Some(80.0), None).line_delta(), None);
+        assert_eq!(change(None, Some(80.0)).line_delta(), None);
+    }
+
+    #[test]
+    fn renders_summary() {
+        let rendered = render("Coverage", change(Some(80.0), Some(75.0)), "master");
+        assert_eq!(
+            rendered.summary.unwrap(),
+            ":bar_chart: **Coverage**: lines 80.00% (+5.00%), branches n/a compared to `master`"
+        );
+        let rendered = render("Coverage", change(Some(80.0), None), "master");
+        assert_eq!(
+            rendered.summary.unwrap(),
+            ":bar_chart: **Coverage**: lines 80.00%, branches n/a"
+        );
+    }
+
+    #[test]
+    fn min_coverage() {
+        let cases = vec![
+            (Some(change(Some(80.0), None)), true, "is 80.00%"),
+            (Some(change(Some(79.99), None)), false, "is 79.99%"),
+            (Some(change(None, Some(80.0))), false, "is unknown"),
+            (None, false, "is unknown"),
+        ];
+        for (change, valid, detail) in cases {
+            let item = min_coverage_item(80.0, change.as_ref());
+            assert_eq!(item.label, "Coverage is at least 80%");
+            assert_eq!(item.valid, valid, "{:?}", change);
+            assert_eq!(item.detail, Some(detail.to_string()), "{:?}", change);
+        }
+    }
+
+    #[test]
+    fn max_coverage_drop() {
+        let cases = vec![
+            (change(Some(80.0), Some(80.5)), Some((true, "dropped by 0.50%"))),
+            (change(Some(80.0), Some(81.0)), Some((true, "dropped by 1.00%"))),
+            (change(Some(79.0), Some(81.0)), Some((false, "dropped by 2.00%"))),
+            (change(Some(85.0), Some(80.0)), Some((true, "dropped by -5.00%"))),
+            (change(Some(80.0), None), None),
+            (change(None, Some(80.0)), None),
+        ];
+        for (change, expected) in cases {
+            let item = max_coverage_drop_item(1.0, &change);
+            if let Some(ref item) = item {
+                assert_eq!(item.label, "Coverage dropped by at most 1%");
+            }
+            let actual = item.map(|i| (i.valid, i.detail.unwrap()));
+            let expected = expected.map(|(valid, detail)| (valid, detail.to_string()));
+            assert_eq!(actual, expected, "{:?}", change);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use failure::Error;
+use xml::attribute::OwnedAttribute;
+use xml::reader::{EventReader, XmlEvent};
+
+use super::{escape_html, truncate, Rendered};
+
+/// Maximum length of a rendered failure message.
+const MAX_MESSAGE_LEN: usize = 2048;
+
+#[derive(Clone, Debug)]
+pub struct FailedTest {
+    pub name: String,
+    pub classname: Option<String>,
+    pub message: String,
+}
+
+impl FailedTest {
+    pub fn full_name(&self) -> String {
+        match self.classname.as_ref() {
+            Some(c) if c != "" => format!("{}::{}", c, self.name),
+            _ => self.name.clone(),
+        }
+    }
+}
+
+/// Totals of a JUnit XML test report.
+#[derive(Clone, Debug, Default)]
+pub struct TestSummary {
+    pub passed: u64,
+    /// Failed tests, including tests with errors.
+    pub failed: Vec<FailedTest>,
+    pub skipped: u64,
+    /// Total time in seconds.
+    pub time: f64,
+}
+
+impl TestSummary {
+    pub fn total(&self) -> u64 {
+        self.passed + self.failed.len() as u64 + self.skipped
+    }
+}
+
+fn attr(attributes: &[OwnedAttribute], name: &str) -> Option<String> {
+    attributes
+        .iter()
+        .find(|a| a.name.local_name == name)
+        .map(|a| a.value.clone())
+}
+
+/// Parse the duration of a test case in seconds.
+///
+/// Some tools format the time with the locale, so `,` is the decimal
+/// separator when there is no `.`, eg `1,5`, and a thousands separator
+/// otherwise, eg `1,234.5`.
+fn parse_time(time: &str) -> Option<f64> {
+    let time = time.trim();
+    let time = if time.contains('.') {
+        time.replace(",", "")
+    } else {
+        time.replace(",", ".")
+    };
+    time.parse().ok()
+}
+
+/// Parse a JUnit XML report.
+///
+/// Both a single `<testsuite>` and `<testsuites>` roots are supported, since
+/// only `<testcase>` elements are evaluated.
+pub fn parse(data: &[u8]) -> Result<TestSummary, Error> {
+    let mut summary = TestSummary::default();
+
+    // The test case currently being parsed.
+    let mut case: Option<(String, Option<String>)> = None;
+    let mut failure: Option<String> = None;
+    let mut skipped = false;
+    let mut in_failure = false;
+
+    for event in EventReader::new(data) {
+        match event.map_err(|e| format_err!("Invalid JUnit XML: {}", e))? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => match name.local_name.as_str() {
+                "testcase" => {
+                    let name = attr(&attributes, "name").unwrap_or_default();
+                    case = Some((name, attr(&attributes, "classname")));
+                    failure = None;
+                    skipped = false;
+                    if let Some(time) = attr(&attributes, "time") {
+                        summary.time += parse_time(&time).unwrap_or(0.0);
+                    }
+                }
+                "failure" | "error" if case.is_some() => {
+                    in_failure = true;
+                    let message = attr(&attributes, "message").unwrap_or_default();
+                    failure = Some(match failure {
+                        Some(prev) => format!("{}\n{}", prev, message),
+                        None => message,
+                    });
+                }
+                "skipped" if case.is_some() => {
+                    skipped = true;
+                }
+                _ => {}
+            },
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                if in_failure {
+                    if let Some(f) = failure.as_mut() {
+                        if f != "" {
+                            f.push('\n');
+                        }
+                        f.push_str(text.trim());
+                    }
+                }
+            }
+            XmlEvent::EndElement { name } => match name.local_name.as_str() {
+                "failure" | "error" => {
+                    in_failure = false;
+                }
+                "testcase" => {
+                    if let Some((name, classname)) = case.take() {
+                        if let Some(message) = failure.take() {
+                            summary.failed.push(FailedTest {
+                                name,
+                                classname,
+                                message,
+                            });
+                        } else if skipped {
+                            summary.skipped += 1;
+                        } else {
+                            summary.passed += 1;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Render a JUnit report.
+///
+/// The totals become the summary, the failing tests the body.
+/// `max_size` limits the size of the body.
+pub fn render(title: &str, data: &[u8], max_size: usize) -> Result<Rendered, Error> {
+    let summary = parse(data)?;
+
+    let status = if summary.failed.len() > 0 {
+        ":x:"
+    } else {
+        ":white_check_mark:"
+    };
+    let totals = format!(
+        "{} **{}**: {} passed, {} failed, {} skipped ({} total) in {:.2}s",
+        status,
+        title,
+        summary.passed,
+        summary.failed.len(),
+        summary.skipped,
+        summary.total(),
+        summary.time,
+    );
+
+    if summary.failed.len() == 0 {
+        return Ok(Rendered {
+            summary: Some(totals),
+            body: None,
+            coverage: None,
+        });
+    }
+
+    let mut body = String::from("Failed tests:\n\n");
+    for (index, test) in summary.failed.iter().enumerate() {
+        let entry = format!(
+            "<details><summary><code>{}</code></summary><pre><code>{}</code></pre></details>\n",
+            escape_html(&test.full_name()),
+            escape_html(truncate(&test.message, MAX_MESSAGE_LEN)),
+        );
+        if body.len() + entry.len() > max_size {
+            body.push_str(&format!(
+                "\n_... and {} more failed tests._\n",
+                summary.failed.len() - index
+            ));
+            break;
+        }
+        body.push_str(&entry);
+    }
+
+    Ok(Rendered {
+        summary: Some(totals),
+        body: Some(body),
+        coverage: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_with_decimal_comma() {
+        assert_eq!(parse_time("1.5"), Some(1.5));
+        assert_eq!(parse_time("1,5"), Some(1.5));
+        assert_eq!(parse_time("1,234.5"), Some(1234.5));
+        assert_eq!(parse_time(" 2 "), Some(2.0));
+        assert_eq!(parse_time("1,2,3"), None);
+        assert_eq!(parse_time(""), None);
+    }
+
+    #[test]
+    fn parses_testsuites() {
+        let summary = parse(include_bytes!("fixtures/junit_testsuites.xml")).unwrap();
+        assert_eq!(summary.passed, 2);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.total(), 5);
+        assert!((summary.time - 1238.25).abs() < 1e-9);
+
+        let failed = &summary.failed;
+        assert_eq!(failed.len(), 2);
+        assert_eq!(failed[0].full_name(), "api::users::deletes_user");
+        assert_eq!(failed[0].message, "assertion failed\nexpected 204, got 500");
+        assert_eq!(failed[1].full_name(), "api::users::lists_users");
+        assert_eq!(failed[1].message, "panicked\nthread 'main' panicked at 'boom'");
+    }
+
+    #[test]
+    fn parses_single_testsuite() {
+        let summary = parse(include_bytes!("fixtures/junit_testsuite.xml")).unwrap();
+        assert_eq!(summary.passed, 2);
+        assert_eq!(summary.failed.len(), 0);
+        assert_eq!(summary.skipped, 0);
+        assert_eq!(summary.time, 1.0);
+    }
+
+    #[test]
+    fn rejects_invalid_xml() {
+        assert!(parse(b"<testsuite><testcase>").is_err());
+    }
+}
@@ -0,0 +1,157 @@
+pub mod cargo;
+pub mod coverage;
+pub mod junit;
+
+use failure::Error;
+
+/// Maximum artifact size in bytes, used if a report does not set `max_size`.
+pub const DEFAULT_MAX_SIZE: usize = 32 * 1024;
+
+/// Largest artifact that is downloaded for formats that are parsed or
+/// truncated, so `max_size` does not apply to the download.
+pub const MAX_DOWNLOAD_SIZE: usize = 10 * 1024 * 1024;
+
+/// Supported report formats.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    /// Artifact is markdown and inserted as-is.
+    Markdown,
+    /// Artifact is plain text and rendered in a code block.
+    Text,
+    /// Artifact is a JUnit XML test report.
+    Junit,
+    /// Artifact is a Cobertura XML coverage report.
+    Cobertura,
+    /// Artifact is an lcov coverage tracefile.
+    Lcov,
+    /// Artifact is the JSON output of `cargo build` or `cargo clippy`.
+    Cargo,
+}
+
+impl ReportFormat {
+    pub fn names() -> &'static [&'static str] {
+        &["markdown", "text", "junit", "cobertura", "lcov", "cargo"]
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        match name {
+            "markdown" => Ok(ReportFormat::Markdown),
+            "text" => Ok(ReportFormat::Text),
+            "junit" => Ok(ReportFormat::Junit),
+            "cobertura" => Ok(ReportFormat::Cobertura),
+            "lcov" => Ok(ReportFormat::Lcov),
+            "cargo" => Ok(ReportFormat::Cargo),
+            other => Err(format_err!(
+                "Unknown report format `{}` (supported: {})",
+                other,
+                Self::names().join(", ")
+            )),
+        }
+    }
+
+    pub fn is_coverage(&self) -> bool {
+        match *self {
+            ReportFormat::Cobertura | ReportFormat::Lcov => true,
+            _ => false,
+        }
+    }
+
+    /// Guess the format from the artifact path.
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".md") || path.ends_with(".markdown") {
+            ReportFormat::Markdown
+        } else {
+            ReportFormat::Text
+        }
+    }
+}
+
+/// Rendered output of a report.
+#[derive(Clone, Debug, Default)]
+pub struct Rendered {
+    /// Short summary line for the Build Status section.
+    pub summary: Option<String>,
+    /// Full content for the Reports section.
+    pub body: Option<String>,
+    /// Parsed totals of coverage reports.
+    pub coverage: Option<coverage::CoverageChange>,
+}
+
+/// Escape text for use in HTML tags like `<pre>`.
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Cut a string down to at most `max` bytes without splitting a character.
+pub fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Render the raw artifact data into markdown.
+pub fn render(
+    format: ReportFormat,
+    title: &str,
+    data: &[u8],
+    max_size: usize,
+) -> Result<Rendered, Error> {
+    let content = String::from_utf8_lossy(data);
+
+    let body = match format {
+        ReportFormat::Markdown => {
+            // Cutting markdown at an arbitrary point can break the rest of
+            // the comment, so refuse to render instead.
+            if data.len() > max_size {
+                bail!(
+                    "Report is too large: {} bytes (limit: {} bytes)",
+                    data.len(),
+                    max_size
+                );
+            }
+            content.into_owned()
+        }
+        ReportFormat::Text => {
+            let text = truncate(&content, max_size);
+            let mut out = format!("```\n{}\n```\n", text.replace("```", "` ` `"));
+            if text.len() < content.len() {
+                out.push_str(&format!(
+                    "\n_Report truncated: showing {} of {} bytes._\n",
+                    text.len(),
+                    content.len()
+                ));
+            }
+            out
+        }
+        ReportFormat::Junit => return junit::render(title, data, max_size),
+        ReportFormat::Cobertura | ReportFormat::Lcov => {
+            let change = coverage::CoverageChange {
+                current: coverage::parse(format, data)?,
+                base: None,
+            };
+            return Ok(coverage::render(title, change, ""));
+        }
+        ReportFormat::Cargo => {
+            let diagnostics = cargo::parse(data)?;
+            return Ok(Rendered {
+                summary: Some(cargo::summary(title, &diagnostics, 0)),
+                body: None,
+                coverage: None,
+            });
+        }
+    };
+
+    Ok(Rendered {
+        summary: None,
+        body: Some(body),
+        coverage: None,
+    })
+}
@@ -0,0 +1,131 @@
+use chrono::{DateTime, Utc};
+
+use calendar::BusinessCalendar;
+use client::types;
+use templates;
+
+pub const WARNING_MARKER: &str = "stale_warning";
+pub const CLOSED_MARKER: &str = "stale_closed";
+pub const REOPENED_MARKER: &str = "stale_reopened";
+
+const DEFAULT_DAYS_UNTIL_WARNING: i64 = 30;
+const DEFAULT_DAYS_UNTIL_CLOSE: i64 = 7;
+
+/// What is due for a merge request without activity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StaleAction {
+    /// Post the warning, with the number of idle working days.
+    Warn(i64),
+    Close,
+}
+
+/// The `[stale]` section of the repo config.
+///
+/// Stale merge requests get a warning first and are closed if nobody reacts
+/// within the grace period. The comments are rendered from the
+/// `stale_warning`, `stale_close` and `stale_reopen` templates.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct StaleConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Working days without activity before the warning is posted.
+    pub days_until_warning: Option<i64>,
+    /// Working days after the warning before the merge request is closed.
+    pub days_until_close: Option<i64>,
+    /// Merge requests with any of these labels are never closed.
+    #[serde(default)]
+    pub exempt_labels: Vec<String>,
+}
+
+impl StaleConfig {
+    pub fn days_until_warning(&self) -> i64 {
+        self.days_until_warning.unwrap_or(DEFAULT_DAYS_UNTIL_WARNING)
+    }
+
+    pub fn days_until_close(&self) -> i64 {
+        self.days_until_close.unwrap_or(DEFAULT_DAYS_UNTIL_CLOSE)
+    }
+
+    pub fn is_exempt(&self, mr: &types::MergeRequest) -> bool {
+        !self.enabled || mr.labels.iter().any(|l| self.exempt_labels.contains(l))
+    }
+
+    /// Decide what is due at `now`.
+    ///
+    /// `warned_at` is the time of the pending warning, one posted after the
+    /// last activity.
+    pub fn action(
+        &self,
+        calendar: &BusinessCalendar,
+        last_activity: DateTime<Utc>,
+        warned_at: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) -> Option<StaleAction> {
+        match warned_at {
+            None => {
+                let idle_days = calendar.working_days_between(last_activity, now);
+                if idle_days >= self.days_until_warning() {
+                    Some(StaleAction::Warn(idle_days))
+                } else {
+                    None
+                }
+            }
+            Some(warned_at) => {
+                if calendar.working_days_between(warned_at, now) >= self.days_until_close() {
+                    Some(StaleAction::Close)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Context of the stale templates.
+    pub fn context(&self, mr: &types::MergeRequest, idle_days: i64) -> templates::StaleContext {
+        templates::StaleContext {
+            merge_request: mr.clone(),
+            author: mr.author.username.clone(),
+            idle_days,
+            days_until_close: self.days_until_close(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    #[test]
+    fn untouched_merge_request_is_warned_then_closed() {
+        let config = StaleConfig {
+            enabled: true,
+            days_until_warning: Some(10),
+            days_until_close: Some(5),
+            ..StaleConfig::default()
+        };
+        let calendar = BusinessCalendar::default();
+        // A monday, nothing happens afterwards.
+        let last_activity = Utc.ymd(2018, 3, 5).and_hms(9, 0, 0);
+
+        // Friday of the next week is the 9th working day.
+        let now = Utc.ymd(2018, 3, 16).and_hms(9, 0, 0);
+        assert_eq!(config.action(&calendar, last_activity, None, now), None);
+
+        let warned_at = Utc.ymd(2018, 3, 19).and_hms(9, 0, 0);
+        assert_eq!(
+            config.action(&calendar, last_activity, None, warned_at),
+            Some(StaleAction::Warn(10))
+        );
+
+        // The grace period counts from the warning.
+        let now = Utc.ymd(2018, 3, 23).and_hms(17, 0, 0);
+        assert_eq!(config.action(&calendar, last_activity, Some(warned_at), now), None);
+        let now = Utc.ymd(2018, 3, 26).and_hms(9, 0, 0);
+        assert_eq!(
+            config.action(&calendar, last_activity, Some(warned_at), now),
+            Some(StaleAction::Close)
+        );
+    }
+}
@@ -0,0 +1,659 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use chrono::{DateTime, Utc};
+use failure::Error;
+use slog::Logger;
+
+use bot::{ConfigRevision, FullMergeRequest, RepoConfig};
+
+/// A repo config together with the time it was loaded.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredProjectConfig {
+    pub project_id: u64,
+    pub config: RepoConfig,
+    /// Branch and commit the config was loaded from. Missing in configs
+    /// stored by older versions, which are ignored.
+    #[serde(default)]
+    pub revision: ConfigRevision,
+    pub loaded_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionKind {
+    CommentCreated,
+    CommentUpdated,
+    CommentDeleted,
+    DiscussionCreated,
+    MergeRequestUpdated,
+    MergeRequestClosed,
+    MergeRequestReopened,
+    JobRetried,
+}
+
+/// A record of something the bot did on a merge request.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Action {
+    pub kind: ActionKind,
+    pub project_id: u64,
+    pub merge_request_iid: u64,
+    pub note_id: Option<u64>,
+    /// Marker of the affected comment or the rule that caused the action,
+    /// eg `report`.
+    pub marker: Option<String>,
+    /// Human readable details, eg why a job was retried.
+    #[serde(default)]
+    pub details: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Action {
+    pub fn new(
+        kind: ActionKind,
+        project_id: u64,
+        merge_request_iid: u64,
+        note_id: Option<u64>,
+        marker: &str,
+    ) -> Self {
+        Action {
+            kind,
+            project_id,
+            merge_request_iid,
+            note_id,
+            marker: Some(marker.to_string()),
+            details: None,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+/// A merge request comment with a command for the bot that was handled.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProcessedCommand {
+    pub project_id: u64,
+    pub merge_request_iid: u64,
+    pub note_id: u64,
+    /// Command name, eg `retry`.
+    pub command: String,
+    /// End of the pause for `snooze` commands.
+    pub snoozed_until: Option<DateTime<Utc>>,
+    pub processed_at: DateTime<Utc>,
+}
+
+/// Persistence backend for the bot cache.
+///
+/// The cache keeps everything in memory and writes through to the store, so
+/// the store is only read on startup.
+pub trait Store {
+    fn merge_requests(&self) -> Result<Vec<FullMergeRequest>, Error>;
+    fn save_merge_request(&mut self, mr: &FullMergeRequest) -> Result<(), Error>;
+    fn remove_merge_request(&mut self, id: u64) -> Result<(), Error>;
+
+    fn project_configs(&self) -> Result<Vec<StoredProjectConfig>, Error>;
+    fn save_project_config(&mut self, conf: &StoredProjectConfig) -> Result<(), Error>;
+
+    fn actions(&self) -> Result<Vec<Action>, Error>;
+    fn add_action(&mut self, action: &Action) -> Result<(), Error>;
+    /// Replace all actions, to drop old ones.
+    fn replace_actions(&mut self, actions: &[Action]) -> Result<(), Error>;
+
+    fn commands(&self) -> Result<Vec<ProcessedCommand>, Error>;
+    fn add_command(&mut self, command: &ProcessedCommand) -> Result<(), Error>;
+    /// Replace all handled commands, to drop old ones.
+    fn replace_commands(&mut self, commands: &[ProcessedCommand]) -> Result<(), Error>;
+}
+
+/// Store that does not persist anything.
+///
+/// State is lost on restart.
+pub struct MemoryStore;
+
+impl Store for MemoryStore {
+    fn merge_requests(&self) -> Result<Vec<FullMergeRequest>, Error> {
+        Ok(Vec::new())
+    }
+
+    fn save_merge_request(&mut self, _mr: &FullMergeRequest) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn remove_merge_request(&mut self, _id: u64) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn project_configs(&self) -> Result<Vec<StoredProjectConfig>, Error> {
+        Ok(Vec::new())
+    }
+
+    fn save_project_config(&mut self, _conf: &StoredProjectConfig) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn actions(&self) -> Result<Vec<Action>, Error> {
+        Ok(Vec::new())
+    }
+
+    fn add_action(&mut self, _action: &Action) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn replace_actions(&mut self, _actions: &[Action]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn commands(&self) -> Result<Vec<ProcessedCommand>, Error> {
+        Ok(Vec::new())
+    }
+
+    fn add_command(&mut self, _command: &ProcessedCommand) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn replace_commands(&mut self, _commands: &[ProcessedCommand]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A change of a file in the store directory.
+enum FileWrite {
+    /// Replace the file with the content.
+    Replace(PathBuf, Vec<u8>),
+    /// Append a line to the file.
+    Append(PathBuf, Vec<u8>),
+    Remove(PathBuf),
+}
+
+impl FileWrite {
+    fn path(&self) -> &Path {
+        match *self {
+            FileWrite::Replace(ref path, _)
+            | FileWrite::Append(ref path, _)
+            | FileWrite::Remove(ref path) => path.as_path(),
+        }
+    }
+
+    fn apply(&self) -> Result<(), Error> {
+        match *self {
+            FileWrite::Replace(ref path, ref data) => write_atomic(path, data),
+            FileWrite::Append(ref path, ref line) => {
+                let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+                f.write_all(line)?;
+                Ok(())
+            }
+            FileWrite::Remove(ref path) => {
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Write a file by writing a temporary file first and renaming it, so a
+/// crash can not leave a truncated file behind.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let tmp = path.with_extension("json.tmp");
+    {
+        let mut f = File::create(&tmp)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Store that keeps JSON files in a directory.
+///
+/// Values are serialized by the caller, but the files are written by a
+/// background thread in order, so neither the cache lock nor the event loop
+/// waits for the disk. Pending writes are finished when the store is
+/// dropped.
+///
+/// Layout:
+/// * `merge_requests/<id>.json`
+/// * `project_configs/<project_id>_<branch>.json`, with `/` in the branch
+///   encoded as `%2F`
+/// * `actions.jsonl`: log of bot actions, appended to and rewritten when
+///   old actions are dropped
+/// * `commands.jsonl`: log of handled comment commands, like the actions
+pub struct FileStore {
+    dir: PathBuf,
+    writes: Option<mpsc::Sender<FileWrite>>,
+    writer: Option<thread::JoinHandle<()>>,
+    log: Logger,
+}
+
+impl FileStore {
+    pub fn open<P: Into<PathBuf>>(dir: P, log: Logger) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join("merge_requests"))?;
+        fs::create_dir_all(dir.join("project_configs"))?;
+
+        let (writes, received) = mpsc::channel::<FileWrite>();
+        let writer_log = log.clone();
+        let writer = thread::Builder::new()
+            .name("store-writer".to_string())
+            .spawn(move || {
+                for write in received {
+                    if let Err(e) = write.apply() {
+                        error!(writer_log, "store_write_failed";
+                            "path" => write.path().display().to_string(),
+                            "error" => e.to_string(),
+                        );
+                    }
+                }
+            })?;
+
+        Ok(FileStore {
+            dir,
+            writes: Some(writes),
+            writer: Some(writer),
+            log,
+        })
+    }
+
+    /// Hand a write to the writer thread.
+    fn send(&self, write: FileWrite) -> Result<(), Error> {
+        self.writes
+            .as_ref()
+            .and_then(|w| w.send(write).ok())
+            .ok_or_else(|| format_err!("The store writer thread stopped"))
+    }
+
+    fn merge_request_path(&self, id: u64) -> PathBuf {
+        self.dir.join("merge_requests").join(format!("{}.json", id))
+    }
+
+    fn project_config_path(&self, project_id: u64, branch: &str) -> PathBuf {
+        let branch = branch.replace("%", "%25").replace("/", "%2F");
+        self.dir
+            .join("project_configs")
+            .join(format!("{}_{}.json", project_id, branch))
+    }
+
+    fn actions_path(&self) -> PathBuf {
+        self.dir.join("actions.jsonl")
+    }
+
+    fn commands_path(&self) -> PathBuf {
+        self.dir.join("commands.jsonl")
+    }
+
+    /// Log a stored value that can not be read, eg one written by an
+    /// incompatible version. It is skipped, so the bot still starts.
+    fn skip_invalid(&self, path: &Path, line: Option<usize>, e: &Error) {
+        warn!(self.log, "store_file_skipped";
+            "path" => path.display().to_string(),
+            "line" => line,
+            "error" => e.to_string(),
+        );
+    }
+
+    /// Read a file with one JSON value per line, skipping invalid lines.
+    fn read_lines<T>(&self, path: &Path) -> Result<Vec<T>, Error>
+    where
+        T: ::serde::de::DeserializeOwned,
+    {
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let reader = BufReader::new(File::open(path)?);
+        let mut items = Vec::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim() == "" {
+                continue;
+            }
+            match ::serde_json::from_str(&line) {
+                Ok(item) => items.push(item),
+                Err(e) => self.skip_invalid(path, Some(index + 1), &e.into()),
+            }
+        }
+        Ok(items)
+    }
+
+    /// Append a JSON value as a line to a file.
+    fn append_line<T: ::serde::Serialize>(&self, path: PathBuf, value: &T) -> Result<(), Error> {
+        let mut line = ::serde_json::to_vec(value)?;
+        line.push(b'\n');
+        self.send(FileWrite::Append(path, line))
+    }
+
+    /// Replace a file with one JSON value per line.
+    fn replace_lines<T>(&self, path: PathBuf, values: &[T]) -> Result<(), Error>
+    where
+        T: ::serde::Serialize,
+    {
+        let mut data = Vec::new();
+        for value in values {
+            ::serde_json::to_writer(&mut data, value)?;
+            data.push(b'\n');
+        }
+        self.send(FileWrite::Replace(path, data))
+    }
+
+    /// Replace a file with a JSON value.
+    fn replace<T: ::serde::Serialize>(&self, path: PathBuf, value: &T) -> Result<(), Error> {
+        let data = ::serde_json::to_vec(value)?;
+        self.send(FileWrite::Replace(path, data))
+    }
+
+    /// Read all JSON files in a subdirectory, skipping invalid files.
+    fn read_dir<T>(&self, name: &str) -> Result<Vec<T>, Error>
+    where
+        T: ::serde::de::DeserializeOwned,
+    {
+        let mut items = Vec::new();
+        for entry in fs::read_dir(self.dir.join(name))? {
+            let path = entry?.path();
+            if path.extension().map(|e| e == "json").unwrap_or(false) {
+                let res = File::open(&path)
+                    .map_err(Error::from)
+                    .and_then(|f| ::serde_json::from_reader(f).map_err(Error::from));
+                match res {
+                    Ok(item) => items.push(item),
+                    Err(e) => self.skip_invalid(&path, None, &e),
+                }
+            }
+        }
+        Ok(items)
+    }
+}
+
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        // Closing the channel stops the writer after the pending writes.
+        self.writes.take();
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
+
+impl Store for FileStore {
+    fn merge_requests(&self) -> Result<Vec<FullMergeRequest>, Error> {
+        self.read_dir("merge_requests")
+    }
+
+    fn save_merge_request(&mut self, mr: &FullMergeRequest) -> Result<(), Error> {
+        self.replace(self.merge_request_path(mr.request.id), mr)
+    }
+
+    fn remove_merge_request(&mut self, id: u64) -> Result<(), Error> {
+        self.send(FileWrite::Remove(self.merge_request_path(id)))
+    }
+
+    fn project_configs(&self) -> Result<Vec<StoredProjectConfig>, Error> {
+        self.read_dir("project_configs")
+    }
+
+    fn save_project_config(&mut self, conf: &StoredProjectConfig) -> Result<(), Error> {
+        let path = self.project_config_path(conf.project_id, &conf.revision.branch);
+        self.replace(path, conf)
+    }
+
+    fn actions(&self) -> Result<Vec<Action>, Error> {
+        self.read_lines(&self.actions_path())
+    }
+
+    fn add_action(&mut self, action: &Action) -> Result<(), Error> {
+        self.append_line(self.actions_path(), action)
+    }
+
+    fn replace_actions(&mut self, actions: &[Action]) -> Result<(), Error> {
+        self.replace_lines(self.actions_path(), actions)
+    }
+
+    fn commands(&self) -> Result<Vec<ProcessedCommand>, Error> {
+        self.read_lines(&self.commands_path())
+    }
+
+    fn add_command(&mut self, command: &ProcessedCommand) -> Result<(), Error> {
+        self.append_line(self.commands_path(), command)
+    }
+
+    fn replace_commands(&mut self, commands: &[ProcessedCommand]) -> Result<(), Error> {
+        self.replace_lines(self.commands_path(), commands)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+
+    use chrono::Utc;
+    use serde_json;
+    use slog::{Discard, Logger};
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn open(dir: &TempDir) -> FileStore {
+        FileStore::open(dir.path(), Logger::root(Discard, o!())).unwrap()
+    }
+
+    fn write_file(path: &Path, content: &str) {
+        File::create(path)
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+    }
+
+    fn merge_request() -> FullMergeRequest {
+        let mut value: serde_json::Value =
+            serde_json::from_str(include_str!("fixtures/merge_request.json")).unwrap();
+        value["repo_config"] = serde_json::to_value(RepoConfig::default()).unwrap();
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn project_config(branch: &str) -> StoredProjectConfig {
+        StoredProjectConfig {
+            project_id: 1,
+            config: RepoConfig::default(),
+            revision: ConfigRevision {
+                branch: branch.to_string(),
+                sha: "1a2b3c4d5e6f".to_string(),
+                short_sha: "1a2b3c4d".to_string(),
+                found: true,
+                preview: false,
+                problems: Vec::new(),
+            },
+            loaded_at: Utc::now(),
+        }
+    }
+
+    fn action(marker: &str) -> Action {
+        Action::new(ActionKind::CommentCreated, 1, 3, None, marker)
+    }
+
+    fn command(note_id: u64) -> ProcessedCommand {
+        ProcessedCommand {
+            project_id: 1,
+            merge_request_iid: 3,
+            note_id,
+            command: "retry".to_string(),
+            snoozed_until: None,
+            processed_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn merge_requests_round_trip() {
+        let dir = TempDir::new("gitlab-bot-store").unwrap();
+        {
+            let mut store = open(&dir);
+            store.save_merge_request(&merge_request()).unwrap();
+        }
+
+        let mut store = open(&dir);
+        let mrs = store.merge_requests().unwrap();
+        assert_eq!(mrs.len(), 1);
+        assert_eq!(mrs[0].request.id, 7);
+        assert_eq!(mrs[0].request.title, "Add login");
+        assert_eq!(mrs[0].comments[0].body, "Looks good");
+        assert_eq!(mrs[0].pipelines[0].status, "success");
+
+        store.remove_merge_request(7).unwrap();
+        // Removing a missing file is fine.
+        store.remove_merge_request(8).unwrap();
+        drop(store);
+        assert!(open(&dir).merge_requests().unwrap().is_empty());
+    }
+
+    #[test]
+    fn project_configs_round_trip() {
+        let dir = TempDir::new("gitlab-bot-store").unwrap();
+        {
+            let mut store = open(&dir);
+            store.save_project_config(&project_config("master")).unwrap();
+            store.save_project_config(&project_config("feature/a%b")).unwrap();
+            // Replaces the first one.
+            store.save_project_config(&project_config("master")).unwrap();
+        }
+
+        let mut branches = open(&dir)
+            .project_configs()
+            .unwrap()
+            .into_iter()
+            .map(|c| c.revision.branch)
+            .collect::<Vec<_>>();
+        branches.sort();
+        assert_eq!(branches, vec!["feature/a%b", "master"]);
+        assert!(dir.path().join("project_configs/1_feature%2Fa%25b.json").exists());
+    }
+
+    #[test]
+    fn project_config_paths_are_encoded() {
+        let dir = TempDir::new("gitlab-bot-store").unwrap();
+        let store = open(&dir);
+        let name = |branch: &str| {
+            store
+                .project_config_path(1, branch)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        };
+        assert_eq!(name("master"), "1_master.json");
+        assert_eq!(name("feature/login"), "1_feature%2Flogin.json");
+        assert_eq!(name("100%"), "1_100%25.json");
+        // Branches that look encoded do not collide with real slashes.
+        assert_eq!(name("feature%2Flogin"), "1_feature%252Flogin.json");
+        assert_ne!(name("a/b"), name("a%2Fb"));
+    }
+
+    #[test]
+    fn actions_round_trip() {
+        let dir = TempDir::new("gitlab-bot-store").unwrap();
+        {
+            let mut store = open(&dir);
+            store.add_action(&action("report")).unwrap();
+            store.add_action(&action("reminder")).unwrap();
+        }
+        {
+            let mut store = open(&dir);
+            let markers = store
+                .actions()
+                .unwrap()
+                .into_iter()
+                .map(|a| a.marker.unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(markers, vec!["report", "reminder"]);
+
+            store.replace_actions(&[action("title_warning")]).unwrap();
+            store.add_action(&action("report")).unwrap();
+        }
+
+        let actions = open(&dir).actions().unwrap();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0].marker, Some("title_warning".to_string()));
+        assert_eq!(actions[0].kind, ActionKind::CommentCreated);
+        assert_eq!(actions[1].marker, Some("report".to_string()));
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let dir = TempDir::new("gitlab-bot-store").unwrap();
+        {
+            let mut store = open(&dir);
+            store.add_command(&command(1)).unwrap();
+            store.add_command(&command(2)).unwrap();
+        }
+        {
+            let mut store = open(&dir);
+            let ids = store
+                .commands()
+                .unwrap()
+                .iter()
+                .map(|c| c.note_id)
+                .collect::<Vec<_>>();
+            assert_eq!(ids, vec![1, 2]);
+            store.replace_commands(&[command(2)]).unwrap();
+        }
+
+        let commands = open(&dir).commands().unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].note_id, 2);
+        assert_eq!(commands[0].command, "retry");
+    }
+
+    #[test]
+    fn skips_invalid_files_and_lines() {
+        let dir = TempDir::new("gitlab-bot-store").unwrap();
+        {
+            let mut store = open(&dir);
+            store.save_merge_request(&merge_request()).unwrap();
+        }
+        let path = dir.path();
+        write_file(&path.join("merge_requests/8.json"), "{\"id\": 8").unwrap();
+        write_file(&path.join("merge_requests/notes.txt"), "not a snapshot").unwrap();
+        write_file(&path.join("project_configs/1_master.json"), "[]").unwrap();
+        let valid = serde_json::to_string(&action("report")).unwrap();
+        let lines = format!("{}\nnot json\n\n{{\"kind\": \"unknown\"}}\n{}\n", valid, valid);
+        write_file(&path.join("actions.jsonl"), &lines).unwrap();
+        write_file(&path.join("commands.jsonl"), "{\"note_id\": 1}\n").unwrap();
+
+        let store = open(&dir);
+        let mrs = store.merge_requests().unwrap();
+        assert_eq!(mrs.len(), 1);
+        assert_eq!(mrs[0].request.id, 7);
+        assert!(store.project_configs().unwrap().is_empty());
+        assert_eq!(store.actions().unwrap().len(), 2);
+        assert!(store.commands().unwrap().is_empty());
+    }
+
+    #[test]
+    fn missing_logs_are_empty() {
+        let dir = TempDir::new("gitlab-bot-store").unwrap();
+        let store = open(&dir);
+        assert!(store.merge_requests().unwrap().is_empty());
+        assert!(store.project_configs().unwrap().is_empty());
+        assert!(store.actions().unwrap().is_empty());
+        assert!(store.commands().unwrap().is_empty());
+    }
+
+    #[test]
+    fn drop_finishes_pending_writes() {
+        let dir = TempDir::new("gitlab-bot-store").unwrap();
+        {
+            let mut store = open(&dir);
+            for i in 0..500 {
+                store.add_action(&action(&format!("step-{}", i))).unwrap();
+            }
+            store.save_merge_request(&merge_request()).unwrap();
+        }
+
+        let store = open(&dir);
+        let actions = store.actions().unwrap();
+        assert_eq!(actions.len(), 500);
+        assert_eq!(actions[499].marker, Some("step-499".to_string()));
+        assert_eq!(store.merge_requests().unwrap().len(), 1);
+        assert!(!dir.path().join("merge_requests/7.json.tmp").exists());
+    }
+}
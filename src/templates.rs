@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+
+use failure::Error;
+use handlebars::{self, Handlebars, Template};
+use serde::Serialize;
+
+use bot::ConfigRevision;
+use client::types;
+use commits;
+use config::ConfigProblem;
+
+pub const REPORT: &str = "report";
+pub const REMINDER: &str = "reminder";
+pub const TITLE_WARNING: &str = "title_warning";
+pub const BRANCH_WARNING: &str = "branch_warning";
+pub const COMMIT_WARNING: &str = "commit_warning";
+pub const STALE_WARNING: &str = "stale_warning";
+pub const STALE_CLOSE: &str = "stale_close";
+pub const STALE_REOPEN: &str = "stale_reopen";
+pub const COMMAND_REPLY: &str = "command_reply";
+
+/// Names of all templates.
+pub const NAMES: &[&str] = &[
+    REPORT,
+    REMINDER,
+    TITLE_WARNING,
+    BRANCH_WARNING,
+    COMMIT_WARNING,
+    STALE_WARNING,
+    STALE_CLOSE,
+    STALE_REOPEN,
+    COMMAND_REPLY,
+];
+
+const DEFAULT_REPORT: &str = include_str!("templates/report.hbs");
+const DEFAULT_REMINDER: &str = include_str!("templates/reminder.hbs");
+const DEFAULT_TITLE_WARNING: &str = include_str!("templates/title_warning.hbs");
+const DEFAULT_BRANCH_WARNING: &str = include_str!("templates/branch_warning.hbs");
+const DEFAULT_COMMIT_WARNING: &str = include_str!("templates/commit_warning.hbs");
+const DEFAULT_STALE_WARNING: &str = include_str!("templates/stale_warning.hbs");
+const DEFAULT_STALE_CLOSE: &str = include_str!("templates/stale_close.hbs");
+const DEFAULT_STALE_REOPEN: &str = include_str!("templates/stale_reopen.hbs");
+const DEFAULT_COMMAND_REPLY: &str = include_str!("templates/command_reply.hbs");
+
+/// The `[templates]` section of the repo config.
+///
+/// Comments are rendered from Handlebars templates. The built-in defaults in
+/// `src/templates/` can be overridden inline or with files in the repo. Each
+/// template gets one of the context structs below.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct TemplateConfig {
+    pub report: Option<String>,
+    pub reminder: Option<String>,
+    pub title_warning: Option<String>,
+    pub branch_warning: Option<String>,
+    pub commit_warning: Option<String>,
+    pub stale_warning: Option<String>,
+    pub stale_close: Option<String>,
+    pub stale_reopen: Option<String>,
+    pub command_reply: Option<String>,
+    /// Paths of template files in the repository, by template name.
+    /// Templates from files take precedence over inline templates.
+    #[serde(default)]
+    pub files: HashMap<String, String>,
+}
+
+impl TemplateConfig {
+    fn get(&self, name: &str) -> Option<&String> {
+        match name {
+            REPORT => self.report.as_ref(),
+            REMINDER => self.reminder.as_ref(),
+            TITLE_WARNING => self.title_warning.as_ref(),
+            BRANCH_WARNING => self.branch_warning.as_ref(),
+            COMMIT_WARNING => self.commit_warning.as_ref(),
+            STALE_WARNING => self.stale_warning.as_ref(),
+            STALE_CLOSE => self.stale_close.as_ref(),
+            STALE_REOPEN => self.stale_reopen.as_ref(),
+            COMMAND_REPLY => self.command_reply.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Override a template.
+    pub fn set(&mut self, name: &str, template: String) -> Result<(), Error> {
+        let slot = match name {
+            REPORT => &mut self.report,
+            REMINDER => &mut self.reminder,
+            TITLE_WARNING => &mut self.title_warning,
+            BRANCH_WARNING => &mut self.branch_warning,
+            COMMIT_WARNING => &mut self.commit_warning,
+            STALE_WARNING => &mut self.stale_warning,
+            STALE_CLOSE => &mut self.stale_close,
+            STALE_REOPEN => &mut self.stale_reopen,
+            COMMAND_REPLY => &mut self.command_reply,
+            _ => bail!(
+                "Unknown template `{}`, expected one of: {}",
+                name,
+                NAMES.join(", ")
+            ),
+        };
+        *slot = Some(template);
+        Ok(())
+    }
+
+    /// Render a template overridden by the repo.
+    ///
+    /// Returns `None` if the repo uses the built-in default.
+    pub fn render_override<T: Serialize>(
+        &self,
+        name: &str,
+        context: &T,
+    ) -> Option<Result<String, Error>> {
+        self.get(name).map(|template| render(name, template, context))
+    }
+
+    /// Find unknown template names and inline templates with syntax errors.
+    ///
+    /// Template files are not loaded, see `check`.
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        for name in self.files.keys() {
+            if !NAMES.contains(&name.as_str()) {
+                problems.push(ConfigProblem::for_key(
+                    format!("templates.files.{}", name),
+                    format!("unknown template, expected one of: {}", NAMES.join(", ")),
+                ));
+            }
+        }
+        for name in NAMES {
+            if let Some(template) = self.get(name) {
+                if let Err(e) = check(name, template) {
+                    problems.push(ConfigProblem::for_key(
+                        format!("templates.{}", name),
+                        e.to_string(),
+                    ));
+                }
+            }
+        }
+        problems
+    }
+}
+
+/// Check the syntax of a template.
+pub fn check(name: &str, template: &str) -> Result<(), Error> {
+    Template::compile(template)
+        .map(|_| ())
+        .map_err(|e| format_err!("Invalid template `{}`: {}", name, e))
+}
+
+fn default_template(name: &str) -> Result<&'static str, Error> {
+    let template = match name {
+        REPORT => DEFAULT_REPORT,
+        REMINDER => DEFAULT_REMINDER,
+        TITLE_WARNING => DEFAULT_TITLE_WARNING,
+        BRANCH_WARNING => DEFAULT_BRANCH_WARNING,
+        COMMIT_WARNING => DEFAULT_COMMIT_WARNING,
+        STALE_WARNING => DEFAULT_STALE_WARNING,
+        STALE_CLOSE => DEFAULT_STALE_CLOSE,
+        STALE_REOPEN => DEFAULT_STALE_REOPEN,
+        COMMAND_REPLY => DEFAULT_COMMAND_REPLY,
+        _ => bail!("Unknown template `{}`", name),
+    };
+    Ok(template)
+}
+
+fn render<T: Serialize>(name: &str, template: &str, context: &T) -> Result<String, Error> {
+    let mut hb = Handlebars::new();
+    // The output is markdown, escaping is up to the context values.
+    hb.register_escape_fn(handlebars::no_escape);
+    hb.render_template(template, context)
+        .map_err(|e| format_err!("Could not render template `{}`: {}", name, e))
+}
+
+/// Format text as an inline markdown code span.
+///
+/// The span is delimited by more backticks than the longest run of
+/// backticks in the text, so text like a commit title can not end it early.
+pub fn code_span(text: &str) -> String {
+    let longest_run = text.split(|c| c != '`').map(|run| run.len()).max().unwrap_or(0);
+    let fence = "`".repeat(longest_run + 1);
+    // Without the padding, a backtick at either end would join the fence.
+    if text.starts_with('`') || text.ends_with('`') {
+        format!("{} {} {}", fence, text, fence)
+    } else {
+        format!("{}{}{}", fence, text, fence)
+    }
+}
+
+/// Render a built-in template.
+pub fn render_default<T: Serialize>(name: &str, context: &T) -> Result<String, Error> {
+    render(name, default_template(name)?, context)
+}
+
+/// Render a message setting, eg the reminder message, with a
+/// `MessageContext`.
+pub fn render_message(message: &str, context: &MessageContext) -> Result<String, Error> {
+    render("message", message, context)
+}
+
+/// Context of the `report` template.
+#[derive(Serialize, Clone, Debug)]
+pub struct ReportContext {
+    pub merge_request: types::MergeRequest,
+    pub project: types::Project,
+    /// Newest pipeline of the merge request.
+    pub pipeline: Option<PipelineContext>,
+    /// Summary lines of the configured reports.
+    pub report_summary: Option<String>,
+    /// Details of the configured reports, a `## Reports` section.
+    pub report_body: Option<String>,
+    /// Jobs that were retried because of a flaky failure, as markdown.
+    pub retried_jobs: Vec<String>,
+    pub failed_jobs: Vec<JobContext>,
+    pub successful_jobs: Vec<JobContext>,
+    pub validation: Vec<ValidationItem>,
+    /// The applied repo config.
+    pub config: ConfigRevision,
+    /// Problems of the repo config to show, see `FullMergeRequest`.
+    pub config_problems: Vec<ConfigProblem>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PipelineContext {
+    pub id: u64,
+    pub sha: String,
+    pub status: String,
+    pub failed: bool,
+    pub passed: bool,
+    /// Pending, running or restarted because of a flaky job.
+    pub running: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct JobContext {
+    pub id: u64,
+    pub name: String,
+    pub url: String,
+    /// Category of the matching failure hint.
+    pub category: Option<String>,
+    /// Markdown hint of the matching failure hint.
+    pub hint: Option<String>,
+    /// HTML escaped log excerpt of failed jobs.
+    pub log: Option<String>,
+}
+
+/// A checkbox of the Validation section.
+#[derive(Serialize, Clone, Debug)]
+pub struct ValidationItem {
+    pub label: String,
+    pub valid: bool,
+    /// Explanation shown if the check failed, eg the actual coverage.
+    pub detail: Option<String>,
+}
+
+impl ValidationItem {
+    pub fn new<S: Into<String>>(label: S, valid: bool) -> Self {
+        ValidationItem {
+            label: label.into(),
+            valid,
+            detail: None,
+        }
+    }
+
+    pub fn with_detail<S: Into<String>>(label: S, valid: bool, detail: String) -> Self {
+        ValidationItem {
+            label: label.into(),
+            valid,
+            detail: Some(detail),
+        }
+    }
+}
+
+/// Context of the `reminder` template.
+#[derive(Serialize, Clone, Debug)]
+pub struct ReminderContext {
+    pub merge_request: types::MergeRequest,
+    /// Message from the `[reminders]` config or the escalation step.
+    pub message: String,
+    /// Working days without activity.
+    pub idle_days: i64,
+}
+
+/// Context of the `message` settings of reminders and escalation steps.
+#[derive(Serialize, Clone, Debug)]
+pub struct MessageContext {
+    pub merge_request: types::MergeRequest,
+    /// The pinged users, eg `@alice @bob`.
+    pub users: String,
+    /// Configured number of idle working days.
+    pub days: i64,
+}
+
+/// Context of the `stale_warning`, `stale_close` and `stale_reopen`
+/// templates.
+#[derive(Serialize, Clone, Debug)]
+pub struct StaleContext {
+    pub merge_request: types::MergeRequest,
+    /// Username of the author.
+    pub author: String,
+    /// Working days without activity, 0 for `stale_reopen`.
+    pub idle_days: i64,
+    /// Working days after the warning before the merge request is closed.
+    pub days_until_close: i64,
+}
+
+/// Context of the `command_reply` template.
+#[derive(Serialize, Clone, Debug)]
+pub struct CommandReplyContext {
+    pub merge_request: types::MergeRequest,
+    /// Username of the comment author.
+    pub author: String,
+    /// Markdown replies, eg the help or why a command failed.
+    pub replies: Vec<String>,
+}
+
+/// Context of the `title_warning` and `branch_warning` templates.
+#[derive(Serialize, Clone, Debug)]
+pub struct WarningContext {
+    pub merge_request: types::MergeRequest,
+    /// Username of the author.
+    pub author: String,
+    /// Configured error message, or a default mentioning the pattern.
+    pub error: String,
+}
+
+/// Context of the `commit_warning` template.
+#[derive(Serialize, Clone, Debug)]
+pub struct CommitWarningContext {
+    pub merge_request: types::MergeRequest,
+    /// Username of the author.
+    pub author: String,
+    pub commits: Vec<commits::InvalidCommit>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_defaults_compile() {
+        for name in NAMES {
+            let template = default_template(name).unwrap();
+            check(name, template).unwrap();
+        }
+    }
+
+    #[test]
+    fn code_spans() {
+        let cases = vec![
+            ("feat: add login", "`feat: add login`"),
+            ("fix: handle `None` values", "``fix: handle `None` values``"),
+            ("fix: handle `None`", "`` fix: handle `None` ``"),
+            ("fix: use ``` fences", "````fix: use ``` fences````"),
+            ("`wip`", "`` `wip` ``"),
+            ("", "``"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(code_span(text), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn unknown_template_is_an_error() {
+        assert!(default_template("nope").is_err());
+        assert!(render_default("nope", &()).is_err());
+        assert!(TemplateConfig::default().set("nope", String::new()).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use regex::Regex;
+
+use config::Pattern;
+use reports::{escape_html, truncate};
+
+const DEFAULT_MAX_SIZE: usize = 16 * 1024;
+/// Gitlab rejects comments over 1MB, and the excerpts share the report
+/// comment with the other sections.
+const DEFAULT_MAX_TOTAL_SIZE: usize = 256 * 1024;
+const DEFAULT_TAIL_LINES: usize = 30;
+const DEFAULT_CONTEXT_LINES: usize = 3;
+const DEFAULT_ERROR_PATTERN: &str = r"(?i)\b(error|panicked|fatal|failed|failures?)\b";
+
+const TRUNCATED: &str = "[... log truncated ...]\n";
+
+/// Maximum length of a single line in an excerpt.
+const MAX_LINE_LEN: usize = 1024;
+
+lazy_static! {
+    static ref DEFAULT_ERROR_REGEX: Regex = Regex::new(DEFAULT_ERROR_PATTERN).unwrap();
+    static ref ANSI_REGEX: Regex =
+        Regex::new(r"\x1b\[[0-9;?]*[A-Za-z]|\x1b\][^\x07]*\x07").unwrap();
+    static ref SECTION_REGEX: Regex =
+        Regex::new(r"section_(?:start|end):\d+:[^\r\n]*(?:\r|$)").unwrap();
+}
+
+/// The `[trace]` section of the repo config.
+///
+/// Controls the log excerpt shown for failed jobs.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct TraceConfig {
+    /// Maximum size of the excerpt of a job log in bytes.
+    pub max_size: Option<usize>,
+    /// Maximum size of the excerpts of all failed jobs together in bytes.
+    pub max_total_size: Option<usize>,
+    /// Number of lines at the end of the log that are always shown.
+    pub tail_lines: Option<usize>,
+    /// Number of lines shown before and after each error line.
+    pub context_lines: Option<usize>,
+    /// Regex for lines with errors.
+    pub error_pattern: Option<Pattern>,
+}
+
+impl TraceConfig {
+    pub fn max_size(&self) -> usize {
+        self.max_size.unwrap_or(DEFAULT_MAX_SIZE)
+    }
+
+    pub fn max_total_size(&self) -> usize {
+        self.max_total_size.unwrap_or(DEFAULT_MAX_TOTAL_SIZE)
+    }
+
+    pub fn tail_lines(&self) -> usize {
+        self.tail_lines.unwrap_or(DEFAULT_TAIL_LINES)
+    }
+
+    pub fn context_lines(&self) -> usize {
+        self.context_lines.unwrap_or(DEFAULT_CONTEXT_LINES)
+    }
+
+    fn is_error(&self, line: &str) -> bool {
+        match self.error_pattern.as_ref() {
+            Some(p) => p.is_match(line),
+            None => DEFAULT_ERROR_REGEX.is_match(line),
+        }
+    }
+}
+
+/// Remove ANSI escape codes, Gitlab section markers and output that was
+/// overwritten with carriage returns, eg progress bars.
+pub fn clean(trace: &str) -> String {
+    let trace = ANSI_REGEX.replace_all(trace, "");
+    let mut lines = Vec::new();
+    for line in trace.lines() {
+        let has_marker = SECTION_REGEX.is_match(line);
+        let line = SECTION_REGEX.replace_all(line, "");
+        let line = line.rsplit('\r').find(|s| s.trim() != "").unwrap_or("");
+        if has_marker && line.trim() == "" {
+            continue;
+        }
+        lines.push(line.to_string());
+    }
+    lines.join("\n")
+}
+
+fn omitted(count: usize) -> String {
+    format!("[... {} lines omitted ...]", count)
+}
+
+/// Collapse the cleaned logs of several jobs, in the same order.
+///
+/// Each excerpt gets at most `max_size` bytes and all of them together at
+/// most `max_total_size`. Space a short log does not use goes to the
+/// following ones.
+pub fn excerpts(traces: &[String], config: &TraceConfig) -> Vec<String> {
+    let mut remaining = config.max_total_size();
+    let mut out = Vec::new();
+    for (index, trace) in traces.iter().enumerate() {
+        let share = remaining / (traces.len() - index);
+        let excerpt = excerpt(trace, config, share.min(config.max_size()));
+        remaining = remaining.saturating_sub(excerpt.len());
+        out.push(excerpt);
+    }
+    out
+}
+
+/// Collapse a cleaned log to its tail and the lines around errors.
+///
+/// The result is HTML escaped and at most `max_size` bytes. If the budget is
+/// exceeded, the beginning is dropped, since the end of a log is usually the
+/// most relevant part.
+fn excerpt(trace: &str, config: &TraceConfig, max_size: usize) -> String {
+    let lines = trace.lines().collect::<Vec<_>>();
+    let mut keep = vec![false; lines.len()];
+
+    let tail_start = lines.len().saturating_sub(config.tail_lines());
+    for k in keep[tail_start..].iter_mut() {
+        *k = true;
+    }
+
+    let context = config.context_lines();
+    for (index, line) in lines.iter().enumerate() {
+        if config.is_error(line) {
+            let start = index.saturating_sub(context);
+            let end = (index + context + 1).min(lines.len());
+            for k in keep[start..end].iter_mut() {
+                *k = true;
+            }
+        }
+    }
+
+    let mut parts = Vec::new();
+    let mut skipped = 0;
+    for (line, keep) in lines.iter().zip(keep) {
+        if !keep {
+            skipped += 1;
+            continue;
+        }
+        if skipped > 0 {
+            parts.push(omitted(skipped));
+            skipped = 0;
+        }
+        parts.push(escape_html(truncate(line, MAX_LINE_LEN)));
+    }
+    if skipped > 0 {
+        parts.push(omitted(skipped));
+    }
+
+    // Take lines from the end until the budget is used up.
+    let max_size = max_size.saturating_sub(TRUNCATED.len());
+    let mut size = 0;
+    let mut start = parts.len();
+    while start > 0 && size + parts[start - 1].len() + 1 <= max_size {
+        size += parts[start - 1].len() + 1;
+        start -= 1;
+    }
+
+    let mut out = String::new();
+    if start > 0 {
+        out.push_str(TRUNCATED);
+    }
+    out.push_str(&parts[start..].join("\n"));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(lines: usize) -> String {
+        (0..lines)
+            .map(|i| format!("line {:04}", i))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn excerpts_share_the_total_size() {
+        let config = TraceConfig {
+            max_size: Some(1000),
+            max_total_size: Some(1500),
+            tail_lines: Some(1000),
+            ..TraceConfig::default()
+        };
+        let traces = vec![log(10), log(500), log(500)];
+        let excerpts = excerpts(&traces, &config);
+
+        // The short log leaves its share to the others.
+        assert_eq!(excerpts[0], traces[0]);
+        assert!(excerpts[1].len() <= 1000);
+        assert!(excerpts[1].starts_with(TRUNCATED));
+        assert!(excerpts[2].ends_with("line 0499"));
+        let total = excerpts.iter().map(|e| e.len()).sum::<usize>();
+        assert!(total <= 1500, "total size {}", total);
+    }
+
+    #[test]
+    fn clean_removes_escape_codes_and_sections() {
+        let raw = "section_start:1:build\r\x1b[0K\x1b[32;1mok\x1b[0m\n\
+                   progress 10%\rprogress 100%\n\
+                   section_end:2:build\r\x1b[0K";
+        assert_eq!(clean(raw), "ok\nprogress 100%");
+    }
+}
@@ -0,0 +1,418 @@
+use std::net::SocketAddr;
+
+use failure::Error;
+use futures::prelude::*;
+use futures::future;
+use hyper::{self, Method, StatusCode};
+use hyper::header::ContentLength;
+use hyper::server::{Http, Request, Response, Service};
+use slog::Logger;
+use tokio_core::reactor::Handle;
+
+use bot::Bot;
+
+/// Largest accepted payload. Pipeline events of big pipelines are the
+/// largest ones Gitlab sends.
+const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Deserialize, Debug)]
+struct HookProject {
+    id: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct HookMergeRequest {
+    iid: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct HookPipeline {
+    #[serde(rename = "ref")]
+    branch: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct HookNote {
+    noteable_type: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct HookUser {
+    username: String,
+}
+
+/// A Gitlab webhook payload.
+///
+/// Only the fields needed to find the affected merge requests are parsed.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "object_kind", rename_all = "snake_case")]
+enum Hook {
+    MergeRequest {
+        project: HookProject,
+        object_attributes: HookMergeRequest,
+    },
+    Pipeline {
+        project: HookProject,
+        object_attributes: HookPipeline,
+        merge_request: Option<HookMergeRequest>,
+    },
+    /// Job events are sent with the "build" object kind.
+    Build {
+        project_id: u64,
+        #[serde(rename = "ref")]
+        branch: String,
+    },
+    Note {
+        project: HookProject,
+        object_attributes: HookNote,
+        merge_request: Option<HookMergeRequest>,
+        /// Author of the note.
+        user: HookUser,
+    },
+}
+
+/// The merge requests affected by a webhook event.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HookTarget {
+    MergeRequest { project_id: u64, iid: u64 },
+    /// All open merge requests with the given source branch.
+    Branch { project_id: u64, branch: String },
+}
+
+impl Hook {
+    /// Get the merge requests to check.
+    ///
+    /// Notes of the bot itself are ignored, otherwise every comment it posts
+    /// would check the merge request again.
+    fn target(self, bot_username: &str) -> Option<HookTarget> {
+        match self {
+            Hook::MergeRequest {
+                project,
+                object_attributes,
+            } => Some(HookTarget::MergeRequest {
+                project_id: project.id,
+                iid: object_attributes.iid,
+            }),
+            Hook::Pipeline {
+                project,
+                merge_request: Some(mr),
+                ..
+            } => Some(HookTarget::MergeRequest {
+                project_id: project.id,
+                iid: mr.iid,
+            }),
+            Hook::Pipeline {
+                project,
+                object_attributes,
+                merge_request: None,
+            } => Some(HookTarget::Branch {
+                project_id: project.id,
+                branch: object_attributes.branch,
+            }),
+            Hook::Build { project_id, branch } => Some(HookTarget::Branch { project_id, branch }),
+            Hook::Note {
+                project,
+                object_attributes,
+                merge_request: Some(mr),
+                user,
+            } => {
+                let on_merge_request = object_attributes.noteable_type == "MergeRequest";
+                if on_merge_request && user.username != bot_username {
+                    Some(HookTarget::MergeRequest {
+                        project_id: project.id,
+                        iid: mr.iid,
+                    })
+                } else {
+                    None
+                }
+            }
+            Hook::Note { .. } => None,
+        }
+    }
+}
+
+/// HTTP service that receives Gitlab webhooks and hands the affected merge
+/// requests to the bot.
+#[derive(Clone)]
+struct WebhookService {
+    bot: Bot,
+    /// Username of the bot user, to ignore its own notes.
+    bot_username: String,
+    secret: String,
+    handle: Handle,
+    log: Logger,
+}
+
+impl WebhookService {
+    fn is_authorized(&self, req: &Request) -> bool {
+        req.headers()
+            .get_raw("X-Gitlab-Token")
+            .and_then(|x| x.one())
+            .map(|token| constant_time_eq(token, self.secret.as_bytes()))
+            .unwrap_or(false)
+    }
+
+    fn handle_payload(&self, body: &[u8]) -> Response {
+        let value = match ::serde_json::from_slice::<::serde_json::Value>(body) {
+            Ok(v) => v,
+            Err(e) => {
+                debug!(self.log, "webhook_payload_invalid"; "error" => e.to_string());
+                return Response::new().with_status(StatusCode::BadRequest);
+            }
+        };
+        // Gitlab disables hooks that keep failing, so events the bot does
+        // not handle, like pushes, are acknowledged too.
+        let hook = match ::serde_json::from_value::<Hook>(value.clone()) {
+            Ok(h) => h,
+            Err(e) => {
+                debug!(self.log, "webhook_event_ignored";
+                    "object_kind" => value.get("object_kind").and_then(|k| k.as_str()),
+                    "error" => e.to_string(),
+                );
+                return Response::new().with_status(StatusCode::Ok);
+            }
+        };
+        trace!(self.log, "webhook_received"; "hook" => format!("{:?}", hook));
+
+        if let Some(target) = hook.target(&self.bot_username) {
+            let log = self.log.clone();
+            let f = self.bot
+                .clone()
+                .process_hook_target(target.clone())
+                .map_err(move |e| {
+                    error!(log, "webhook_processing_failed";
+                        "target" => format!("{:?}", target),
+                        "error" => e.to_string(),
+                    );
+                });
+            self.handle.spawn(f);
+        }
+
+        Response::new().with_status(StatusCode::Accepted)
+    }
+}
+
+impl Service for WebhookService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item = Response, Error = hyper::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        if req.method() != &Method::Post {
+            return Box::new(future::ok(
+                Response::new().with_status(StatusCode::MethodNotAllowed),
+            ));
+        }
+        if !self.is_authorized(&req) {
+            warn!(self.log, "webhook_unauthorized");
+            return Box::new(future::ok(
+                Response::new().with_status(StatusCode::Unauthorized),
+            ));
+        }
+
+        let too_large = req.headers()
+            .get::<ContentLength>()
+            .map(|l| l.0 > MAX_BODY_SIZE as u64)
+            .unwrap_or(false);
+        if too_large {
+            return Box::new(future::ok(
+                Response::new().with_status(StatusCode::PayloadTooLarge),
+            ));
+        }
+
+        // The length header is optional, so the size is checked while
+        // reading too.
+        let service = self.clone();
+        let body = req.body().fold(Vec::new(), |mut body, chunk| {
+            if body.len() + chunk.len() > MAX_BODY_SIZE {
+                return Err(hyper::Error::TooLarge);
+            }
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        });
+        Box::new(body.then(move |res| match res {
+            Ok(body) => Ok(service.handle_payload(&body)),
+            Err(hyper::Error::TooLarge) => {
+                Ok(Response::new().with_status(StatusCode::PayloadTooLarge))
+            }
+            Err(e) => Err(e),
+        }))
+    }
+}
+
+/// Compare two byte strings in a time that does not depend on where they
+/// differ, so the secret can not be guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Start the webhook listener on the given address.
+///
+/// The server is spawned onto the reactor, so this returns immediately.
+pub fn serve(
+    bot: Bot,
+    bot_username: String,
+    addr: SocketAddr,
+    secret: String,
+    handle: &Handle,
+    log: Logger,
+) -> Result<(), Error> {
+    if secret == "" {
+        bail!("Refusing to start the webhook listener without a secret");
+    }
+    let service = WebhookService {
+        bot,
+        bot_username,
+        secret,
+        handle: handle.clone(),
+        log: log.clone(),
+    };
+    let server = Http::new().serve_addr_handle(&addr, handle, move || Ok(service.clone()))?;
+    info!(log, "webhook_listening"; "addr" => addr.to_string());
+
+    let conn_handle = handle.clone();
+    let conn_log = log.clone();
+    let f = server
+        .for_each(move |conn| {
+            let log = conn_log.clone();
+            conn_handle.spawn(conn.map(|_| ()).map_err(move |e| {
+                debug!(log, "webhook_connection_failed"; "error" => e.to_string());
+            }));
+            Ok(())
+        })
+        .map_err(move |e| {
+            error!(log, "webhook_server_failed"; "error" => e.to_string());
+        });
+    handle.spawn(f);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{self, Value};
+
+    use super::*;
+
+    fn target(payload: Value) -> Option<HookTarget> {
+        serde_json::from_value::<Hook>(payload)
+            .unwrap()
+            .target("gitlab-bot")
+    }
+
+    fn note(noteable_type: &str, merge_request: Value, username: &str) -> Value {
+        json!({
+            "object_kind": "note",
+            "project": {"id": 1},
+            "object_attributes": {"noteable_type": noteable_type, "note": "LGTM"},
+            "merge_request": merge_request,
+            "user": {"username": username, "name": "Someone"},
+        })
+    }
+
+    #[test]
+    fn merge_request_event_targets_the_merge_request() {
+        let payload = json!({
+            "object_kind": "merge_request",
+            "project": {"id": 1},
+            "object_attributes": {"iid": 2, "title": "Add login"},
+            "user": {"username": "dev"},
+        });
+        assert_eq!(
+            target(payload),
+            Some(HookTarget::MergeRequest {
+                project_id: 1,
+                iid: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn pipeline_event_targets_the_merge_request_or_branch() {
+        let payload = json!({
+            "object_kind": "pipeline",
+            "project": {"id": 1},
+            "object_attributes": {"ref": "feature", "status": "success"},
+            "merge_request": {"iid": 2},
+        });
+        assert_eq!(
+            target(payload),
+            Some(HookTarget::MergeRequest {
+                project_id: 1,
+                iid: 2,
+            })
+        );
+
+        let branch = Some(HookTarget::Branch {
+            project_id: 1,
+            branch: "feature".to_string(),
+        });
+        let payload = json!({
+            "object_kind": "pipeline",
+            "project": {"id": 1},
+            "object_attributes": {"ref": "feature"},
+            "merge_request": null,
+        });
+        assert_eq!(target(payload), branch);
+        let payload = json!({
+            "object_kind": "pipeline",
+            "project": {"id": 1},
+            "object_attributes": {"ref": "feature"},
+        });
+        assert_eq!(target(payload), branch);
+    }
+
+    #[test]
+    fn build_event_targets_the_branch() {
+        let payload = json!({
+            "object_kind": "build",
+            "project_id": 1,
+            "ref": "feature",
+            "build_status": "failed",
+        });
+        assert_eq!(
+            target(payload),
+            Some(HookTarget::Branch {
+                project_id: 1,
+                branch: "feature".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn note_event_targets_merge_requests_commented_by_others() {
+        assert_eq!(
+            target(note("MergeRequest", json!({"iid": 2}), "dev")),
+            Some(HookTarget::MergeRequest {
+                project_id: 1,
+                iid: 2,
+            })
+        );
+        // The bot's own comments.
+        assert_eq!(target(note("MergeRequest", json!({"iid": 2}), "gitlab-bot")), None);
+        // Comments on commits, issues and snippets.
+        assert_eq!(target(note("Commit", Value::Null, "dev")), None);
+        assert_eq!(target(note("Issue", Value::Null, "dev")), None);
+        assert_eq!(target(note("Snippet", json!({"iid": 2}), "dev")), None);
+    }
+
+    #[test]
+    fn other_events_are_not_parsed() {
+        let payload = json!({"object_kind": "push", "project": {"id": 1}});
+        assert!(serde_json::from_value::<Hook>(payload).is_err());
+    }
+
+    #[test]
+    fn compares_secrets() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"Secret"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+}